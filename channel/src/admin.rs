@@ -0,0 +1,412 @@
+use axum::{
+    extract::{Path, Request, State as AxumState},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::{atomic::Ordering, Arc};
+use subtle::ConstantTimeEq;
+use tracing::{debug, info};
+
+use crate::auth::JwtAuth;
+use crate::channel::ChannelError;
+use crate::websocket::{add_persistent_channel, kick_agent, kick_conn, revoke_token, State, REVOCATION_TTL};
+
+/// REST admin api, every request needs `Authorization: Bearer {api_key}`,
+/// or a JWT signed with the socket secret whose scope has `admin`, see `ApiClaims`
+///
+/// - `GET /api/channels`: channels with their member counts
+/// - `POST /api/channels/{topic}`: create a persistent channel
+/// - `DELETE /api/channels/{topic}`: remove a channel with all its agents
+/// - `GET /api/channels/{topic}/agents`: agents in a channel
+/// - `GET /api/connections`: connections with metadata
+/// - `GET /api/users/{user_id}`: whether the user is connected, with its connections
+/// - `POST /api/connections/{conn_id}/kick`, `POST /api/agents/{agent_id}/kick`: kick with `{"reason": ...}`
+/// - `POST /api/tokens/{token_id}/revoke`: kick who uses the token, refused until `{"until": unix time}`, see `REVOCATION_TTL`
+pub fn router(state: Arc<State>, api_key: &str) -> Router {
+    Router::new()
+        .route("/api/channels", get(channels_list))
+        .route("/api/channels/:topic", post(channel_create).delete(channel_remove))
+        .route("/api/channels/:topic/agents", get(channel_agents))
+        .route("/api/connections", get(conn_list))
+        .route("/api/users/:user_id", get(user_get))
+        .route("/api/connections/:conn_id/kick", post(conn_kick))
+        .route("/api/agents/:agent_id/kick", post(agent_kick))
+        .route("/api/tokens/:token_id/revoke", post(token_revoke))
+        .route_layer(middleware::from_fn_with_state(ApiAuth::new(&state, Some(api_key), "admin"), require_auth))
+        .with_state(state)
+}
+
+/// publish api for backends without redis, with `Authorization: Bearer {api_key}`
+/// or a JWT signed with the socket secret whose scope has `publish`
+///
+/// - `POST /api/channels/{topic}/events/{event}`: broadcast the JSON body, `{"receivers": n}`, 404 or 409 if nobody is there
+/// - `POST /api/events`: broadcast `[{"topic", "event", "payload"}]`, one result per event
+/// - `POST /api/users/{user_id}/events`: send `{"topic", "event", "payload"}` to every connection of the user,
+///   `{"sessions": n}`, 404 if the user is not connected
+pub fn publish_router(state: Arc<State>, api_key: Option<&str>) -> Router {
+    let router = Router::new()
+        .route("/api/channels/:topic/events/:event", post(event_publish))
+        .route("/api/events", post(events_publish))
+        .route("/api/users/:user_id/events", post(user_publish));
+    with_api_auth(router, &state, api_key, "publish").with_state(state)
+}
+
+/// require the api key, or a JWT signed with the socket secret whose scope has `scope`, on the routes of the router
+pub fn with_api_auth<S: Clone + Send + Sync + 'static>(
+    router: Router<S>, state: &Arc<State>, api_key: Option<&str>, scope: &'static str,
+) -> Router<S> {
+    router.route_layer(middleware::from_fn_with_state(ApiAuth::new(state, api_key, scope), require_auth))
+}
+
+/// credentials of the http api
+#[derive(Clone)]
+struct ApiAuth {
+    api_key: Option<String>,
+    state: Arc<State>, // the jwt secret is reloadable
+    jwt: Arc<JwtAuth>, // verifies with the keys of the state
+    scope: &'static str,
+}
+
+impl ApiAuth {
+    fn new(state: &Arc<State>, api_key: Option<&str>, scope: &'static str) -> Self {
+        ApiAuth {
+            api_key: api_key.map(|k| k.to_string()),
+            state: state.clone(),
+            jwt: Arc::new(JwtAuth::default()),
+            scope,
+        }
+    }
+}
+
+async fn require_auth(AxumState(auth): AxumState<ApiAuth>, request: Request, next: Next) -> Response {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let authorized = token.is_some_and(|token| {
+        auth.api_key.as_ref().is_some_and(|key| key.as_bytes().ct_eq(token.as_bytes()).into())
+            || auth.jwt.verify_scope(&auth.state, token, auth.scope)
+    });
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "unauthorized" }))).into_response();
+    }
+    next.run(request).await
+}
+
+/// http status of channel errors, the body is `{"error": ...}`
+pub fn error_response(e: ChannelError) -> (StatusCode, Json<Value>) {
+    let status = match e {
+        ChannelError::ChannelNotFound | ChannelError::AgentNotInitiated => StatusCode::NOT_FOUND,
+        ChannelError::ChannelEmpty => StatusCode::CONFLICT,
+        ChannelError::JoinRefused => StatusCode::FORBIDDEN,
+        ChannelError::MessageSendError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(json!({ "error": e.to_string() })))
+}
+
+#[derive(Serialize)]
+struct ChannelSummary {
+    name: String,
+    agents: u32,
+    persistent: bool,
+}
+
+async fn channels_list(AxumState(state): AxumState<Arc<State>>) -> Json<Vec<ChannelSummary>> {
+    let ctl = state.ctl.lock().await;
+    let mut channels = ctl
+        .channels
+        .lock()
+        .await
+        .values()
+        .map(|channel| ChannelSummary {
+            name: channel.name.clone(),
+            agents: channel.count.load(Ordering::SeqCst),
+            persistent: channel.persistent || state.is_special_channel(&channel.name),
+        })
+        .collect::<Vec<ChannelSummary>>();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(channels)
+}
+
+async fn channel_create(AxumState(state): AxumState<Arc<State>>, Path(topic): Path<String>) -> impl IntoResponse {
+    add_persistent_channel(&state.ctl, state.broker.clone(), topic.clone()).await;
+    info!("ADMIN / persistent channel {} created", topic);
+    (StatusCode::CREATED, Json(json!({ "channel": topic })))
+}
+
+async fn channel_remove(AxumState(state): AxumState<Arc<State>>, Path(topic): Path<String>) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    let ctl = state.ctl.lock().await;
+    if !ctl.channel_exists(&topic).await {
+        return Err(error_response(ChannelError::ChannelNotFound));
+    }
+    let closing = ctl.channel_rm(topic.clone()).await;
+    drop(ctl);
+    closing.terminate().await;
+    info!("ADMIN / channel {} removed", topic);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn channel_agents(AxumState(state): AxumState<Arc<State>>, Path(topic): Path<String>) -> Result<Json<Vec<String>>, (StatusCode, Json<Value>)> {
+    let ctl = state.ctl.lock().await;
+    let channels = ctl.channels.lock().await;
+    let channel = channels.get(&topic).ok_or(error_response(ChannelError::ChannelNotFound))?;
+    let agents = channel.agents().await.clone();
+    Ok(Json(agents))
+}
+
+async fn conn_list(AxumState(state): AxumState<Arc<State>>) -> impl IntoResponse {
+    Json(state.ctl.lock().await.conn_list().await)
+}
+
+async fn user_get(AxumState(state): AxumState<Arc<State>>, Path(user_id): Path<String>) -> Json<Value> {
+    let connections = state.ctl.lock().await.user_conns(&user_id).await;
+    Json(json!({ "user_id": user_id, "online": !connections.is_empty(), "connections": connections }))
+}
+
+#[derive(Deserialize, Default)]
+struct Kick {
+    reason: Option<String>,
+}
+
+async fn conn_kick(
+    AxumState(state): AxumState<Arc<State>>, Path(conn_id): Path<String>, kick: Option<Json<Kick>>,
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    let reason = kick.and_then(|Json(k)| k.reason).unwrap_or_else(|| "kicked".to_string());
+    kick_conn(&state, &conn_id, &reason).await.map_err(error_response)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn agent_kick(
+    AxumState(state): AxumState<Arc<State>>, Path(agent_id): Path<String>, kick: Option<Json<Kick>>,
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    let reason = kick.and_then(|Json(k)| k.reason).unwrap_or_else(|| "kicked".to_string());
+    kick_agent(&state, &agent_id, &reason).await.map_err(error_response)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Default)]
+struct Revoke {
+    until: Option<u64>,
+}
+
+async fn token_revoke(AxumState(state): AxumState<Arc<State>>, Path(token_id): Path<String>, revoke: Option<Json<Revoke>>) -> Json<Value> {
+    let until = revoke
+        .and_then(|Json(r)| r.until)
+        .unwrap_or_else(|| chrono::Utc::now().timestamp() as u64 + REVOCATION_TTL.as_secs());
+    let kicked = revoke_token(&state, &token_id, until).await;
+    Json(json!({ "kicked": kicked }))
+}
+
+async fn event_publish(
+    AxumState(state): AxumState<Arc<State>>, Path((topic, event)): Path<(String, String)>, Json(payload): Json<Value>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let receivers = state
+        .ctl
+        .lock()
+        .await
+        .channel_broadcast_json(&topic, &event, payload)
+        .await
+        .map_err(error_response)?;
+    debug!("API / {}:{} published to {} receivers", topic, event, receivers);
+    Ok(Json(json!({ "receivers": receivers })))
+}
+
+#[derive(Deserialize)]
+struct Event {
+    topic: String,
+    event: String,
+    #[serde(default)]
+    payload: Value,
+}
+
+async fn user_publish(
+    AxumState(state): AxumState<Arc<State>>, Path(user_id): Path<String>, Json(e): Json<Event>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let sessions = state.ctl.lock().await.user_send(&user_id, &e.topic, &e.event, e.payload).await;
+    if sessions == 0 {
+        return Err((StatusCode::NOT_FOUND, Json(json!({ "error": "user not connected" }))));
+    }
+    debug!("API / {}:{} sent to {} sessions of user {}", e.topic, e.event, sessions, user_id);
+    Ok(Json(json!({ "sessions": sessions })))
+}
+
+// every event is published, failures included, one result per event in order
+async fn events_publish(AxumState(state): AxumState<Arc<State>>, Json(events): Json<Vec<Event>>) -> Json<Vec<Value>> {
+    let ctl = state.ctl.lock().await;
+    let mut results = vec![];
+    for e in events {
+        match ctl.channel_broadcast_json(&e.topic, &e.event, e.payload).await {
+            Ok(receivers) => results.push(json!({ "receivers": receivers })),
+            Err(err) => {
+                let (status, Json(body)) = error_response(err);
+                results.push(json!({ "status": status.as_u16(), "error": body["error"] }));
+            }
+        }
+    }
+    Json(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::socket::ChannelSocket;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    async fn call(app: &Router, method: &str, uri: &str, key: &str) -> (StatusCode, Value) {
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn post_json(app: &Router, uri: &str, token: &str, body: Value) -> (StatusCode, Value) {
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_publish_api() {
+        use crate::auth::ApiClaims;
+        use jsonwebtoken::{encode, EncodingKey, Header};
+
+        let state = ChannelSocket::builder().jwt_secret("secret").build().state();
+        let app = publish_router(state.clone(), Some("key"));
+        let claims = ApiClaims {
+            sub: "billing".into(),
+            scope: "publish".into(),
+            exp: chrono::Utc::now().timestamp() as usize + 60,
+        };
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+
+        let (status, _) = post_json(&app, "/api/channels/room:1/events/news", "wrong", json!({})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post_json(&app, "/api/channels/room:1/events/news", "key", json!({})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        {
+            let ctl = state.ctl.lock().await;
+            ctl.channel_add("room:1".into(), None).await;
+        }
+        let (status, _) = post_json(&app, "/api/channels/room:1/events/news", &token, json!({})).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let mut channel_rx = {
+            let ctl = state.ctl.lock().await;
+            ctl.agent_add("conn1:room:1:1".into(), None).await;
+            ctl.channel_join("room:1", "conn1:room:1:1".into()).await.unwrap().subscribe()
+        };
+        let (status, body) = post_json(&app, "/api/channels/room:1/events/news", &token, json!({ "n": 1 })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "receivers": 2 })); // the relay task of the agent, and channel_rx
+        let crate::channel::ChannelMessage::Reply(message) = channel_rx.recv().await.unwrap() else {
+            panic!("reply expected");
+        };
+        assert_eq!(message.event, "news");
+
+        let events = json!([
+            { "topic": "room:1", "event": "a", "payload": {} },
+            { "topic": "room:2", "event": "b" },
+        ]);
+        let (status, body) = post_json(&app, "/api/events", "key", events).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0], json!({ "receivers": 2 }));
+        assert_eq!(body[1]["status"], 404);
+
+        let event = json!({ "topic": "user:u1", "event": "notice", "payload": { "n": 1 } });
+        let (status, _) = post_json(&app, "/api/users/u1/events", "key", event.clone()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let mut conn_rx = {
+            let ctl = state.ctl.lock().await;
+            ctl.conn_add_tx("conn1".into()).await;
+            let identity = crate::auth::Identity {
+                user_id: "u1".into(),
+                ..Default::default()
+            };
+            let info = crate::channel::ConnInfo {
+                identity: Some(identity),
+                ..Default::default()
+            };
+            ctl.conn_info_set("conn1", info).await;
+            ctl.conn_rx("conn1".into()).await.unwrap()
+        };
+        let (status, body) = post_json(&app, "/api/users/u1/events", "key", event).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "sessions": 1 }));
+        assert!(matches!(conn_rx.recv().await.unwrap(), crate::channel::ChannelMessage::Reply(m) if m.event == "notice"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_api() {
+        let state = ChannelSocket::builder().build().state();
+        let app = router(state.clone(), "key");
+
+        let (status, _) = call(&app, "GET", "/api/channels", "wrong").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = call(&app, "POST", "/api/channels/room:1", "key").await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = call(&app, "GET", "/api/channels", "key").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!([{ "name": "room:1", "agents": 0, "persistent": true }]));
+
+        {
+            let ctl = state.ctl.lock().await;
+            ctl.conn_add_tx("conn1".into()).await;
+            ctl.agent_add("conn1:room:1:1".into(), None).await;
+            ctl.channel_join("room:1", "conn1:room:1:1".into()).await.unwrap();
+        }
+        let (_, body) = call(&app, "GET", "/api/channels/room:1/agents", "key").await;
+        assert_eq!(body, json!(["conn1:room:1:1"]));
+        let (_, body) = call(&app, "GET", "/api/connections", "key").await;
+        assert_eq!(body[0]["conn_id"], "conn1");
+        assert_eq!(body[0]["agents"], json!(["conn1:room:1:1"]));
+        let (_, body) = call(&app, "GET", "/api/users/u1", "key").await;
+        assert_eq!(body, json!({ "user_id": "u1", "online": false, "connections": [] }));
+
+        let mut conn_rx = state.ctl.lock().await.conn_rx("conn1".into()).await.unwrap();
+        let (status, _) = call(&app, "POST", "/api/agents/conn1:room:1:1/kick", "key").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(matches!(conn_rx.recv().await.unwrap(), crate::channel::ChannelMessage::Reply(m) if m.event == "phx_close"));
+        let (_, body) = call(&app, "GET", "/api/channels", "key").await;
+        assert_eq!(body[0]["agents"], 0); // persistent, kept when empty
+
+        let (status, _) = call(&app, "POST", "/api/agents/conn1:room:1:1/kick", "key").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(&app, "POST", "/api/connections/conn1/kick", "key").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(matches!(conn_rx.recv().await.unwrap(), crate::channel::ChannelMessage::Close { code: 1008, .. }));
+
+        let (status, body) = call(&app, "POST", "/api/tokens/t1/revoke", "key").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "kicked": 0 }));
+        assert!(state.is_revoked("t1"));
+
+        let (status, _) = call(&app, "DELETE", "/api/channels/room:1", "key").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&app, "DELETE", "/api/channels/room:1", "key").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
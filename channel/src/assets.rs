@@ -0,0 +1,126 @@
+use axum::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use std::path::PathBuf;
+use tower_http::services::ServeDir;
+use tracing::{debug, info};
+
+/// pages bundled into the binary, the phoenix.js demo and the admin channel viewer
+pub const INDEX_HTML: &str = include_str!("bin/index.html");
+pub const ADMIN_HTML: &str = include_str!("bin/admin.html");
+
+/// where the bundled pages are mounted, None disables a page
+#[derive(Clone, Debug)]
+pub struct Pages {
+    pub index: Option<String>,
+    pub admin: Option<String>,
+    /// files here override the bundled ones with the same name, other files are served as they are
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for Pages {
+    fn default() -> Self {
+        Pages {
+            index: Some("/".into()),
+            admin: Some("/admin.html".into()),
+            dir: None,
+        }
+    }
+}
+
+/// router serving the pages, merge it last as the override directory is its fallback
+/// the index page is at `/index.html` too, where it was served from the source tree before
+pub fn router(pages: &Pages) -> Router {
+    let mut router = Router::new();
+    let mut routes = vec![(pages.index.clone(), "index.html", INDEX_HTML), (pages.admin.clone(), "admin.html", ADMIN_HTML)];
+    let legacy = Some("/index.html".to_string());
+    if pages.index.is_some() && !routes.iter().any(|(path, _, _)| *path == legacy) {
+        routes.push((legacy, "index.html", INDEX_HTML));
+    }
+    for (path, name, bundled) in routes {
+        let Some(path) = path else {
+            info!("ASSETS / {} disabled", name);
+            continue;
+        };
+        let dir = pages.dir.clone();
+        router = router.route(&path, get(move || page(dir, name, bundled)));
+        info!("ASSETS / {} at {}", name, path);
+    }
+    match &pages.dir {
+        Some(dir) => router.fallback_service(ServeDir::new(dir)),
+        None => router,
+    }
+}
+
+async fn page(dir: Option<PathBuf>, name: &'static str, bundled: &'static str) -> impl IntoResponse {
+    let html = match dir {
+        Some(dir) => match tokio::fs::read_to_string(dir.join(name)).await {
+            Ok(html) => html,
+            Err(e) => {
+                debug!("ASSETS / {} not overridden: {}", name, e);
+                bundled.to_string()
+            }
+        },
+        None => bundled.to_string(),
+    };
+    (StatusCode::OK, [(CONTENT_TYPE, "text/html; charset=utf-8")], html)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    async fn get_page(app: &Router, uri: &str) -> (StatusCode, String) {
+        let request = axum::http::Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8_lossy(&body).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_bundled_pages() {
+        let app = router(&Pages::default());
+        assert_eq!(get_page(&app, "/").await, (StatusCode::OK, INDEX_HTML.to_string()));
+        assert_eq!(get_page(&app, "/admin.html").await, (StatusCode::OK, ADMIN_HTML.to_string()));
+        assert_eq!(get_page(&app, "/index.html").await, (StatusCode::OK, INDEX_HTML.to_string()));
+
+        let app = router(&Pages {
+            index: Some("/demo".into()),
+            admin: None,
+            dir: None,
+        });
+        assert_eq!(get_page(&app, "/demo").await.0, StatusCode::OK);
+        assert_eq!(get_page(&app, "/").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get_page(&app, "/admin.html").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get_page(&app, "/index.html").await.0, StatusCode::OK);
+
+        let app = router(&Pages {
+            index: Some("/index.html".into()),
+            ..Default::default()
+        });
+        assert_eq!(get_page(&app, "/index.html").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_override_dir() {
+        let dir = std::env::temp_dir().join(format!("channel-assets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("admin.html"), "custom admin").unwrap();
+        std::fs::write(dir.join("app.js"), "console.log(1)").unwrap();
+
+        let app = router(&Pages {
+            dir: Some(dir.clone()),
+            ..Default::default()
+        });
+        assert_eq!(get_page(&app, "/admin.html").await, (StatusCode::OK, "custom admin".to_string()));
+        assert_eq!(get_page(&app, "/").await, (StatusCode::OK, INDEX_HTML.to_string()));
+        assert_eq!(get_page(&app, "/app.js").await, (StatusCode::OK, "console.log(1)".to_string()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,756 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use jsonwebtoken::{decode, decode_header, errors::ErrorKind, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing::{debug, error, info, warn};
+
+use crate::broker::Broker;
+use crate::config::AuthConfig;
+use crate::utils::topic_matches;
+use crate::websocket::{revoke_token, State, REVOCATION_TTL};
+
+/// claims of the channel token, as issued by `/token`
+/// a token with neither `subscribe` nor `publish` joins `channel` and pushes any event to it
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Claims {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub channel: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscribe: Vec<String>, // topic patterns to join besides `channel`, e.g. `room:*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish: Option<BTreeMap<String, Vec<String>>>, // topic pattern -> events to push, `*` for any
+    pub exp: usize,
+}
+
+impl Claims {
+    pub fn may_subscribe(&self, topic: &str) -> bool {
+        self.channel == topic || self.subscribe.iter().any(|pattern| topic_matches(pattern, topic))
+    }
+
+    /// what joining the topic grants, every event without the `publish` claim, until `exp`
+    pub fn grant(&self, topic: &str) -> Grant {
+        let events = self.publish.as_ref().map(|publish| {
+            publish
+                .iter()
+                .filter(|(pattern, _)| topic_matches(pattern, topic))
+                .flat_map(|(_, events)| events.iter().cloned())
+                .collect()
+        });
+        Grant {
+            events,
+            expires: (self.exp > 0).then_some(self.exp as u64),
+            token_id: (!self.id.is_empty()).then(|| self.id.clone()),
+            response: None,
+        }
+    }
+}
+
+/// what a join authorizes, kept for the agent until it leaves
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Grant {
+    pub events: Option<Vec<String>>, // events the client may push to the topic, any if not set
+    pub expires: Option<u64>,        // unix time the agent is kicked at, unless it renews the grant
+    pub token_id: Option<String>,    // the agent is kicked when the token is revoked
+    pub response: Option<Value>,     // response of the join reply, unless the channel handler answers
+}
+
+impl Grant {
+    pub fn may_publish(&self, event: &str) -> bool {
+        match &self.events {
+            Some(events) => events.iter().any(|e| e == "*" || e == event),
+            None => true,
+        }
+    }
+}
+
+/// claims of the tokens for the http api, `scope` is space separated, e.g. `publish admin`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiClaims {
+    pub sub: String,
+    pub scope: String,
+    pub exp: usize,
+}
+
+/// who is behind a connection, authenticated when it connected, see `ConnectAuth`
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+    pub claims: Value, // of the connect token, or the subject of the TLS certificate
+}
+
+impl Identity {
+    /// `exp` of the claims, the connection is closed then unless it authenticates again
+    pub fn expires(&self) -> Option<u64> {
+        self.claims.get("exp").and_then(Value::as_u64)
+    }
+
+    /// `jti` of the claims, or `id` for the channel tokens
+    pub fn token_id(&self) -> Option<&str> {
+        ["jti", "id"].iter().find_map(|key| self.claims.get(key).and_then(Value::as_str))
+    }
+}
+
+/// decides whether a client may join a topic, checked before the channel handler
+#[async_trait]
+pub trait JoinAuth: Send + Sync {
+    /// `Err(reason)` refuses the join, the reason is the response of the error reply
+    /// the grant is checked on every push of the client to the topic
+    async fn authorize(&self, state: &State, conn_id: &str, identity: Option<&Identity>, topic: &str, payload: &Value) -> Result<Grant, Value>;
+}
+
+/// authenticates a websocket from the params of its url before the upgrade,
+/// phoenix.js sends `new Socket(url, {params: {userToken}})` as `?userToken=...&vsn=2.0.0`
+#[async_trait]
+pub trait ConnectAuth: Send + Sync {
+    /// `Ok(None)` accepts an anonymous connection, `Err(reason)` refuses it with 401
+    async fn authenticate(&self, state: &State, params: &HashMap<String, String>) -> Result<Option<Identity>, String>;
+}
+
+pub(crate) fn is_hmac(algorithm: Algorithm) -> bool {
+    matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeysError {
+    Read { path: PathBuf, message: String },
+    Invalid(String),
+}
+
+impl std::error::Error for KeysError {}
+
+impl fmt::Display for KeysError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeysError::Read { path, message } => write!(formatter, "fail to read {}: {}", path.display(), message),
+            KeysError::Invalid(message) => write!(formatter, "{}", message),
+        }
+    }
+}
+
+/// keys verifying the tokens, the secret for HS* and the public keys of a PEM or JWKS file for the others
+/// the key of a token is picked by its `kid`, a PEM file or a JWKS of a single key verifies any `kid`
+#[derive(Clone)]
+pub struct JwtKeys {
+    validation: Validation,
+    secret: DecodingKey,
+    keys: HashMap<String, DecodingKey>,          // kid -> key, `""` for a PEM file or a JWK without kid
+    file: Option<(PathBuf, Option<SystemTime>)>, // keys file, modification time when read
+}
+
+impl JwtKeys {
+    /// HS256 with the secret, neither issuer nor audience checked
+    pub fn secret(secret: &str) -> JwtKeys {
+        JwtKeys {
+            validation: Validation::default(),
+            secret: DecodingKey::from_secret(secret.as_bytes()),
+            keys: HashMap::new(),
+            file: None,
+        }
+    }
+
+    /// the algorithms, keys and checks of `[auth]`, the HS* tokens are verified with `secret`
+    pub fn load(auth: &AuthConfig, secret: &str) -> Result<JwtKeys, KeysError> {
+        let algorithms = auth
+            .jwt_algorithms
+            .iter()
+            .map(|name| {
+                name.parse::<Algorithm>()
+                    .map_err(|_| KeysError::Invalid(format!("unknown algorithm `{}`", name)))
+            })
+            .collect::<Result<Vec<Algorithm>, KeysError>>()?;
+        let mut validation = Validation::new(*algorithms.first().unwrap_or(&Algorithm::HS256));
+        validation.algorithms = algorithms;
+        if let Some(issuer) = &auth.jwt_issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if !auth.jwt_audience.is_empty() {
+            validation.set_audience(&auth.jwt_audience);
+        }
+        let keys = JwtKeys {
+            validation,
+            secret: DecodingKey::from_secret(secret.as_bytes()),
+            keys: HashMap::new(),
+            file: auth.jwt_keys.clone().map(|path| (path, None)),
+        };
+        keys.reload()
+    }
+
+    /// the same keys and checks, with another secret
+    pub fn with_secret(&self, secret: &str) -> JwtKeys {
+        JwtKeys {
+            secret: DecodingKey::from_secret(secret.as_bytes()),
+            ..self.clone()
+        }
+    }
+
+    /// the keys file read again, if any
+    pub fn reload(&self) -> Result<JwtKeys, KeysError> {
+        let Some((path, _)) = &self.file else {
+            return Ok(self.clone());
+        };
+        let modified = modified(path);
+        let text = std::fs::read_to_string(path).map_err(|e| KeysError::Read {
+            path: path.clone(),
+            message: e.to_string(),
+        })?;
+        let keys = if text.trim_start().starts_with('{') {
+            jwks(&text)?
+        } else {
+            let algorithm = self.validation.algorithms.iter().copied().find(|algorithm| !is_hmac(*algorithm));
+            HashMap::from([(String::new(), pem(&text, algorithm)?)])
+        };
+        Ok(JwtKeys {
+            keys,
+            file: Some((path.clone(), modified)),
+            ..self.clone()
+        })
+    }
+
+    /// the keys file changed since it was read
+    pub fn changed(&self) -> bool {
+        self.file.as_ref().is_some_and(|(path, read)| modified(path) != *read)
+    }
+
+    /// the claims of the token, once verified
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, jsonwebtoken::errors::Error> {
+        let header = decode_header(token)?;
+        let key = if is_hmac(header.alg) {
+            Some(&self.secret)
+        } else {
+            header
+                .kid
+                .as_ref()
+                .and_then(|kid| self.keys.get(kid))
+                .or_else(|| self.keys.values().next().filter(|_| self.keys.len() == 1))
+        };
+        let Some(key) = key else {
+            debug!("AUTH / no key for kid {:?}", header.kid);
+            return Err(ErrorKind::InvalidToken.into());
+        };
+        if !self.validation.algorithms.contains(&header.alg) {
+            return Err(ErrorKind::InvalidAlgorithm.into());
+        }
+        // jsonwebtoken wants the algorithms of a single family, the one of the key
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![header.alg];
+        decode::<T>(token, key, &validation).map(|data| data.claims)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn jwks(text: &str) -> Result<HashMap<String, DecodingKey>, KeysError> {
+    let set: JwkSet = serde_json::from_str(text).map_err(|e| KeysError::Invalid(format!("invalid JWKS: {}", e)))?;
+    let mut keys = HashMap::new();
+    for jwk in set.keys.iter() {
+        let kid = jwk.common.key_id.clone().unwrap_or_default();
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| KeysError::Invalid(format!("invalid JWK {:?}: {}", kid, e)))?;
+        keys.insert(kid, key);
+    }
+    if keys.is_empty() {
+        return Err(KeysError::Invalid("no key in the JWKS".into()));
+    }
+    Ok(keys)
+}
+
+// a public key of the family of the algorithm
+fn pem(text: &str, algorithm: Option<Algorithm>) -> Result<DecodingKey, KeysError> {
+    let pem = text.as_bytes();
+    let key = match algorithm {
+        Some(Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512) => {
+            DecodingKey::from_rsa_pem(pem)
+        }
+        Some(Algorithm::ES256 | Algorithm::ES384) => DecodingKey::from_ec_pem(pem),
+        Some(Algorithm::EdDSA) => DecodingKey::from_ed_pem(pem),
+        _ => return Err(KeysError::Invalid("a PEM key needs an RS*, PS*, ES* or EdDSA algorithm".into())),
+    };
+    key.map_err(|e| KeysError::Invalid(format!("invalid PEM public key: {}", e)))
+}
+
+/// check the keys file every `interval`, the keys of the state are replaced when it changed
+/// the current keys stay if the new ones are invalid
+pub async fn watch_keys(state: Arc<State>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let keys = state.jwt_keys();
+        if !keys.changed() {
+            continue;
+        }
+        match keys.reload() {
+            Ok(keys) => {
+                info!("AUTH / jwt keys reloaded");
+                state.set_jwt_keys(keys);
+            }
+            Err(e) => {
+                error!("AUTH / fail to reload, keeping the current jwt keys: {}", e);
+                let mut keys = (*keys).clone();
+                if let Some((path, read)) = keys.file.as_mut() {
+                    *read = modified(path); // retried on the next change
+                }
+                state.set_jwt_keys(keys);
+            }
+        }
+    }
+}
+
+/// revoke the token ids published to the redis channel, e.g. `PUBLISH revocations {token_id}`,
+/// subscribed again a second after the connection is lost
+pub async fn listen_revocations(state: Arc<State>, client: redis::Client, channel: String) {
+    loop {
+        if let Err(e) = revocations(&state, &client, &channel).await {
+            error!("AUTH / revocations from {} stopped: {}", channel, e);
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn revocations(state: &State, client: &redis::Client, channel: &str) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    info!("AUTH / listening to revocations on {}", channel);
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let token_id: String = message.get_payload()?;
+        let until = chrono::Utc::now().timestamp() as u64 + REVOCATION_TTL.as_secs();
+        revoke_token(state, token_id.trim(), until).await;
+    }
+    Ok(())
+}
+
+/// asks the application whether a client may join, for decisions depending on its data
+///
+/// `{"id", "reply_to", "conn_id", "topic", "payload", "user_id", "claims"}` is published to `channel`,
+/// and the answer `{"id", "allow": bool, "response": ..., "events": [...]}` is expected on `reply_to` within `timeout`
+/// `response` is the join reply, or the reason of the refusal, and `events` the events the client may push, any if not set
+/// both go through the broker of the socket, see `Broker::send`
+pub struct RedisJoinAuth {
+    channel: String,
+    reply_to: String, // `{channel}:reply:{random}`, one per server
+    timeout: Duration,
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<JoinAnswer>>>>, // request id -> waiting join
+    listener: tokio::sync::Mutex<Option<JoinHandle<()>>>,              // relays the answers to `pending`
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinAnswer {
+    id: String,
+    allow: bool,
+    #[serde(default)]
+    response: Option<Value>,
+    #[serde(default)]
+    events: Option<Vec<String>>,
+}
+
+impl JoinAnswer {
+    fn grant(self) -> Result<Grant, Value> {
+        if !self.allow {
+            return Err(self.response.unwrap_or_else(|| json!({ "reason": "unauthorized" })));
+        }
+        Ok(Grant {
+            events: self.events,
+            response: self.response,
+            ..Default::default()
+        })
+    }
+}
+
+impl RedisJoinAuth {
+    pub fn new(channel: &str, timeout: Duration) -> Self {
+        RedisJoinAuth {
+            channel: channel.to_string(),
+            reply_to: format!("{}:reply:{}", channel, crate::utils::random_string(8)),
+            timeout,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            listener: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    // subscribed to `reply_to` before the first request, and again once the subscription is lost
+    async fn listen(&self, broker: &dyn Broker) -> redis::RedisResult<()> {
+        let mut listener = self.listener.lock().await;
+        if listener.as_ref().is_some_and(|task| !task.is_finished()) {
+            return Ok(());
+        }
+        let mut messages = broker.subscribe(&self.reply_to).await?;
+        info!("AUTH / waiting for join answers on {}", self.reply_to);
+        let pending = self.pending.clone();
+        let reply_to = self.reply_to.clone();
+        *listener = Some(tokio::spawn(async move {
+            while let Some(payload) = messages.next().await {
+                match serde_json::from_str::<JoinAnswer>(&payload) {
+                    Ok(answer) => match pending.lock().unwrap().remove(&answer.id) {
+                        Some(tx) => drop(tx.send(answer)),
+                        None => debug!("AUTH / join answer {} is late", answer.id),
+                    },
+                    Err(e) => warn!("AUTH / invalid join answer `{}`: {}", payload, e),
+                }
+            }
+            error!("AUTH / join answers from {} stopped", reply_to);
+        }));
+        Ok(())
+    }
+
+    async fn request(&self, broker: &dyn Broker, id: &str, request: Value) -> redis::RedisResult<oneshot::Receiver<JoinAnswer>> {
+        self.listen(broker).await?;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.to_string(), tx);
+        broker.send(&self.channel, request.to_string()).await?;
+        Ok(rx)
+    }
+}
+
+#[async_trait]
+impl JoinAuth for RedisJoinAuth {
+    async fn authorize(&self, state: &State, conn_id: &str, identity: Option<&Identity>, topic: &str, payload: &Value) -> Result<Grant, Value> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let request = json!({
+            "id": id,
+            "reply_to": self.reply_to,
+            "conn_id": conn_id,
+            "topic": topic,
+            "payload": payload,
+            "user_id": identity.map(|identity| &identity.user_id),
+            "claims": identity.map(|identity| &identity.claims),
+        });
+        let answer = match self.request(state.broker.as_ref(), &id, request).await {
+            Ok(rx) => tokio::time::timeout(self.timeout, rx).await,
+            Err(e) => {
+                error!("AUTH / fail to ask {} about {} joining {}: {}", self.channel, conn_id, topic, e);
+                self.pending.lock().unwrap().remove(&id);
+                return Err(json!({ "reason": "unavailable" }));
+            }
+        };
+        self.pending.lock().unwrap().remove(&id);
+        match answer {
+            Ok(Ok(answer)) => answer.grant(),
+            _ => {
+                warn!("AUTH / no answer from {} about {} joining {}", self.channel, conn_id, topic);
+                Err(json!({ "reason": "timeout" }))
+            }
+        }
+    }
+}
+
+/// the join payload carries `{"token": ...}`, a JWT granting the topic, see `Claims`
+/// the default one verifies with the keys of the state, as reloaded by `State::set_jwt_keys`
+#[derive(Default)]
+pub struct JwtAuth {
+    keys: Option<Arc<JwtKeys>>, // the keys of the state if not set
+}
+
+impl JwtAuth {
+    /// HS256 tokens signed with the secret, whatever the keys of the state
+    pub fn new(secret: &str) -> Self {
+        JwtAuth {
+            keys: Some(Arc::new(JwtKeys::secret(secret))),
+        }
+    }
+
+    fn keys(&self, state: &State) -> Arc<JwtKeys> {
+        self.keys.clone().unwrap_or_else(|| state.jwt_keys())
+    }
+
+    pub fn verify(&self, state: &State, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        self.keys(state).decode::<Claims>(token)
+    }
+
+    /// the api token is valid and grants the scope
+    pub fn verify_scope(&self, state: &State, token: &str, scope: &str) -> bool {
+        match self.keys(state).decode::<ApiClaims>(token) {
+            Ok(claims) => claims.scope.split_whitespace().any(|s| s == scope),
+            Err(e) => {
+                warn!("AUTH / invalid api token for scope {}: {}", scope, e);
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl JoinAuth for JwtAuth {
+    /// without a token in the payload, the claims of the connect token grant the topics
+    async fn authorize(&self, state: &State, conn_id: &str, identity: Option<&Identity>, topic: &str, payload: &Value) -> Result<Grant, Value> {
+        let verified = match (payload.get("token").and_then(Value::as_str), identity) {
+            (Some(token), _) => self.verify(state, token),
+            (None, Some(identity)) => Ok(serde_json::from_value::<Claims>(identity.claims.clone()).unwrap_or_default()),
+            (None, None) => return Err(json!({ "reason": "unauthorized" })),
+        };
+        match verified {
+            Ok(claims) if claims.may_subscribe(topic) => Ok(claims.grant(topic)),
+            Ok(claims) => {
+                warn!("AUTH / conn {}, token for {} used to join {}", conn_id, claims.channel, topic);
+                Err(json!({ "reason": "unauthorized" }))
+            }
+            Err(e) => {
+                warn!("AUTH / conn {}, invalid token for {}: {}", conn_id, topic, e);
+                Err(json!({ "reason": "unauthorized" }))
+            }
+        }
+    }
+}
+
+/// `typ` claim of the connect tokens, the other tokens signed with the same keys are refused at connect
+pub const CONNECT_TOKEN_TYPE: &str = "connect";
+
+/// the JWT in the `param` of the connect params, verified with the keys of the state,
+/// it has `"typ": "connect"` and `sub`, the user id
+pub struct JwtConnectAuth {
+    param: String,
+    required: bool, // connections without the param are refused, anonymous otherwise
+}
+
+impl JwtConnectAuth {
+    pub fn new(param: &str, required: bool) -> Self {
+        JwtConnectAuth {
+            param: param.to_string(),
+            required,
+        }
+    }
+}
+
+#[async_trait]
+impl ConnectAuth for JwtConnectAuth {
+    async fn authenticate(&self, state: &State, params: &HashMap<String, String>) -> Result<Option<Identity>, String> {
+        let Some(token) = params.get(&self.param) else {
+            return if self.required {
+                Err(format!("{} required", self.param))
+            } else {
+                Ok(None)
+            };
+        };
+        let claims = state
+            .jwt_keys()
+            .decode::<Value>(token)
+            .map_err(|e| format!("invalid {}: {}", self.param, e))?;
+        // the channel tokens of `/token` and the api tokens are not for connecting
+        if claims.get("typ").and_then(Value::as_str) != Some(CONNECT_TOKEN_TYPE) {
+            return Err(format!("{} is not a connect token", self.param));
+        }
+        let user_id = claims.get("sub").and_then(Value::as_str).unwrap_or_default().to_string();
+        if user_id.is_empty() {
+            return Err(format!("{} has no subject", self.param));
+        }
+        Ok(Some(Identity { user_id, claims }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn token(secret: &str, channel: &str, exp: usize) -> String {
+        let claims = Claims {
+            id: "1".into(),
+            channel: channel.into(),
+            exp,
+            ..Default::default()
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_jwt_auth() {
+        let state = State::new(crate::channel::ChannelControl::new(), Arc::new(crate::broker::LocalBroker::default()), None, "other".into());
+        let auth = JwtAuth::new("secret");
+        let exp = chrono::Utc::now().timestamp() as usize + 60;
+
+        let payload = json!({ "token": token("secret", "room:1", exp) });
+        assert!(auth.authorize(&state, "conn1", None, "room:1", &payload).await.is_ok());
+        assert!(auth.authorize(&state, "conn1", None, "room:2", &payload).await.is_err());
+
+        let payload = json!({ "token": token("other", "room:1", exp) });
+        assert!(auth.authorize(&state, "conn1", None, "room:1", &payload).await.is_err());
+
+        let payload = json!({ "token": token("secret", "room:1", exp - 3600) });
+        assert!(auth.authorize(&state, "conn1", None, "room:1", &payload).await.is_err());
+
+        assert!(auth.authorize(&state, "conn1", None, "room:1", &json!({})).await.is_err());
+
+        // the claims of the connect token, unless the payload has its own token
+        let identity = Identity {
+            user_id: "1".into(),
+            claims: json!({ "sub": "1", "subscribe": ["room:*"], "exp": exp }),
+        };
+        assert!(auth.authorize(&state, "conn1", Some(&identity), "room:1", &json!({})).await.is_ok());
+        assert!(auth.authorize(&state, "conn1", Some(&identity), "lobby", &json!({})).await.is_err());
+        let payload = json!({ "token": token("secret", "lobby", exp) });
+        assert!(auth.authorize(&state, "conn1", Some(&identity), "lobby", &payload).await.is_ok());
+
+        // the default one follows the keys of the state
+        let auth = JwtAuth::default();
+        let payload = json!({ "token": token("secret", "room:1", exp) });
+        assert!(auth.authorize(&state, "conn1", None, "room:1", &payload).await.is_err());
+        state.set_jwt_secret("secret");
+        assert!(auth.authorize(&state, "conn1", None, "room:1", &payload).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_jwt_connect_auth() {
+        let state = State::new(crate::channel::ChannelControl::new(), Arc::new(crate::broker::LocalBroker::default()), None, "secret".into());
+        let exp = chrono::Utc::now().timestamp() as usize + 60;
+        let params = |token: &str| HashMap::from([("userToken".to_string(), token.to_string())]);
+        let user_token =
+            encode(&Header::default(), &json!({ "sub": "u1", "typ": "connect", "exp": exp }), &EncodingKey::from_secret(b"secret")).unwrap();
+
+        let optional = JwtConnectAuth::new("userToken", false);
+        assert_eq!(optional.authenticate(&state, &HashMap::new()).await, Ok(None));
+        let identity = optional.authenticate(&state, &params(&user_token)).await.unwrap().unwrap();
+        assert_eq!(identity.user_id, "u1");
+        assert_eq!(identity.claims["exp"], exp);
+        assert!(optional.authenticate(&state, &params("invalid")).await.is_err()); // refused even if optional
+        let channel_token = token("secret", "room:1", exp);
+        assert_eq!(optional.authenticate(&state, &params(&channel_token)).await, Err("userToken is not a connect token".into()));
+        let api_token =
+            encode(&Header::default(), &json!({ "sub": "u1", "scope": "publish", "exp": exp }), &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(optional.authenticate(&state, &params(&api_token)).await.is_err());
+        let anonymous = encode(&Header::default(), &json!({ "typ": "connect", "exp": exp }), &EncodingKey::from_secret(b"secret")).unwrap();
+        assert_eq!(optional.authenticate(&state, &params(&anonymous)).await, Err("userToken has no subject".into()));
+
+        let required = JwtConnectAuth::new("userToken", true);
+        assert_eq!(required.authenticate(&state, &HashMap::new()).await, Err("userToken required".into()));
+        assert!(required.authenticate(&state, &params(&user_token)).await.is_ok());
+    }
+
+    #[test]
+    fn test_claims_grants() {
+        let claims: Claims = serde_json::from_value(json!({
+            "id": "1",
+            "subscribe": ["room:*", "lobby"],
+            "publish": { "room:*": ["new_msg"], "room:ops": ["*"] },
+            "exp": 0,
+        }))
+        .unwrap();
+        assert!(claims.may_subscribe("room:1") && claims.may_subscribe("lobby"));
+        assert!(!claims.may_subscribe("admin"));
+
+        assert!(claims.grant("room:1").may_publish("new_msg"));
+        assert!(!claims.grant("room:1").may_publish("delete"));
+        assert!(claims.grant("room:ops").may_publish("delete"));
+        assert!(!claims.grant("lobby").may_publish("new_msg"));
+        assert_eq!(claims.grant("lobby").token_id.as_deref(), Some("1"));
+        assert_eq!(claims.grant("lobby").expires, None);
+
+        // the tokens of `/token`
+        let claims = Claims {
+            channel: "room:1".into(),
+            ..Default::default()
+        };
+        assert!(claims.may_subscribe("room:1") && !claims.may_subscribe("room:2"));
+        assert_eq!(claims.grant("room:1"), Grant::default());
+        assert!(Grant::default().may_publish("anything"));
+    }
+
+    #[test]
+    fn test_join_answers() {
+        let answer = |value: Value| serde_json::from_value::<JoinAnswer>(value).unwrap().grant();
+        let grant = answer(json!({ "id": "1", "allow": true, "response": { "role": "member" }, "events": ["new_msg"] })).unwrap();
+        assert_eq!(grant.response, Some(json!({ "role": "member" })));
+        assert!(grant.may_publish("new_msg") && !grant.may_publish("delete"));
+        assert_eq!(answer(json!({ "id": "1", "allow": true })), Ok(Grant::default()));
+
+        assert_eq!(answer(json!({ "id": "1", "allow": false })), Err(json!({ "reason": "unauthorized" })));
+        assert_eq!(answer(json!({ "id": "1", "allow": false, "response": { "reason": "banned" } })), Err(json!({ "reason": "banned" })));
+    }
+
+    // an ES256 key pair, the private key as PEM and the public key as PEM and as JWK
+    fn es256(kid: &str) -> (EncodingKey, String, Value) {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+        let key = rcgen::KeyPair::generate().unwrap();
+        let point = key.public_key_raw(); // uncompressed, 0x04 x y
+        let jwk = json!({
+            "kty": "EC", "crv": "P-256", "alg": "ES256", "kid": kid,
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        });
+        (EncodingKey::from_ec_pem(key.serialize_pem().as_bytes()).unwrap(), key.public_key_pem(), jwk)
+    }
+
+    fn es256_token(key: &EncodingKey, kid: Option<&str>, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = kid.map(|kid| kid.to_string());
+        encode(&header, &claims, key).unwrap()
+    }
+
+    #[test]
+    fn test_jwt_keys() {
+        let dir = std::env::temp_dir().join(format!("channel-jwt-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let claims = json!({ "sub": "billing", "scope": "publish", "exp": exp, "iss": "https://auth.example.com", "aud": "channeld" });
+
+        // a PEM key, any kid
+        let (private1, public1, jwk1) = es256("k1");
+        let path = dir.join("key.pem");
+        std::fs::write(&path, &public1).unwrap();
+        let mut config = AuthConfig {
+            jwt_algorithms: vec!["ES256".into(), "HS256".into()],
+            jwt_keys: Some(path.clone()),
+            jwt_issuer: Some("https://auth.example.com".into()),
+            jwt_audience: vec!["channeld".into()],
+            ..Default::default()
+        };
+        let keys = JwtKeys::load(&config, "secret").unwrap();
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private1, None, claims.clone())).is_ok());
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private1, Some("any"), claims.clone())).is_ok());
+        let hs256 = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(keys.decode::<ApiClaims>(&hs256).is_ok());
+        let mut other = claims.clone();
+        other["iss"] = json!("https://evil.example.com");
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private1, None, other)).is_err());
+        let mut other = claims.clone();
+        other["aud"] = json!("other");
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private1, None, other)).is_err());
+
+        // a JWKS, keys picked by kid
+        let (private2, _, jwk2) = es256("k2");
+        let path = dir.join("jwks.json");
+        std::fs::write(&path, json!({ "keys": [jwk1] }).to_string()).unwrap();
+        config.jwt_keys = Some(path.clone());
+        let keys = JwtKeys::load(&config, "secret").unwrap();
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private1, Some("k1"), claims.clone())).is_ok());
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private2, Some("k2"), claims.clone())).is_err());
+        assert!(!keys.changed());
+
+        // rotated, k2 is added
+        std::thread::sleep(Duration::from_millis(10));
+        std::fs::write(&path, json!({ "keys": [jwk1, jwk2] }).to_string()).unwrap();
+        assert!(keys.changed());
+        let keys = keys.reload().unwrap();
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private2, Some("k2"), claims.clone())).is_ok());
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private2, Some("k3"), claims.clone())).is_err());
+        assert!(keys.decode::<ApiClaims>(&es256_token(&private2, None, claims.clone())).is_err()); // which one?
+
+        std::fs::write(&path, "{}").unwrap();
+        assert!(keys.reload().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_scope() {
+        let state = State::new(crate::channel::ChannelControl::new(), Arc::new(crate::broker::LocalBroker::default()), None, "secret".into());
+        let auth = JwtAuth::default();
+        let claims = ApiClaims {
+            sub: "billing".into(),
+            scope: "read publish".into(),
+            exp: chrono::Utc::now().timestamp() as usize + 60,
+        };
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(auth.verify_scope(&state, &token, "publish"));
+        assert!(!auth.verify_scope(&state, &token, "admin"));
+        assert!(!auth.verify_scope(&state, "invalid", "publish"));
+    }
+}
@@ -1,106 +1,459 @@
 use axum::{
-    extract::{State as AxumState, WebSocketUpgrade},
+    extract::State as AxumState,
+    http::{header::CONTENT_TYPE, StatusCode},
     response::IntoResponse,
     routing::get,
-    Router,
+    Json, Router,
 };
 use channel::{
-    channel::ChannelControl,
-    utils::random_string,
-    websocket::{add_channel, axum_on_connected, datetime_handler, State},
+    admin, assets,
+    auth::{self, JwtKeys},
+    broker::RedisBroker,
+    config::{AuthConfig, Config},
+    cors::{self, Origins},
+    health,
+    listener::{self, ListenAddr, Listener},
+    metrics,
+    proxy::Proxies,
+    socket::ChannelSocket,
+    tls,
+    websocket::{add_channel, admin_feed, datetime_handler, set_special_channels, watch_expiry, State},
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use redis::Client;
-use serde::Deserialize;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tower_http::services::ServeDir;
-use tracing::{error, info};
-use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
-
-async fn websocket_handler(ws: WebSocketUpgrade, AxumState(state): AxumState<Arc<State>>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| axum_on_connected(socket, state))
-}
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{error, info, warn};
+use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry};
 
 // use clap to parse command line arguments
-#[derive(Debug, Deserialize, Parser)]
-#[command(name = "wd", about = "channel server")]
+// every option overrides its key of the config file and of the CHANNELD_* env, see `channel::config::Config`
+#[derive(Debug, Parser)]
+#[command(name = "channeld", about = "channel server")]
 struct Options {
-    #[arg(long, default_value = "127.0.0.1")]
+    /// TOML config file
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    #[arg(long)]
     host: Option<String>,
 
-    #[arg(long, default_value = "5000")]
+    #[arg(long)]
     port: Option<u16>,
 
-    #[arg(long, default_value = None)]
+    #[arg(long)]
     redis_url: Option<String>,
 
-    #[arg(long, default_value = None)]
-    redis_topic: Option<String>,
+    /// bearer token of the admin and publish api at /api, the admin api is disabled if not set
+    #[arg(long)]
+    api_key: Option<String>,
+
+    /// secret of the channel and api tokens, a random one if not set
+    #[arg(long)]
+    jwt_secret: Option<String>,
+
+    /// comma separated algorithms of the tokens, e.g. `RS256,ES256`, HS256 if not set
+    #[arg(long)]
+    jwt_algorithms: Option<String>,
+
+    /// PEM public key or JWKS file verifying the RS*, PS*, ES* and EdDSA tokens
+    #[arg(long)]
+    jwt_keys: Option<String>,
+
+    /// required issuer of the tokens
+    #[arg(long)]
+    jwt_issuer: Option<String>,
+
+    /// comma separated audiences accepted in the tokens
+    #[arg(long)]
+    jwt_audience: Option<String>,
+
+    /// authentication of the websockets by the `userToken` param: off, optional or required
+    #[arg(long)]
+    connect_auth: Option<String>,
+
+    /// path of the bundled phoenix.js demo page, empty to disable it
+    #[arg(long)]
+    index_path: Option<String>,
+
+    /// path of the bundled admin page, empty to disable it
+    #[arg(long)]
+    admin_path: Option<String>,
+
+    /// directory whose files override the bundled pages, other files in it are served too
+    #[arg(long)]
+    static_dir: Option<String>,
+
+    /// seconds between the stats snapshots of the admin channel
+    #[arg(long)]
+    admin_stats_interval: Option<u64>,
+
+    /// event sent to every joined agent on shutdown, e.g. a custom one telling clients to reconnect elsewhere
+    #[arg(long)]
+    shutdown_event: Option<String>,
+
+    /// JSON payload of the shutdown event
+    #[arg(long)]
+    shutdown_payload: Option<String>,
+
+    /// seconds to flush and close the connections on shutdown
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+
+    /// comma separated origins allowed to open websockets and to call the api, e.g. `https://*.example.com`, any if not set
+    #[arg(long)]
+    origins: Option<String>,
+
+    /// PEM certificate chain, serves https and wss with --tls-key
+    #[arg(long)]
+    tls_cert: Option<String>,
+
+    /// PEM private key of the certificate
+    #[arg(long)]
+    tls_key: Option<String>,
+
+    /// PEM roots to verify client certificates against, mutual TLS if set
+    #[arg(long)]
+    tls_client_ca: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// configuration tools
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// validate the configuration and print the effective one
+    Check,
+}
+
+impl Options {
+    // the config keys given on the command line
+    fn config_keys(&self) -> Vec<(&'static str, String)> {
+        let keys = [
+            ("host", self.host.clone()),
+            ("port", self.port.map(|v| v.to_string())),
+            ("redis.url", self.redis_url.clone()),
+            ("auth.api_key", self.api_key.clone()),
+            ("auth.jwt_secret", self.jwt_secret.clone()),
+            ("auth.jwt_algorithms", self.jwt_algorithms.clone()),
+            ("auth.jwt_keys", self.jwt_keys.clone()),
+            ("auth.jwt_issuer", self.jwt_issuer.clone()),
+            ("auth.jwt_audience", self.jwt_audience.clone()),
+            ("auth.connect", self.connect_auth.clone()),
+            ("pages.index", self.index_path.clone()),
+            ("pages.admin", self.admin_path.clone()),
+            ("pages.dir", self.static_dir.clone()),
+            ("admin.stats_interval", self.admin_stats_interval.map(|v| v.to_string())),
+            ("shutdown.event", self.shutdown_event.clone()),
+            ("shutdown.payload", self.shutdown_payload.clone()),
+            ("shutdown.timeout", self.shutdown_timeout.map(|v| v.to_string())),
+            ("origins.allow", self.origins.clone()),
+            ("tls.cert", self.tls_cert.clone()),
+            ("tls.key", self.tls_key.clone()),
+            ("tls.client_ca", self.tls_client_ca.clone()),
+        ];
+        keys.into_iter().filter_map(|(key, value)| value.map(|v| (key, v))).collect()
+    }
+}
+
+// SIGTERM from the deployment, or ctrl-c
+async fn shutdown_signal() {
+    let ctrl_c = async { tokio::signal::ctrl_c().await.expect("fail to listen to ctrl-c") };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("fail to listen to SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("SIGINT received"),
+        _ = terminate => info!("SIGTERM received"),
+    }
+}
+
+// SIGHUP loads the config again and applies its reloadable keys, connections stay
+// an invalid config is rejected and the current one stays active
+#[cfg(unix)]
+async fn reload_on_sighup(
+    state: Arc<State>, path: Option<PathBuf>, cli: Vec<(&'static str, String)>, mut current: Config, filter: reload::Handle<EnvFilter, Registry>,
+    mut feeds: HashMap<String, JoinHandle<()>>,
+) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("fail to listen to SIGHUP");
+    while hangup.recv().await.is_some() {
+        info!("RELOAD / SIGHUP received, loading the config ...");
+        let config = match Config::load(path.as_deref(), |k| std::env::var(k).ok(), &cli) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("RELOAD / invalid configuration, keeping the current one: {}", e);
+                continue;
+            }
+        };
+
+        let changes = current.diff(&config);
+        if changes.is_empty() {
+            info!("RELOAD / no change");
+        }
+        for change in changes.iter() {
+            if change.reloadable() {
+                info!("RELOAD / {}", change);
+            } else {
+                warn!("RELOAD / {}, ignored until restart", change);
+            }
+        }
+
+        if config.log != current.log {
+            match std::env::var("RUST_LOG") {
+                Ok(_) => warn!("RELOAD / log filter kept, RUST_LOG is set"),
+                Err(_) => {
+                    if let Err(e) = filter.reload(EnvFilter::new(&config.log)) {
+                        error!("RELOAD / fail to reload the log filter: {}", e);
+                    }
+                }
+            }
+        }
+        match &config.auth.jwt_secret {
+            Some(secret) if config.auth.jwt_secret != current.auth.jwt_secret => state.set_jwt_secret(secret),
+            None if current.auth.jwt_secret.is_some() => warn!("RELOAD / jwt secret unset, the current one is kept"),
+            _ => {}
+        }
+        let jwt = |auth: &AuthConfig| (auth.jwt_algorithms.clone(), auth.jwt_keys.clone(), auth.jwt_issuer.clone(), auth.jwt_audience.clone());
+        let mut jwt_loaded = true;
+        if jwt(&config.auth) != jwt(&current.auth) {
+            match JwtKeys::load(&config.auth, "") {
+                Ok(keys) => state.set_jwt_keys(keys),
+                Err(e) => {
+                    error!("RELOAD / fail to load the jwt keys, keeping the current ones: {}", e);
+                    jwt_loaded = false;
+                }
+            }
+        }
+        if config.channels.special != current.channels.special {
+            set_special_channels(&state, config.channels.special.clone()).await;
+            feed_special_channels(&state, &config, &mut feeds);
+        }
+        if config.origins != current.origins {
+            state.set_origins(Origins::new(&config.origins.allow));
+        }
+        if config.proxies != current.proxies {
+            state.set_proxies(Proxies::new(&config.proxies.trusted));
+        }
+        if config.limits != current.limits {
+            state.limits.set_config(config.limits.clone());
+        }
+        if config.messages != current.messages {
+            state.set_message_limits(config.messages.clone());
+        }
+
+        // the keys needing a restart keep their current values, to be reported again on the next reload
+        current.log = config.log;
+        current.auth.jwt_secret = config.auth.jwt_secret.or(current.auth.jwt_secret);
+        if jwt_loaded {
+            current.auth.jwt_algorithms = config.auth.jwt_algorithms;
+            current.auth.jwt_keys = config.auth.jwt_keys;
+            current.auth.jwt_issuer = config.auth.jwt_issuer;
+            current.auth.jwt_audience = config.auth.jwt_audience;
+        }
+        current.channels = config.channels;
+        current.origins = config.origins;
+        current.proxies = config.proxies;
+        current.limits = config.limits;
+        current.messages = config.messages;
+    }
+}
+
+// the tasks feeding the special channels, `admin_feed` for admin and `datetime_handler` for system,
+// started for the channels becoming special and aborted for the ones which are no longer
+fn feed_special_channels(state: &Arc<State>, config: &Config, feeds: &mut HashMap<String, JoinHandle<()>>) {
+    feeds.retain(|name, feed| {
+        let special = config.channels.special.contains(name);
+        if !special {
+            feed.abort();
+            info!("ADD_CH / {} feed stopped", name);
+        }
+        special
+    });
+    for name in &config.channels.special {
+        if feeds.contains_key(name) {
+            continue;
+        }
+        let feed = match name.as_str() {
+            "admin" => tokio::spawn(admin_feed(state.clone(), Duration::from_secs(config.admin.stats_interval))),
+            "system" => tokio::spawn(datetime_handler(state.clone(), name.clone())),
+            _ => continue,
+        };
+        info!("ADD_CH / {} feed started", name);
+        feeds.insert(name.clone(), feed);
+    }
+}
+
+// prometheus scraping
+async fn metrics_handler(AxumState(state): AxumState<Arc<State>>) -> impl IntoResponse {
+    let body = metrics::render(&*state.ctl.lock().await).await;
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+// the process is alive
+async fn healthz_handler() -> &'static str {
+    "ok"
+}
+
+// redis and the special channels are working, 503 with the failing subsystems otherwise
+async fn readyz_handler(AxumState(state): AxumState<Arc<State>>) -> impl IntoResponse {
+    match health::readiness(&state).await {
+        Ok(()) => (StatusCode::OK, Json(serde_json::json!({ "ready": true, "failing": [] }))),
+        Err(failing) => (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({ "ready": false, "failing": failing }))),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv::dotenv().ok(); // load .env if possible
 
-    // 设置 tracing 使用 EnvFilter
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    tracing_subscriber::fmt()
-        .with_env_filter(env_filter)
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
-
     let options = Options::parse(); // exit on error
-    if options.redis_url.is_none() || options.redis_topic.is_none() {
-        error!("redis_url and redis_topic must be provided");
+    let config = match Config::load(options.config.as_deref(), |k| std::env::var(k).ok(), &options.config_keys()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration, {}", e);
+            std::process::exit(1);
+        }
+    };
+    // the certificates are checked by `config check` too
+    let acceptor = match &config.tls.cert {
+        Some(_) => match tls::Acceptor::new(&config.tls) {
+            Ok(acceptor) => Some(Arc::new(acceptor)),
+            Err(e) => {
+                eprintln!("invalid configuration, `tls`: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // the HS* secret is the one of the state, see `State::set_jwt_keys`
+    let jwt_keys = match JwtKeys::load(&config.auth, "") {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("invalid configuration, `auth.jwt_keys`: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = options.command
+    {
+        print!("{}", config.to_toml());
         return Ok(());
     }
 
-    let redis_url = options.redis_url.unwrap();
+    // 设置 tracing 使用 EnvFilter, reloadable on SIGHUP
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log));
+    let (env_filter, filter_handle) = reload::Layer::new(env_filter);
+    tracing_subscriber::registry()
+        .with(env_filter)
+        .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE))
+        .init();
 
-    let channel_control = ChannelControl::new();
-    // channel_control.channel_add("phoenix".into(), None).await;
-    // channel_control.channel_add("admin".into(), None).await;
-    //
-    // channel_control.channel_add("system".into(), None).await;
-    // channel_control.channel_add("streaming".into(), None).await;
+    let redis_url = config.redis.url.clone().unwrap_or_default(); // required by validation
+    let shutdown_payload: serde_json::Value = serde_json::from_str(&config.shutdown.payload)?;
 
     let redis_client = Client::open(redis_url.clone())?;
-    let state = Arc::new(State {
-        ctl: Mutex::new(channel_control),
-        redis_url: redis_url.clone(),
-        redis_client,
-        jwt_secret: random_string(8),
-    });
-
-    // state.ctl.lock().await.channel_add("phoenix".into(), None).await;
-    {
-        let channel_name: String = "phoenix".into();
-        add_channel(&state.ctl, state.redis_client.clone(), channel_name.clone()).await;
+    let socket = ChannelSocket::builder()
+        .broker(RedisBroker::new(redis_client).envelope(config.redis.envelope))
+        .config(&config)
+        .build();
+    let state = socket.state();
+    state.set_jwt_keys(jwt_keys);
+    tokio::spawn(auth::watch_keys(state.clone(), Duration::from_secs(config.auth.jwt_reload_interval)));
+    tokio::spawn(watch_expiry(state.clone(), Duration::from_secs(1)));
+    if let Some(channel) = config.redis.revocations.clone() {
+        tokio::spawn(auth::listen_revocations(state.clone(), Client::open(redis_url.clone())?, channel));
     }
 
-    {
-        let channel_name: String = "admin".into();
-        add_channel(&state.ctl, state.redis_client.clone(), channel_name.clone()).await;
+    // phoenix, admin and system by default
+    set_special_channels(&state, config.channels.special.clone()).await;
+    for channel_name in state.special_channels() {
+        add_channel(&state.ctl, state.broker.clone(), channel_name.clone()).await;
     }
+    let mut feeds = HashMap::new();
+    feed_special_channels(&state, &config, &mut feeds);
 
-    {
-        let channel_name: String = "system".into();
-        add_channel(&state.ctl, state.redis_client.clone(), channel_name.clone()).await;
-        tokio::spawn(datetime_handler(state.clone(), channel_name.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(state.clone(), options.config.clone(), options.config_keys(), config.clone(), filter_handle, feeds));
+    #[cfg(not(unix))]
+    drop((filter_handle, feeds));
+
+    // route sets of the listeners, see `config::ROUTES`
+    let mut routes = HashMap::new();
+    // the origins are checked by the websocket handler, and by CORS of the http api
+    state.set_origins(Origins::new(&config.origins.allow));
+    routes.insert("socket", socket.router());
+    routes.insert("token", socket.token_router(config.auth.api_key.as_deref()).layer(cors::layer(state.clone())));
+    let publish = admin::publish_router(state.clone(), config.auth.api_key.as_deref());
+    routes.insert("publish", publish.layer(cors::layer(state.clone())));
+    match &config.auth.api_key {
+        Some(api_key) => {
+            routes.insert("admin", admin::router(state.clone(), api_key).layer(cors::layer(state.clone())));
+        }
+        None => info!("admin api disabled, no api key"),
     }
+    let pages = assets::Pages {
+        index: Some(config.pages.index.clone()).filter(|path| !path.is_empty()),
+        admin: Some(config.pages.admin.clone()).filter(|path| !path.is_empty()),
+        dir: config.pages.dir.clone(),
+    };
+    routes.insert("pages", assets::router(&pages));
+    routes.insert("metrics", Router::new().route("/metrics", get(metrics_handler)).with_state(state.clone()));
+    let health = Router::new()
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler));
+    routes.insert("health", health.with_state(state.clone()));
 
-    let host = options.host.unwrap();
-    let port = options.port.unwrap();
+    if let Some(acceptor) = &acceptor {
+        tokio::spawn(acceptor.clone().watch(Duration::from_secs(config.tls.reload_interval)));
+    }
+    let (stop_tx, stop_rx) = watch::channel(());
+    let mut servers = vec![];
+    for listener_config in config.listeners() {
+        let address = ListenAddr::parse(&listener_config.address)?; // validated
+        let mut app = Router::new();
+        for route in &listener_config.routes {
+            match routes.get(route.as_str()) {
+                Some(routes) => app = app.merge(routes.clone()),
+                None => warn!("SERVE / route `{}` of {} is not served, `auth.api_key` is not set", route, address),
+            }
+        }
+        let listener = Listener::bind(&address)
+            .await
+            .map_err(|e| format!("fail to listen on {}: {}", address, e))?;
+        let tls = matches!(address, ListenAddr::Tcp(_)) && acceptor.is_some();
+        info!("SERVE / serving {} at {}{}", listener_config.routes.join(", "), address, if tls { " over TLS" } else { "" });
+        let mut stop_rx = stop_rx.clone();
+        let signal = async move {
+            let _ = stop_rx.changed().await;
+        };
+        servers.push(tokio::spawn(listener::serve(listener, acceptor.clone(), app, signal)));
+    }
 
-    let app = Router::new()
-        .route("/websocket", get(websocket_handler))
-        .nest_service("/", ServeDir::new("channel/src/bin")) // 需要把 html 直接包含到 binary 中，方便发布
-        .with_state(state.clone());
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", host, port)).await.unwrap();
+    // stops accepting connections on signal, websockets are upgraded already and closed below
+    shutdown_signal().await;
+    let _ = stop_tx.send(());
+    futures::future::join_all(servers).await;
 
-    info!("serving at {}:{} ...", host, port);
-    axum::serve(listener, app).await.unwrap();
+    info!("shutting down, {} to every agent ...", config.shutdown.event);
+    let deadline = Duration::from_secs(config.shutdown.timeout);
+    socket.shutdown(&config.shutdown.event, shutdown_payload, deadline).await;
 
     Ok(())
 }
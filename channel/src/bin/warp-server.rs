@@ -2,7 +2,10 @@
 
 use std::{path::PathBuf, sync::Arc};
 
+use channel::auth::Claims;
+use channel::broker::RedisBroker;
 use channel::channel::ChannelControl;
+use channel::channel::ConnInfo;
 use channel::websocket::{datetime_handler, warp_on_connected, State};
 use clap::{Command, CommandFactory, Parser, ValueHint};
 use futures::{sink::SinkExt, stream::StreamExt};
@@ -32,13 +35,6 @@ struct TokenRequest {
     channel: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    id: String,
-    channel: String,
-    exp: usize,
-}
-
 #[derive(Debug)]
 enum TokenError {
     ChannelNotFound,
@@ -65,9 +61,10 @@ async fn generate_token(req: TokenRequest, state: Arc<State>) -> Result<impl war
         id,
         channel: req.channel,
         exp: expiration,
+        ..Default::default()
     };
 
-    let key = EncodingKey::from_secret(state.jwt_secret.as_bytes());
+    let key = EncodingKey::from_secret(state.jwt_secret().as_bytes());
 
     match encode(&Header::default(), &claims, &key) {
         Ok(token) => Ok(warp::reply::json(&serde_json::json!({
@@ -140,21 +137,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("ping result: {:?}", result);
 
     // shared state among channels, used by websocket
-    let state = Arc::new(State {
-        ctl: Mutex::new(channel_control),
-        redis_url: redis_url.clone(),
-        redis_client,
-        jwt_secret,
-    });
+    let state = Arc::new(State::new(channel_control, Arc::new(RedisBroker::new(redis_client)), None, jwt_secret));
 
     // system channel
-    tokio::spawn(datetime_handler(state.clone(), "system"));
+    tokio::spawn(datetime_handler(state.clone(), "system".into()));
 
     let state_for_ws = state.clone();
     let ws_route = warp::path("websocket")
         .and(warp::ws())
         .and(warp::any().map(move || state_for_ws.clone()))
-        .map(|ws: warp::ws::Ws, state| ws.on_upgrade(move |websocket| warp_on_connected(websocket, state)));
+        .map(|ws: warp::ws::Ws, state| ws.on_upgrade(move |websocket| warp_on_connected(websocket, state, ConnInfo::default())));
 
     // let state_for_token = state.clone();
     // let token_route = warp::path("token")
@@ -0,0 +1,208 @@
+use async_trait::async_trait;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use redis::{AsyncCommands, RedisResult};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        OnceCell,
+    },
+    task::JoinHandle,
+};
+use tracing::{debug, error};
+
+use crate::auth::Identity;
+use crate::channel::{listen_to_redis, ChannelMessage, ControlEvent};
+
+/// the pub/sub backend between the websocket clients and the application
+#[async_trait]
+pub trait Broker: Send + Sync {
+    /// publish an event pushed by a client, `message` is the JSON payload
+    async fn publish(&self, channel: &str, event: &str, message: String) -> RedisResult<()>;
+
+    /// publish an event pushed by the client, the broker may tell the application who pushed it
+    async fn publish_from(&self, channel: &str, event: &str, payload: &Value, _from: &Publisher) -> RedisResult<()> {
+        self.publish(channel, event, payload.to_string()).await
+    }
+
+    /// spawn the task relaying the messages of the channel from the backend to `tx`
+    /// None if the broker has nothing to relay
+    fn listen(
+        &self, channel: &str, tx: broadcast::Sender<ChannelMessage>, events: broadcast::Sender<ControlEvent>,
+    ) -> Option<JoinHandle<RedisResult<()>>>;
+
+    /// whether the backend is reachable, for readiness checks
+    async fn ping(&self) -> RedisResult<()> {
+        Ok(())
+    }
+
+    /// publish `message` to the backend channel as is, for the requests to the application, see `RedisJoinAuth`
+    async fn send(&self, _channel: &str, _message: String) -> RedisResult<()> {
+        Err((redis::ErrorKind::ClientError, "requests are not supported by the broker").into())
+    }
+
+    /// the messages published to the backend channel as is, until the subscription is lost
+    async fn subscribe(&self, _channel: &str) -> RedisResult<BoxStream<'static, String>> {
+        Err((redis::ErrorKind::ClientError, "requests are not supported by the broker").into())
+    }
+}
+
+/// the connection an event comes from
+#[derive(Clone, Debug, Default)]
+pub struct Publisher {
+    pub conn_id: String,
+    pub identity: Option<Arc<Identity>>, // shared with the session of the connection
+}
+
+// a client event with its sender, see `RedisBroker::envelope`
+#[derive(Serialize)]
+struct Envelope<'a> {
+    payload: &'a Value,
+    conn_id: &'a str,
+    user_id: Option<&'a str>,
+    claims: Option<&'a Value>,
+}
+
+/// client events are published to `from:{channel}:{event}`,
+/// messages published to `to:{channel}:{event}` are broadcast to the channel
+pub struct RedisBroker {
+    client: redis::Client,
+    conn: OnceCell<redis::aio::MultiplexedConnection>,
+    envelope: bool,
+}
+
+impl RedisBroker {
+    pub fn new(client: redis::Client) -> Self {
+        RedisBroker {
+            client,
+            conn: OnceCell::new(),
+            envelope: false,
+        }
+    }
+
+    /// publish client events as `{"payload", "conn_id", "user_id", "claims"}` instead of the bare payload,
+    /// `user_id` and `claims` are null for anonymous connections
+    pub fn envelope(mut self, envelope: bool) -> Self {
+        self.envelope = envelope;
+        self
+    }
+
+    pub fn client(&self) -> &redis::Client {
+        &self.client
+    }
+
+    // one multiplexed connection shared by all websocket connections, created on first use
+    async fn conn(&self) -> RedisResult<redis::aio::MultiplexedConnection> {
+        let conn = self.conn.get_or_try_init(|| self.client.get_multiplexed_async_connection()).await?;
+        Ok(conn.clone())
+    }
+}
+
+#[async_trait]
+impl Broker for RedisBroker {
+    /// iredis --url redis://localhost:6379 psubscribe 'from*'
+    async fn publish(&self, channel: &str, event: &str, message: String) -> RedisResult<()> {
+        let redis_topic = format!("from:{}:{}", channel, event);
+        let mut conn = self.conn().await?;
+        let result: RedisResult<String> = conn.publish(redis_topic.clone(), message).await;
+        if let Err(e) = result {
+            error!("BROKER / fail to publish to redis {}: {}", redis_topic, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn publish_from(&self, channel: &str, event: &str, payload: &Value, from: &Publisher) -> RedisResult<()> {
+        if !self.envelope {
+            return self.publish(channel, event, payload.to_string()).await;
+        }
+        let envelope = Envelope {
+            payload,
+            conn_id: &from.conn_id,
+            user_id: from.identity.as_ref().map(|identity| identity.user_id.as_str()),
+            claims: from.identity.as_ref().map(|identity| &identity.claims),
+        };
+        self.publish(channel, event, serde_json::to_string(&envelope).unwrap_or_default()).await
+    }
+
+    fn listen(
+        &self, channel: &str, tx: broadcast::Sender<ChannelMessage>, events: broadcast::Sender<ControlEvent>,
+    ) -> Option<JoinHandle<RedisResult<()>>> {
+        Some(tokio::spawn(listen_to_redis(tx, events, self.client.clone(), channel.to_string())))
+    }
+
+    async fn ping(&self) -> RedisResult<()> {
+        let mut conn = self.conn().await?;
+        let _pong: String = redis::cmd("PING").query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn send(&self, channel: &str, message: String) -> RedisResult<()> {
+        let mut conn = self.conn().await?;
+        let _receivers: i64 = conn.publish(channel, message).await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, channel: &str) -> RedisResult<BoxStream<'static, String>> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(channel).await?;
+        Ok(pubsub.into_on_message().map(|message| message.get_payload().unwrap_or_default()).boxed())
+    }
+}
+
+/// no backend at all, for sockets whose topics are all served by channel handlers
+/// the client events are dropped, the requests are relayed in-process to the subscribers of their channel
+#[derive(Default)]
+pub struct LocalBroker {
+    channels: Mutex<HashMap<String, broadcast::Sender<String>>>, // channel -> subscribers
+}
+
+#[async_trait]
+impl Broker for LocalBroker {
+    async fn publish(&self, channel: &str, event: &str, _message: String) -> RedisResult<()> {
+        debug!("BROKER / local, {}:{} dropped", channel, event);
+        Ok(())
+    }
+
+    fn listen(&self, _: &str, _: broadcast::Sender<ChannelMessage>, _: broadcast::Sender<ControlEvent>) -> Option<JoinHandle<RedisResult<()>>> {
+        None
+    }
+
+    async fn send(&self, channel: &str, message: String) -> RedisResult<()> {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(tx) = channels.get(channel) {
+            if tx.send(message).is_err() {
+                channels.remove(channel); // nobody subscribes anymore
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, channel: &str) -> RedisResult<BoxStream<'static, String>> {
+        let rx = self
+            .channels
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(100).0)
+            .subscribe();
+        let messages = stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(message) => return Some((message, rx)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(messages.boxed())
+    }
+}
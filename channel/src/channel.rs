@@ -3,10 +3,13 @@ use redis::RedisResult;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
     error::Error,
     fmt::{self, Display},
-    sync::atomic::{AtomicU32, Ordering},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
 };
 use tokio::{
     sync::{
@@ -17,11 +20,16 @@ use tokio::{
 };
 use tracing::{debug, error, info, warn};
 
+use crate::auth::Identity;
+use crate::handler::{ChannelHandler, Socket};
+use crate::metrics::{Metrics, METRICS};
+use crate::utils::topic_matches;
 use crate::websocket::{Response, ServerMessage, ServerPayload};
 
 #[derive(Clone, Debug, Serialize)]
 pub enum ChannelMessage {
     Reply(ServerMessage),
+    Close { code: u16, reason: String }, // close the connection, after the messages queued before
 }
 
 impl Display for ChannelMessage {
@@ -30,10 +38,28 @@ impl Display for ChannelMessage {
             ChannelMessage::Reply(reply) => {
                 write!(formatter, "<{}>", reply)
             }
+            ChannelMessage::Close { code, reason } => {
+                write!(formatter, "<Close code={}, reason={}>", code, reason)
+            }
         }
     }
 }
 
+/// control plane events, for auditing, billing and analytics, see `ChannelControl::subscribe`
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlEvent {
+    Connected { conn_id: String },
+    Disconnected { conn_id: String },
+    ChannelCreated { channel: String },
+    ChannelRemoved { channel: String },
+    Joined { channel: String, agent_id: String },
+    Left { channel: String, agent_id: String },
+    Broadcast { channel: String, event: String, receivers: usize },
+    ListenerStarted { channel: String },
+    ListenerStopped { channel: String, reason: String },
+}
+
 /// agent channel, can broadcast to every agent in the channel
 pub struct Channel {
     pub name: String,
@@ -41,6 +67,27 @@ pub struct Channel {
     pub agents: Mutex<Vec<String>>,
     pub count: AtomicU32,
     pub redis_listen_task: Option<JoinHandle<RedisResult<()>>>,
+    pub persistent: bool, // kept when the last agent leaves, like the special channels
+}
+
+/// what is known about a websocket connection
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConnInfo {
+    pub remote_addr: Option<String>,
+    pub user_agent: Option<String>,
+    pub connected_at: String,       // rfc3339, set when the connection is added
+    pub identity: Option<Identity>, // who the client is, from its connect token or TLS certificate
+    #[serde(skip)]
+    pub conn_id: Option<String>, // picked before the upgrade to hold a session, see `ChannelControl::user_reserve`
+}
+
+/// a connection as listed by `ChannelControl::conn_list`
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnSummary {
+    pub conn_id: String,
+    #[serde(flatten)]
+    pub info: ConnInfo,
+    pub agents: Vec<String>,
 }
 
 /// manages all channels
@@ -49,6 +96,49 @@ pub struct ChannelControl {
     agent_relay_task: Mutex<HashMap<String, Agent>>,                     // agent_id -> JoinHandle
     agent_tx: Mutex<HashMap<String, broadcast::Sender<ChannelMessage>>>, // agent_id -> Sender
     conn_tx: Mutex<HashMap<String, broadcast::Sender<ChannelMessage>>>,  // conn_id -> Sender
+    conn_info: Mutex<HashMap<String, ConnInfo>>,                         // conn_id -> ConnInfo
+    users: Mutex<HashMap<String, HashSet<String>>>,                      // user_id -> conn_ids, of the connections with an identity
+    handlers: Mutex<Vec<(String, Arc<dyn ChannelHandler>)>>,             // topic pattern -> handler
+    sockets: Mutex<HashMap<String, HandlerSocket>>,                      // agent_id -> HandlerSocket
+    events: broadcast::Sender<ControlEvent>,
+}
+
+/// agent joined to a topic with a handler
+#[derive(Clone)]
+pub struct HandlerSocket {
+    handler: Arc<dyn ChannelHandler>,
+    socket: Socket,
+}
+
+impl HandlerSocket {
+    pub async fn info(&self, message: &serde_json::Value) {
+        self.handler.handle_info(message, &self.socket).await;
+    }
+}
+
+/// sockets removed from the control, their handlers are terminated by the caller
+/// once the ctl lock is released, so that a handler may call back into the control
+#[must_use = "the handlers are not terminated until `terminate` is awaited"]
+#[derive(Default)]
+pub struct Terminations {
+    sockets: Vec<HandlerSocket>,
+    reason: String,
+}
+
+impl Terminations {
+    fn new(sockets: Vec<HandlerSocket>, reason: &str) -> Self {
+        Self {
+            sockets,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub async fn terminate(self) {
+        for hs in self.sockets {
+            hs.handler.terminate(&self.reason, &hs.socket).await;
+            debug!("HANDLER / {} terminated, reason: {}", hs.socket.agent_id, self.reason);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -70,6 +160,7 @@ pub enum ChannelError {
     ChannelEmpty,
     MessageSendError,
     AgentNotInitiated,
+    JoinRefused,
 }
 
 impl Error for ChannelError {}
@@ -80,6 +171,7 @@ impl fmt::Display for ChannelError {
             ChannelError::ChannelNotFound => write!(formatter, "<ChannelNotFound>"),
             ChannelError::ChannelEmpty => write!(formatter, "<ChannelEmpty: channel has not agents>"),
             ChannelError::AgentNotInitiated => write!(formatter, "<AgentNotInitiated>"),
+            ChannelError::JoinRefused => write!(formatter, "<JoinRefused: the channel handler refused the join>"),
             ChannelError::MessageSendError => write!(formatter, "<MessageSendError: failed to send a message to the channel>"),
         }
     }
@@ -95,6 +187,7 @@ impl Channel {
             agents: Mutex::new(vec![]),
             count: AtomicU32::new(0),
             redis_listen_task: None,
+            persistent: false,
         }
     }
 
@@ -125,6 +218,7 @@ impl Channel {
 
     /// broadcast messages to the channel
     /// it returns the number of agents who received the message
+    #[allow(clippy::result_large_err)]
     pub fn send(&self, data: ChannelMessage) -> Result<usize, SendError<ChannelMessage>> {
         self.tx.send(data)
     }
@@ -133,7 +227,7 @@ impl Channel {
         self.count.load(Ordering::SeqCst) == 0
     }
 
-    pub async fn agents(&self) -> tokio::sync::MutexGuard<Vec<String>> {
+    pub async fn agents(&self) -> tokio::sync::MutexGuard<'_, Vec<String>> {
         self.agents.lock().await
     }
 }
@@ -151,27 +245,194 @@ impl ChannelControl {
             agent_tx: Mutex::new(HashMap::new()),
             agent_relay_task: Mutex::new(HashMap::new()),
             conn_tx: Mutex::new(HashMap::new()),
+            conn_info: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+            handlers: Mutex::new(vec![]),
+            sockets: Mutex::new(HashMap::new()),
+            events: broadcast::channel(1000).0,
+        }
+    }
+
+    /// ChannelControl with handlers registered up front, see `handler_add`
+    pub fn with_handlers(handlers: Vec<(String, Arc<dyn ChannelHandler>)>) -> Self {
+        ChannelControl {
+            handlers: Mutex::new(handlers),
+            ..Self::new()
         }
     }
 
+    /// subscribe to control plane events: connections, channels, joins, leaves and broadcasts
+    /// a subscriber that falls behind more than 1000 events gets `RecvError::Lagged`
+    pub fn subscribe(&self) -> broadcast::Receiver<ControlEvent> {
+        self.events.subscribe()
+    }
+
+    /// sender of control plane events, for tasks that don't hold the ChannelControl
+    pub fn events_tx(&self) -> broadcast::Sender<ControlEvent> {
+        self.events.clone()
+    }
+
+    fn emit(&self, event: ControlEvent) {
+        // it fails only when nobody subscribes
+        let _ = self.events.send(event);
+    }
+
     pub async fn conn_add_tx(&self, conn_id: String) {
         let mut conn_tx = self.conn_tx.lock().await;
         match conn_tx.entry(conn_id.clone()) {
             Entry::Vacant(entry) => {
                 let (tx, _rx) = broadcast::channel(100);
                 entry.insert(tx);
+                let info = ConnInfo {
+                    connected_at: chrono::Utc::now().to_rfc3339(),
+                    ..Default::default()
+                };
+                self.conn_info.lock().await.insert(conn_id.clone(), info);
                 debug!("CONN / conn_tx added, conn_id: {}", conn_id.clone());
+                self.emit(ControlEvent::Connected { conn_id });
             }
             Entry::Occupied(_) => {}
         }
     }
 
+    /// remote address and user agent of the connection, the connection time is kept
+    /// the connection is registered for the user of its identity, see `user_conns`
+    pub async fn conn_info_set(&self, conn_id: &str, info: ConnInfo) {
+        let user_id = info.identity.as_ref().map(|identity| identity.user_id.clone());
+        if let Some(existing) = self.conn_info.lock().await.get_mut(conn_id) {
+            *existing = ConnInfo {
+                connected_at: existing.connected_at.clone(),
+                ..info
+            };
+        } else {
+            return;
+        }
+        if let Some(user_id) = user_id {
+            self.user_add(&user_id, conn_id).await;
+        }
+    }
+
+    pub async fn conn_info(&self, conn_id: &str) -> Option<ConnInfo> {
+        self.conn_info.lock().await.get(conn_id).cloned()
+    }
+
+    /// the identity of the connection, renewed when it authenticates again
+    pub async fn conn_identity_set(&self, conn_id: &str, identity: Identity) {
+        let user_id = identity.user_id.clone();
+        let Some(previous) = self.conn_info.lock().await.get_mut(conn_id).map(|info| info.identity.replace(identity)) else {
+            return;
+        };
+        if let Some(previous) = previous.filter(|previous| previous.user_id != user_id) {
+            self.user_rm(&previous.user_id, conn_id).await;
+        }
+        self.user_add(&user_id, conn_id).await;
+    }
+
+    async fn user_add(&self, user_id: &str, conn_id: &str) {
+        self.users
+            .lock()
+            .await
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(conn_id.to_string());
+    }
+
+    async fn user_rm(&self, user_id: &str, conn_id: &str) {
+        let mut users = self.users.lock().await;
+        if let Some(conn_ids) = users.get_mut(user_id) {
+            conn_ids.remove(conn_id);
+            if conn_ids.is_empty() {
+                users.remove(user_id);
+            }
+        }
+    }
+
+    /// take one of the `max` sessions of the user for a connection about to be added, none if all are taken
+    /// checked and taken at once, the session is given back by `conn_cleanup` or `user_release`
+    pub async fn user_reserve(&self, user_id: &str, conn_id: &str, max: usize) -> bool {
+        let mut users = self.users.lock().await;
+        let conn_ids = users.entry(user_id.to_string()).or_default();
+        if conn_ids.len() >= max && !conn_ids.contains(conn_id) {
+            if conn_ids.is_empty() {
+                users.remove(user_id);
+            }
+            return false;
+        }
+        conn_ids.insert(conn_id.to_string());
+        true
+    }
+
+    /// give back the session of a connection which was never added, see `user_reserve`
+    pub async fn user_release(&self, user_id: &str, conn_id: &str) {
+        self.user_rm(user_id, conn_id).await;
+    }
+
+    /// the connections of the user, every tab and device
+    pub async fn user_conns(&self, user_id: &str) -> Vec<String> {
+        let mut conn_ids = self
+            .users
+            .lock()
+            .await
+            .get(user_id)
+            .map(|conn_ids| conn_ids.iter().cloned().collect::<Vec<String>>())
+            .unwrap_or_default();
+        conn_ids.sort();
+        conn_ids
+    }
+
+    pub async fn user_online(&self, user_id: &str) -> bool {
+        self.users.lock().await.contains_key(user_id)
+    }
+
+    /// send the event to every connection of the user, phoenix.js dispatches it to the channel of the topic if joined
+    /// it returns the number of connections which received it
+    pub async fn user_send(&self, user_id: &str, topic: &str, event: &str, payload: serde_json::Value) -> usize {
+        let mut sent = 0;
+        for conn_id in self.user_conns(user_id).await {
+            let message = ServerMessage {
+                join_ref: None,
+                event_ref: "0".into(),
+                topic: topic.to_string(),
+                event: event.to_string(),
+                payload: ServerPayload::ServerJsonValue(payload.clone()),
+            };
+            match self.conn_send(conn_id.clone(), ChannelMessage::Reply(message)).await {
+                Ok(_) => sent += 1,
+                Err(e) => warn!("CONN / fail to send {} to {} of user {}: {}", event, conn_id, user_id, e),
+            }
+        }
+        sent
+    }
+
+    /// all connections, with the agents they joined
+    pub async fn conn_list(&self) -> Vec<ConnSummary> {
+        let agent_ids = self.agent_list().await;
+        let mut conns = self
+            .conn_info
+            .lock()
+            .await
+            .iter()
+            .map(|(conn_id, info)| ConnSummary {
+                conn_id: conn_id.clone(),
+                info: info.clone(),
+                agents: agent_ids.iter().filter(|id| agent_conn_id(id) == Some(conn_id)).cloned().collect(),
+            })
+            .collect::<Vec<ConnSummary>>();
+        conns.sort_by(|a, b| a.info.connected_at.cmp(&b.info.connected_at));
+        conns
+    }
+
     pub async fn conn_rx(&self, conn_id: String) -> Result<broadcast::Receiver<ChannelMessage>, ChannelError> {
-        Ok(self.conn_tx.lock().await.get(&conn_id).unwrap().subscribe())
+        self.conn_tx
+            .lock()
+            .await
+            .get(&conn_id)
+            .map(|tx| tx.subscribe())
+            .ok_or(ChannelError::ChannelNotFound)
     }
 
     pub async fn conn_tx(&self, conn_id: String) -> Result<broadcast::Sender<ChannelMessage>, ChannelError> {
-        Ok(self.conn_tx.lock().await.get(&conn_id).unwrap().clone())
+        self.conn_tx.lock().await.get(&conn_id).cloned().ok_or(ChannelError::ChannelNotFound)
     }
 
     pub async fn conn_send(&self, conn_id: String, message: ChannelMessage) -> Result<usize, ChannelError> {
@@ -184,9 +445,67 @@ impl ChannelControl {
             .map_err(|_| ChannelError::MessageSendError)
     }
 
+    /// close the connection once the messages queued before are sent
+    pub async fn conn_close(&self, conn_id: String, code: u16, reason: &str) -> Result<usize, ChannelError> {
+        self.conn_send(
+            conn_id,
+            ChannelMessage::Close {
+                code,
+                reason: reason.to_string(),
+            },
+        )
+        .await
+    }
+
+    pub async fn conn_count(&self) -> usize {
+        self.conn_tx.lock().await.len()
+    }
+
+    pub async fn relay_task_count(&self) -> usize {
+        self.agent_relay_task.lock().await.len()
+    }
+
+    /// first step of the graceful shutdown, see `websocket::shutdown`
+    /// every joined agent gets `event` (phx_close for phoenix.js), then every connection is closed with 1001 going away
+    /// it returns the number of connections to be closed
+    pub async fn close_all(&self, event: &str, payload: serde_json::Value) -> usize {
+        for (name, channel) in self.channels.lock().await.iter() {
+            for agent_id in channel.agents().await.iter() {
+                if let Err(e) = self.agent_notify(agent_id, event, payload.clone()).await {
+                    warn!("CLOSE / fail to notify agent {} of {}: {}", agent_id, name, e);
+                }
+            }
+        }
+
+        let conn_ids = self.conn_tx.lock().await.keys().cloned().collect::<Vec<String>>();
+        for conn_id in conn_ids.iter() {
+            if let Err(e) = self.conn_close(conn_id.clone(), 1001, "server shutdown").await {
+                warn!("CLOSE / fail to close conn {}: {}", conn_id, e);
+            }
+        }
+        info!("CLOSE / {} connections closing", conn_ids.len());
+        conn_ids.len()
+    }
+
+    /// last step of the graceful shutdown, nothing comes from redis any more
+    pub async fn listen_tasks_abort(&self) {
+        for (name, channel) in self.channels.lock().await.iter() {
+            if let Some(task) = &channel.redis_listen_task {
+                task.abort();
+                info!("CLOSE / channel {} redis listen task aborted", name);
+            }
+        }
+    }
+
     // 清理所有和conn 有关的: conn, channel, agent
     // agent_id: {conn_id}:{channel}:{join_ref}
-    pub async fn conn_cleanup(&self, conn_id: String) {
+    // the handlers of the connection are returned, to be terminated with `closed`
+    pub async fn conn_cleanup(&self, conn_id: String) -> Terminations {
+        let mut sockets = self.sockets.lock().await;
+        let agent_ids = sockets.keys().filter(|k| k.starts_with(&conn_id)).cloned().collect::<Vec<String>>();
+        let removed = agent_ids.iter().filter_map(|agent_id| sockets.remove(agent_id)).collect();
+        drop(sockets);
+
         self.agent_relay_task.lock().await.retain(|k, agent| {
             if k.starts_with(&conn_id) {
                 agent.relay_task.abort();
@@ -200,15 +519,43 @@ impl ChannelControl {
         agent_tx.retain(|k, _| !k.starts_with(&conn_id));
         debug!("CONN / agent_tx cleared, conn_id: {}, {} {:?}", conn_id, agent_tx.len(), agent_tx.keys().collect::<Vec<&String>>());
 
-        self.conn_tx.lock().await.remove_entry(&conn_id);
+        for (name, channel) in self.channels.lock().await.iter() {
+            let mut agents = channel.agents.lock().await;
+            let left = agents
+                .iter()
+                .filter(|agent| agent.starts_with(&conn_id))
+                .cloned()
+                .collect::<Vec<String>>();
+            agents.retain(|agent| !agent.starts_with(&conn_id));
+            channel.count.store(agents.len() as u32, Ordering::SeqCst);
+            for agent_id in left {
+                debug!("CONN / {} left {}", agent_id, name);
+                self.emit(ControlEvent::Left {
+                    channel: name.clone(),
+                    agent_id,
+                });
+            }
+        }
+
+        let info = self.conn_info.lock().await.remove(&conn_id);
+        if let Some(identity) = info.and_then(|info| info.identity) {
+            self.user_rm(&identity.user_id, &conn_id).await;
+        }
+        if self.conn_tx.lock().await.remove_entry(&conn_id).is_some() {
+            self.emit(ControlEvent::Disconnected { conn_id: conn_id.clone() });
+        }
         debug!("CONN / conn cleared, {}", conn_id);
+        Terminations::new(removed, "closed")
     }
 
     pub async fn channel_add(&self, channel_name: String, capacity: Option<usize>) {
         let mut channels = self.channels.lock().await;
-        channels
-            .entry(channel_name.clone())
-            .or_insert_with(|| Channel::new(channel_name.clone(), capacity));
+        if let Entry::Vacant(entry) = channels.entry(channel_name.clone()) {
+            entry.insert(Channel::new(channel_name.clone(), capacity));
+            self.emit(ControlEvent::ChannelCreated {
+                channel: channel_name.clone(),
+            });
+        }
         // None if key does not exist, or value replace and old value retured
         // let inserted = channels.insert(channel_name.clone(), Channel::new(channel_name.clone(), capacity));
         debug!("CH / channel {} added", channel_name);
@@ -219,19 +566,33 @@ impl ChannelControl {
         let channel = channels.get_mut(&channel_name).unwrap();
         channel.redis_listen_task = Some(redis_listen_task);
         info!("CH / added redis listen task to channel {}", channel_name);
-
-        // self.admin_pub().await;
     }
 
-    pub async fn admin_pub(&self) {
-        if let Err(e) = self.channel_broadcast_json("admin", "CH", json!({})).await {
-            info!("CH / fail to publish admin event: {}", e);
+    /// publish to the admin channel, nothing happens if no admin is watching
+    pub async fn admin_pub(&self, event: &str, payload: serde_json::Value) {
+        match self.channel_broadcast_json("admin", event, payload).await {
+            Ok(_) | Err(ChannelError::ChannelEmpty) => {}
+            Err(e) => info!("CH / fail to publish admin event: {}", e),
         }
     }
 
+    /// snapshot of the node, published to the admin channel periodically
+    pub async fn stats(&self) -> serde_json::Value {
+        let channels = self.channels.lock().await;
+        let agents: u32 = channels.values().map(|channel| channel.count.load(Ordering::SeqCst)).sum();
+        json!({
+            "connections": self.conn_count().await,
+            "channels": channels.len(),
+            "agents": agents,
+            "relay_tasks": self.relay_task_count().await,
+        })
+    }
+
     // 删除一个 channel
     // channel 上所有的资源: channel, agents, agent_tx, relay_task, redis_listen_task, conn_tx
-    pub async fn channel_rm(&self, channel_name: String) {
+    // the handlers of the channel are returned, to be terminated with `removed`
+    pub async fn channel_rm(&self, channel_name: String) -> Terminations {
+        let mut removed = vec![];
         let mut channels = self.channels.lock().await;
         match channels.entry(channel_name.clone()) {
             Entry::Vacant(_) => {}
@@ -248,13 +609,24 @@ impl ChannelControl {
                     task.abort();
                     info!("CH_RM / channel {} redis listen task aborted", channel_name);
                 }
+                let mut sockets = self.sockets.lock().await;
+                let agent_ids = sockets
+                    .iter()
+                    .filter(|(_, hs)| hs.socket.topic == channel_name)
+                    .map(|(k, _)| k.clone())
+                    .collect::<Vec<String>>();
+                removed.extend(agent_ids.iter().filter_map(|agent_id| sockets.remove(agent_id)));
 
                 entry.remove();
                 info!("CH_RM / removed from channels, {}", channel_name);
+                self.emit(ControlEvent::ChannelRemoved {
+                    channel: channel_name.clone(),
+                });
             }
         }
         let channel_names = channels.keys().cloned().collect::<Vec<String>>();
         info!("CH_RM / {} cleared, channels: {} {:?}", channel_name, channel_names.len(), channel_names);
+        Terminations::new(removed, "removed")
     }
 
     // pub async fn channel_rm_conn_agents(&self, conn_id: String) {
@@ -266,6 +638,15 @@ impl ChannelControl {
     //     }
     // }
 
+    /// the channel is kept when empty, until removed with `channel_rm`
+    pub async fn channel_persist(&self, channel_name: &str) -> Result<(), ChannelError> {
+        let mut channels = self.channels.lock().await;
+        let channel = channels.get_mut(channel_name).ok_or(ChannelError::ChannelNotFound)?;
+        channel.persistent = true;
+        info!("CH / {} is persistent", channel_name);
+        Ok(())
+    }
+
     pub async fn channel_exists(&self, channel_name: &str) -> bool {
         let channels = self.channels.lock().await;
         channels.contains_key(channel_name)
@@ -282,11 +663,14 @@ impl ChannelControl {
 
         // 订阅 channel 并将消息转发给 agent
         let relay_task = tokio::spawn(async move {
-            while let Ok(channel_message) = channel_rx.recv().await {
-                match &channel_message {
-                    ChannelMessage::Reply(_reply_message) => {
+            loop {
+                match channel_rx.recv().await {
+                    Ok(channel_message @ ChannelMessage::Reply(_)) => {
                         let _ = agent_tx.send(channel_message);
                     }
+                    Ok(ChannelMessage::Close { .. }) => {} // connections are closed one by one, never by channel
+                    Err(broadcast::error::RecvError::Lagged(n)) => Metrics::add(&METRICS.lagged, n),
+                    Err(broadcast::error::RecvError::Closed) => break,
                 }
             }
         });
@@ -297,12 +681,16 @@ impl ChannelControl {
             }
             Entry::Vacant(entry) => {
                 entry.insert(Agent {
-                    id: agent_id,
+                    id: agent_id.clone(),
                     channel: channel_name.to_string().clone(),
                     relay_task,
                 });
             }
         }
+        self.emit(ControlEvent::Joined {
+            channel: channel_name.to_string(),
+            agent_id,
+        });
         Ok(channel_tx)
     }
 
@@ -319,6 +707,10 @@ impl ChannelControl {
             }
             Entry::Vacant(_) => {}
         }
+        self.emit(ControlEvent::Left {
+            channel: name.clone(),
+            agent_id,
+        });
         Ok(channel.count.load(Ordering::SeqCst) as usize)
     }
 
@@ -343,10 +735,21 @@ impl ChannelControl {
             return Err(ChannelError::ChannelEmpty);
         }
 
-        channel.send(message).map_err(|e| {
+        let ChannelMessage::Reply(ref reply) = message else {
+            warn!("CH / close is not broadcast, channel: {}", channel_name);
+            return Err(ChannelError::MessageSendError);
+        };
+        let event = reply.event.clone();
+        let receivers = channel.send(message).map_err(|e| {
             error!("CH / broadcasting error, channel: {}, {:?}", channel_name, e);
             ChannelError::MessageSendError
-        })
+        })?;
+        self.emit(ControlEvent::Broadcast {
+            channel: channel_name,
+            event,
+            receivers,
+        });
+        Ok(receivers)
     }
 
     pub async fn agent_rx(&self, agent_id: String) -> Result<broadcast::Receiver<ChannelMessage>, ChannelError> {
@@ -405,10 +808,82 @@ impl ChannelControl {
         info!("AGENT / list {} {:?}", agents.len(), agents);
     }
 
+    /// push an event to the client of the agent, with the join_ref of the agent as phx_close needs
+    pub async fn agent_notify(&self, agent_id: &str, event: &str, payload: serde_json::Value) -> Result<usize, ChannelError> {
+        let (conn_id, channel_name, join_ref) = agent_parts(agent_id).ok_or(ChannelError::AgentNotInitiated)?;
+        let message = ServerMessage {
+            join_ref: Some(join_ref.to_string()),
+            event_ref: join_ref.to_string(),
+            topic: channel_name.to_string(),
+            event: event.to_string(),
+            payload: ServerPayload::ServerJsonValue(payload),
+        };
+        self.conn_send(conn_id.to_string(), ChannelMessage::Reply(message)).await
+    }
+
     /// list all agents
     pub async fn agent_list(&self) -> Vec<String> {
         self.agent_tx.lock().await.keys().cloned().collect()
     }
+
+    /// register a handler for topics matching the pattern, e.g. `room:*`
+    /// the first registered pattern matching a topic wins
+    pub async fn handler_add(&self, pattern: &str, handler: Arc<dyn ChannelHandler>) {
+        self.handlers.lock().await.push((pattern.to_string(), handler));
+        info!("HANDLER / added for {}", pattern);
+    }
+
+    pub async fn handler_for(&self, topic: &str) -> Option<Arc<dyn ChannelHandler>> {
+        self.handlers
+            .lock()
+            .await
+            .iter()
+            .find(|(pattern, _)| topic_matches(pattern, topic))
+            .map(|(_, handler)| handler.clone())
+    }
+
+    /// create the socket handed to the handler of an agent joining the channel
+    pub async fn socket_new(&self, conn_id: &str, agent_id: &str, channel_name: &str, join_ref: Option<String>) -> Result<Socket, ChannelError> {
+        let conn_tx = self.conn_tx.lock().await.get(conn_id).ok_or(ChannelError::ChannelNotFound)?.clone();
+        let channel_tx = self
+            .channels
+            .lock()
+            .await
+            .get(channel_name)
+            .ok_or(ChannelError::ChannelNotFound)?
+            .tx
+            .clone();
+        Ok(Socket::new(conn_id.to_string(), agent_id.to_string(), channel_name.to_string(), join_ref, conn_tx, channel_tx))
+    }
+
+    /// keep the socket of an agent whose join the handler accepted
+    pub async fn socket_add(&self, socket: Socket, handler: Arc<dyn ChannelHandler>) {
+        debug!("HANDLER / socket added, {}", socket.agent_id);
+        self.sockets
+            .lock()
+            .await
+            .insert(socket.agent_id.clone(), HandlerSocket { handler, socket });
+    }
+
+    pub async fn socket_get(&self, agent_id: &str) -> Option<Socket> {
+        self.sockets.lock().await.get(agent_id).map(|hs| hs.socket.clone())
+    }
+
+    /// remove the socket of the agent, its handler is to be terminated with the reason
+    pub async fn socket_rm(&self, agent_id: &str, reason: &str) -> Terminations {
+        let removed = self.sockets.lock().await.remove(agent_id);
+        debug!("HANDLER / socket removed, {}, reason: {}", agent_id, reason);
+        Terminations::new(removed.into_iter().collect(), reason)
+    }
+
+    /// the handler sockets of the agents in the channel, see `websocket::channel_info`
+    pub async fn channel_sockets(&self, channel_name: &str) -> Result<Vec<HandlerSocket>, ChannelError> {
+        if !self.channel_exists(channel_name).await {
+            return Err(ChannelError::ChannelNotFound);
+        }
+        let sockets = self.sockets.lock().await;
+        Ok(sockets.values().filter(|hs| hs.socket.topic == channel_name).cloned().collect())
+    }
 }
 
 /// 从 Redis 反序列化的, 之后转发到 websocket
@@ -433,9 +908,9 @@ pub enum ResponseFromRedis {
     Message { message: String },
 }
 
-impl Into<Response> for ResponseFromRedis {
-    fn into(self) -> Response {
-        match self {
+impl From<ResponseFromRedis> for Response {
+    fn from(value: ResponseFromRedis) -> Self {
+        match value {
             ResponseFromRedis::Empty {} => Response::Empty {},
             ResponseFromRedis::Join {} => Response::Join {},
             ResponseFromRedis::Heartbeat {} => Response::Heartbeat {},
@@ -466,23 +941,47 @@ impl ChannelEventFromRedis {
 }
 
 /// 从redis 监听消息, per channel 的任务
-pub async fn listen_to_redis(tx: broadcast::Sender<ChannelMessage>, redis_client: redis::Client, channel_name: String) -> RedisResult<()> {
+/// messages published to the channel are reported as `ControlEvent::Broadcast` on `events`
+/// relay messages published to `to:{channel}:*` until redis fails, the listener status goes to `events`
+pub async fn listen_to_redis(
+    tx: broadcast::Sender<ChannelMessage>, events: broadcast::Sender<ControlEvent>, redis_client: redis::Client, channel_name: String,
+) -> RedisResult<()> {
+    let result = redis_listen(tx, events.clone(), redis_client, channel_name.clone()).await;
+    let reason = match &result {
+        Ok(()) => "connection closed".to_string(),
+        Err(e) => e.to_string(),
+    };
+    error!("LISTENER / {} stopped: {}", channel_name, reason);
+    let _ = events.send(ControlEvent::ListenerStopped {
+        channel: channel_name,
+        reason,
+    });
+    result
+}
+
+async fn redis_listen(
+    tx: broadcast::Sender<ChannelMessage>, events: broadcast::Sender<ControlEvent>, redis_client: redis::Client, channel_name: String,
+) -> RedisResult<()> {
     let redis_topic = format!("to:{}:*", channel_name);
     let mut redis_pubsub = redis_client.get_async_pubsub().await?;
     redis_pubsub.psubscribe(redis_topic.clone()).await?;
     let mut redis_pubsub_stream = redis_pubsub.on_message();
+    let _ = events.send(ControlEvent::ListenerStarted {
+        channel: channel_name.clone(),
+    });
     let mut counter = 0; // TODO: counter 有问题, 在这里完全没有意义
 
     info!("LISTENER / subscribed to redis, channel: {}", redis_topic);
     loop {
         let optional_message = redis_pubsub_stream.next().await;
         if optional_message.is_none() {
-            error!("LISTENER / from redis: none");
-            continue;
+            error!("LISTENER / from redis: none, the connection is closed");
+            return Ok(());
         }
 
         let stream_message = optional_message.unwrap();
         let payload: String = stream_message.get_payload()?;
+        Metrics::inc(&METRICS.redis_in);
         debug!("LISTENER / from redis, {}, payload: `{}`", stream_message.get_channel_name(), payload.clone());
 
         let response_from_redis_result = serde_json::from_str::<serde_json::Value>(&payload);
@@ -500,7 +999,13 @@ pub async fn listen_to_redis(tx: broadcast::Sender<ChannelMessage>, redis_client
         // the format is to:channel_name:event_name, split it by `:`
         match ChannelEventFromRedis::parse(stream_message.get_channel_name()) {
             Ok(msg) => {
-                _channel_publish(counter, value.clone(), tx.clone(), &msg.channel, &msg.event).await;
+                if let Some(receivers) = _channel_publish(counter, value.clone(), tx.clone(), &msg.channel, &msg.event).await {
+                    let _ = events.send(ControlEvent::Broadcast {
+                        channel: msg.channel,
+                        event: msg.event,
+                        receivers,
+                    });
+                }
             }
             Err(e) => {
                 warn!("LISTENER / invalid redis channel format: {}", e);
@@ -512,7 +1017,26 @@ pub async fn listen_to_redis(tx: broadcast::Sender<ChannelMessage>, redis_client
     }
 }
 
-async fn _channel_publish(counter: i32, value: serde_json::Value, tx: broadcast::Sender<ChannelMessage>, channel_name: &str, event_name: &str) {
+/// agent_id: {conn_id}:{channel}:{join_ref}, an empty join_ref if there is none
+pub fn agent_id(conn_id: &str, channel: &str, join_ref: Option<&str>) -> String {
+    format!("{}:{}:{}", conn_id, channel, join_ref.unwrap_or_default())
+}
+
+/// agent_id: {conn_id}:{channel}:{join_ref}, the channel name may contain `:`
+pub fn agent_parts(agent_id: &str) -> Option<(&str, &str, &str)> {
+    let (conn_id, rest) = agent_id.split_once(':')?;
+    let (channel, join_ref) = rest.rsplit_once(':')?;
+    Some((conn_id, channel, join_ref))
+}
+
+fn agent_conn_id(agent_id: &str) -> Option<&str> {
+    agent_parts(agent_id).map(|(conn_id, _, _)| conn_id)
+}
+
+/// it returns the number of agents who received the message
+async fn _channel_publish(
+    counter: i32, value: serde_json::Value, tx: broadcast::Sender<ChannelMessage>, channel_name: &str, event_name: &str,
+) -> Option<usize> {
     let reply_message = ServerMessage {
         join_ref: None,
         event_ref: counter.to_string(),
@@ -527,18 +1051,24 @@ async fn _channel_publish(counter: i32, value: serde_json::Value, tx: broadcast:
     //     .channel_broadcast(channel_name.to_string(), ChannelMessage::Reply(reply_message.clone()))
     //     .await
     match tx.send(ChannelMessage::Reply(reply_message.clone())) {
-        Ok(_) => debug!("REDIS_PUB / published, {} > {}", event_name, reply_message),
+        Ok(receivers) => {
+            debug!("REDIS_PUB / published, {} > {}", event_name, reply_message);
+            Some(receivers)
+        }
         Err(e) => {
             // it throws error if there's no client
+            Metrics::inc(&METRICS.dropped);
             error!("REDIS_PUB / fail to send, channel: {}, event: {}, err: {}", channel_name, event_name, e);
+            None
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::channel::{Channel, ChannelControl, ChannelError, ChannelMessage};
-    use crate::websocket::{Response, ServerMessage, ServerPayload};
+    use crate::auth::Identity;
+    use crate::channel::{Channel, ChannelControl, ChannelError, ChannelMessage, ConnInfo, ControlEvent};
+    use crate::websocket::{Response, ServerMessage, ServerPayload, ServerResponse};
 
     fn create_test_message(topic: &str, reference: &str, message: &str) -> ChannelMessage {
         ChannelMessage::Reply(ServerMessage {
@@ -546,7 +1076,7 @@ mod test {
             event_ref: reference.to_string(),
             topic: topic.to_string(),
             event: "test_event".to_string(),
-            payload: ServerPayload {
+            payload: ServerPayload::ServerResponse(ServerResponse {
                 status: "ok".to_string(),
                 // response: json!({
                 //     "message": message.to_string(),
@@ -554,7 +1084,7 @@ mod test {
                 response: Response::Message {
                     message: message.to_string(),
                 },
-            },
+            }),
         })
     }
 
@@ -665,7 +1195,11 @@ mod test {
             assert_eq!(msg.topic, "test");
 
             // let value = from_value(msg.payload.response);
-            if let Response::Message { message } = msg.payload.response {
+            if let ServerPayload::ServerResponse(ServerResponse {
+                response: Response::Message { message },
+                ..
+            }) = msg.payload
+            {
                 assert_eq!(message, "hello");
             } else {
                 panic!("Wrong response type");
@@ -725,6 +1259,10 @@ mod test {
         // Test leave non-existent channel
         let result = ctl.channel_leave("nonexistent".into(), "user1".into()).await;
         assert!(matches!(result.unwrap_err(), ChannelError::ChannelNotFound));
+
+        // unknown connections
+        assert!(matches!(ctl.conn_rx("conn1".into()).await.unwrap_err(), ChannelError::ChannelNotFound));
+        assert!(matches!(ctl.conn_tx("conn1".into()).await.unwrap_err(), ChannelError::ChannelNotFound));
     }
 
     #[tokio::test]
@@ -760,7 +1298,7 @@ mod test {
         ctl.channel_add("test".into(), None).await;
         assert_eq!(ctl.channels.lock().await.len(), 1);
 
-        ctl.channel_rm("test".into()).await;
+        ctl.channel_rm("test".into()).await.terminate().await;
         assert_eq!(ctl.channels.lock().await.len(), 0);
     }
 
@@ -804,7 +1342,7 @@ mod test {
             event_ref: "1".to_string(),
             topic: "test".to_string(),
             event: "test_event".to_string(),
-            payload: crate::websocket::ServerPayload {
+            payload: crate::websocket::ServerPayload::ServerResponse(crate::websocket::ServerResponse {
                 status: "ok".to_string(),
                 // response: json!({
                 //     "message": "test message".to_string(),
@@ -812,7 +1350,7 @@ mod test {
                 response: Response::Message {
                     message: "test message".to_string(),
                 },
-            },
+            }),
         });
 
         let result = ctl.channel_broadcast("test".to_string(), message).await;
@@ -843,7 +1381,7 @@ mod test {
             event_ref: "1".to_string(),
             topic: "room1".to_string(),
             event: "broadcast".to_string(),
-            payload: crate::websocket::ServerPayload {
+            payload: crate::websocket::ServerPayload::ServerResponse(crate::websocket::ServerResponse {
                 status: "ok".to_string(),
                 // response: json!({
                 //     "message": "hello all".to_string(),
@@ -851,7 +1389,7 @@ mod test {
                 response: Response::Message {
                     message: "hello all".to_string(),
                 },
-            },
+            }),
         });
 
         let result = ctl.channel_broadcast("room1".to_string(), message).await;
@@ -956,7 +1494,7 @@ mod test {
         }
 
         // Remove channel
-        ctl.channel_rm("room1".into()).await;
+        ctl.channel_rm("room1".into()).await.terminate().await;
 
         // Verify cleanup
         assert!(ctl.channels.lock().await.is_empty());
@@ -999,6 +1537,113 @@ mod test {
     //     }
     // }
 
+    #[tokio::test]
+    async fn test_control_events() {
+        let ctl = ChannelControl::new();
+        let mut events = ctl.subscribe();
+
+        ctl.conn_add_tx("conn1".into()).await;
+        ctl.channel_add("room1".into(), None).await;
+        ctl.channel_add("room1".into(), None).await; // exists, no event
+        ctl.agent_add("conn1:room1:1".into(), None).await;
+        ctl.channel_join("room1", "conn1:room1:1".into()).await.unwrap();
+        ctl.channel_broadcast("room1".into(), create_test_message("room1", "1", "hello"))
+            .await
+            .unwrap();
+        ctl.conn_cleanup("conn1".into()).await.terminate().await;
+        ctl.channel_rm("room1".into()).await.terminate().await;
+
+        let expected = vec![
+            ControlEvent::Connected { conn_id: "conn1".into() },
+            ControlEvent::ChannelCreated { channel: "room1".into() },
+            ControlEvent::Joined {
+                channel: "room1".into(),
+                agent_id: "conn1:room1:1".into(),
+            },
+            ControlEvent::Broadcast {
+                channel: "room1".into(),
+                event: "test_event".into(),
+                receivers: 1,
+            },
+            ControlEvent::Left {
+                channel: "room1".into(),
+                agent_id: "conn1:room1:1".into(),
+            },
+            ControlEvent::Disconnected { conn_id: "conn1".into() },
+            ControlEvent::ChannelRemoved { channel: "room1".into() },
+        ];
+        for event in expected {
+            assert_eq!(events.try_recv().unwrap(), event);
+        }
+        assert!(events.try_recv().is_err());
+        assert!(ctl.channels.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_conn_cleanup_leaves_channels() {
+        let ctl = ChannelControl::new();
+        ctl.channel_add("room1".into(), None).await;
+        ctl.conn_add_tx("conn1".into()).await;
+        ctl.agent_add("conn1:room1:1".into(), None).await;
+        ctl.channel_join("room1", "conn1:room1:1".into()).await.unwrap();
+        assert!(!ctl.channels.lock().await.get("room1").unwrap().empty());
+
+        ctl.conn_cleanup("conn1".into()).await.terminate().await;
+        assert!(ctl.channels.lock().await.get("room1").unwrap().empty());
+    }
+
+    #[tokio::test]
+    async fn test_users() {
+        let ctl = ChannelControl::new();
+        let identity = |user_id: &str| ConnInfo {
+            identity: Some(Identity {
+                user_id: user_id.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        for (conn_id, user_id) in [("conn1", "u1"), ("conn2", "u1"), ("conn3", "u2")] {
+            ctl.conn_add_tx(conn_id.into()).await;
+            ctl.conn_info_set(conn_id, identity(user_id)).await;
+        }
+        ctl.conn_add_tx("conn4".into()).await; // anonymous
+        assert_eq!(ctl.user_conns("u1").await, vec!["conn1", "conn2"]);
+        assert!(ctl.user_online("u2").await && !ctl.user_online("u3").await);
+
+        let _rx1 = ctl.conn_rx("conn1".into()).await.unwrap();
+        let mut rx = ctl.conn_rx("conn2".into()).await.unwrap();
+        assert_eq!(ctl.user_send("u1", "user:u1", "notice", serde_json::json!({})).await, 2);
+        assert!(matches!(rx.recv().await.unwrap(), ChannelMessage::Reply(m) if m.topic == "user:u1" && m.event == "notice"));
+
+        ctl.conn_cleanup("conn1".into()).await.terminate().await;
+        assert_eq!(ctl.user_conns("u1").await, vec!["conn2"]);
+        ctl.conn_cleanup("conn3".into()).await.terminate().await;
+        assert!(!ctl.user_online("u2").await);
+        assert_eq!(ctl.user_send("u2", "user:u2", "notice", serde_json::json!({})).await, 0);
+
+        // reserved before the connection is added
+        assert!(ctl.user_reserve("u1", "conn5", 2).await);
+        assert!(!ctl.user_reserve("u1", "conn6", 2).await);
+        ctl.conn_add_tx("conn5".into()).await;
+        ctl.conn_info_set("conn5", identity("u1")).await;
+        assert_eq!(ctl.user_conns("u1").await, vec!["conn2", "conn5"]);
+        ctl.conn_cleanup("conn5".into()).await.terminate().await;
+        assert!(ctl.user_reserve("u1", "conn6", 2).await);
+        ctl.user_release("u1", "conn6").await;
+        assert_eq!(ctl.user_conns("u1").await, vec!["conn2"]);
+        assert!(!ctl.user_reserve("u3", "conn7", 0).await);
+        assert!(!ctl.user_online("u3").await);
+    }
+
+    #[test]
+    fn test_control_event_json() {
+        let event = ControlEvent::Joined {
+            channel: "room1".into(),
+            agent_id: "conn1:room1:1".into(),
+        };
+        assert_eq!(serde_json::to_value(&event).unwrap(), serde_json::json!({"type": "joined", "channel": "room1", "agent_id": "conn1:room1:1"}));
+    }
+
     #[test]
     fn test_reply_message_display() {
         // Test message response
@@ -1007,7 +1652,7 @@ mod test {
             event_ref: "ref1".to_string(),
             topic: "test".to_string(),
             event: "msg".to_string(),
-            payload: ServerPayload {
+            payload: ServerPayload::ServerResponse(ServerResponse {
                 status: "ok".to_string(),
                 // response: json!({
                 //     "message": "hello".to_string(),
@@ -1015,9 +1660,9 @@ mod test {
                 response: Response::Message {
                     message: "hello".to_string(),
                 },
-            },
+            }),
         };
-        assert_eq!(message.to_string(), r#"Message join_ref=1, ref=ref1, topic=test, event=msg, <Payload status=ok, response=...>"#);
+        assert_eq!(message.to_string(), r#"Message join_ref=1, ref=ref1, topic=test, event=msg, <ServerResponse status=ok, response=...>"#);
 
         // Test datetime response
         let datetime = ServerMessage {
@@ -1025,7 +1670,7 @@ mod test {
             event_ref: "ref2".to_string(),
             topic: "system".to_string(),
             event: "datetime".to_string(),
-            payload: ServerPayload {
+            payload: ServerPayload::ServerResponse(ServerResponse {
                 status: "ok".to_string(),
                 // response: json!({
                 //     "datetime": "2024-01-01T00:00:00".to_string(),
@@ -1035,9 +1680,12 @@ mod test {
                     datetime: "2024-01-01T00:00:00".to_string(),
                     counter: 42,
                 },
-            },
+            }),
         };
-        assert_eq!(datetime.to_string(), r#"Message join_ref=None, ref=ref2, topic=system, event=datetime, <Payload status=ok, response=...>"#);
+        assert_eq!(
+            datetime.to_string(),
+            r#"Message join_ref=None, ref=ref2, topic=system, event=datetime, <ServerResponse status=ok, response=...>"#
+        );
 
         // Test empty response
         let empty = ServerMessage {
@@ -1045,12 +1693,32 @@ mod test {
             event_ref: "ref3".to_string(),
             topic: "test".to_string(),
             event: "phx_reply".to_string(),
-            payload: ServerPayload {
+            payload: ServerPayload::ServerResponse(ServerResponse {
                 status: "ok".to_string(),
                 // response: json!({}),
                 response: Response::Empty {},
-            },
+            }),
+        };
+        assert_eq!(empty.to_string(), r#"Message join_ref=None, ref=ref3, topic=test, event=phx_reply, <ServerResponse status=ok, response=...>"#);
+    }
+
+    #[tokio::test]
+    async fn test_close_all() {
+        let ctl = ChannelControl::new();
+        ctl.channel_add("room:1".into(), None).await;
+        ctl.conn_add_tx("conn1".into()).await;
+        let mut conn_rx = ctl.conn_rx("conn1".into()).await.unwrap();
+        ctl.agent_add("conn1:room:1:3".into(), None).await;
+        ctl.channel_join("room:1", "conn1:room:1:3".into()).await.unwrap();
+
+        assert_eq!(ctl.close_all("reconnect", serde_json::json!({ "url": "ws://other" })).await, 1);
+        let ChannelMessage::Reply(message) = conn_rx.recv().await.unwrap() else {
+            panic!("reply expected");
         };
-        assert_eq!(empty.to_string(), r#"Message join_ref=None, ref=ref3, topic=test, event=phx_reply, <Payload status=ok, response=...>"#);
+        assert_eq!(message.topic, "room:1");
+        assert_eq!(message.event, "reconnect");
+        assert_eq!(message.join_ref, Some("3".into()));
+        assert!(matches!(conn_rx.recv().await.unwrap(), ChannelMessage::Close { code: 1001, .. }));
+        assert_eq!(ctl.conn_count().await, 1); // until the connection is cleaned up
     }
 }
@@ -0,0 +1,898 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::auth::is_hmac;
+use crate::cors::Origins;
+use crate::listener::ListenAddr;
+use crate::proxy::Proxies;
+use crate::websocket::SPECIAL_CHANNELS;
+
+/// channeld configuration, layered: defaults < TOML file < `CHANNELD_*` env < command line
+///
+/// ```toml
+/// host = "0.0.0.0"
+/// port = 5000
+///
+/// # host and port with every route if no listener is set
+/// [[listeners]]
+/// address = "0.0.0.0:5000"
+/// routes = ["socket", "pages", "health"]
+///
+/// [[listeners]]
+/// address = "unix:/run/channeld.sock"
+/// routes = ["admin", "publish", "metrics"]
+///
+/// [redis]
+/// url = "redis://localhost:6379"
+///
+/// [auth]
+/// jwt_algorithms = ["RS256"]
+/// jwt_keys = "/etc/channeld/jwks.json"
+/// jwt_issuer = "https://auth.example.com"
+/// connect = "required"
+/// join = "jwt"
+///
+/// [shutdown]
+/// event = "reconnect"
+/// payload = '{"url": "wss://other.example.com/socket"}'
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub log: String, // EnvFilter directives, RUST_LOG wins if set
+    pub redis: RedisConfig,
+    pub auth: AuthConfig,
+    pub pages: PagesConfig,
+    pub admin: AdminConfig,
+    pub shutdown: ShutdownConfig,
+    pub channels: ChannelsConfig,
+    pub tls: TlsConfig,
+    pub listeners: Vec<ListenerConfig>, // file only, see `listeners()`
+    pub origins: OriginsConfig,
+    pub proxies: ProxiesConfig,
+    pub limits: LimitsConfig,
+    pub messages: MessagesConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    pub url: Option<String>,
+    pub envelope: bool,              // client events are published with their sender, see `RedisBroker::envelope`
+    pub revocations: Option<String>, // channel of the revoked token ids, see `auth::listen_revocations`
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub api_key: Option<String>,      // bearer token of the admin and publish api, the admin api is disabled if not set
+    pub jwt_secret: Option<String>,   // secret of the channel and api tokens, a random one if not set
+    pub jwt_algorithms: Vec<String>,  // accepted algorithms, e.g. `RS256`, the HS* ones verify with the secret
+    pub jwt_keys: Option<PathBuf>,    // PEM public key or JWKS of the RS*, PS*, ES* and EdDSA tokens
+    pub jwt_issuer: Option<String>,   // required `iss` of the tokens if set
+    pub jwt_audience: Vec<String>,    // accepted `aud` of the tokens, checked if not empty
+    pub jwt_reload_interval: u64,     // seconds between the checks of the keys file, reloaded when changed
+    pub connect: ConnectMode,         // authentication of the websockets by a token in their params
+    pub connect_param: String,        // the param of the token, `new Socket(url, {params: {userToken}})`, with `"typ": "connect"`
+    pub join: JoinMode,               // authorization of the joins
+    pub join_channel: Option<String>, // redis channel the application answers joins on, see `RedisJoinAuth`
+    pub join_timeout: u64,            // milliseconds to wait for the answer, refused then
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectMode {
+    #[default]
+    Off, // params are ignored
+    Optional, // connections without a token are anonymous
+    Required,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JoinMode {
+    #[default]
+    Off, // every join is accepted
+    Jwt,   // the token of the join, or of the connection, grants the topic, see `JwtAuth`
+    Redis, // the application answers on `join_channel`, see `RedisJoinAuth`
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            api_key: None,
+            jwt_secret: None,
+            jwt_algorithms: vec!["HS256".into()],
+            jwt_keys: None,
+            jwt_issuer: None,
+            jwt_audience: vec![],
+            jwt_reload_interval: 60,
+            connect: ConnectMode::Off,
+            connect_param: "userToken".into(),
+            join: JoinMode::Off,
+            join_channel: None,
+            join_timeout: 3000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PagesConfig {
+    pub index: String, // path of the bundled phoenix.js demo page, empty to disable it
+    pub admin: String, // path of the bundled admin page, empty to disable it
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub stats_interval: u64, // seconds between the stats snapshots of the admin channel
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    pub event: String,   // sent to every joined agent, e.g. a custom one telling clients to reconnect elsewhere
+    pub payload: String, // JSON payload of the event
+    pub timeout: u64,    // seconds to flush and close the connections
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelsConfig {
+    pub special: Vec<String>, // created at startup and never removed when empty
+}
+
+/// browser origins allowed to open websockets, and by the CORS of `/token` and the api, see `cors::Origins`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OriginsConfig {
+    pub allow: Vec<String>, // e.g. `https://*.example.com`, any origin if empty
+}
+
+/// reverse proxies whose `x-forwarded-for` tells the client address, see `proxy::Proxies`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxiesConfig {
+    pub trusted: Vec<String>, // e.g. `10.0.0.0/8` or `unix`, the header is ignored if empty
+}
+
+/// limits on inbound messages, a violation gets an error reply, or a close with 1009 for frames
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessagesConfig {
+    pub max_frame_bytes: usize,   // websocket frame
+    pub max_payload_bytes: usize, // payload as JSON
+    pub max_depth: usize,         // nesting of the payload, `{}` is 1
+    pub max_name_length: usize,   // topics and events
+    pub name_chars: String,       // allowed in topics and events, besides ascii letters and digits
+}
+
+impl Default for MessagesConfig {
+    fn default() -> Self {
+        MessagesConfig {
+            max_frame_bytes: 1024 * 1024,
+            max_payload_bytes: 64 * 1024,
+            max_depth: 32,
+            max_name_length: 255,
+            name_chars: ":_-.@/".into(),
+        }
+    }
+}
+
+/// token bucket, `burst` at once, then `per_second`
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// rate limits, file only, none if not set, see `ratelimit::RateLimits`
+///
+/// ```toml
+/// [limits]
+/// messages = { per_second = 20, burst = 50 }
+/// connections = { per_second = 1, burst = 10 }
+/// max_violations = 10
+/// max_sessions = 5
+///
+/// [[limits.channels]]
+/// pattern = "chat:*"
+/// pushes = { per_second = 100, burst = 200 }
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub messages: Option<Rate>,       // inbound messages per connection, heartbeats and leaves aside
+    pub joins: Option<Rate>,          // joins per connection
+    pub pushes: Option<Rate>,         // client events per topic, from every connection
+    pub connections: Option<Rate>,    // new connections per IP
+    pub max_violations: u32,          // a connection over its limits more often in a minute is closed, 0 never
+    pub max_sessions: u32,            // connections per authenticated user, 0 for any
+    pub channels: Vec<ChannelLimits>, // the first rule matching the topic overrides the limits it sets
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            messages: None,
+            joins: None,
+            pushes: None,
+            connections: None,
+            max_violations: 10,
+            max_sessions: 0,
+            channels: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelLimits {
+    pub pattern: String, // e.g. `room:*`
+    #[serde(default)]
+    pub messages: Option<Rate>,
+    #[serde(default)]
+    pub joins: Option<Rate>,
+    #[serde(default)]
+    pub pushes: Option<Rate>,
+}
+
+/// route sets of a listener, `socket` is the websocket, `token` issues channel tokens, `pages` the bundled pages,
+/// `health` is `/healthz` and `/readyz`
+pub const ROUTES: [&str; 7] = ["socket", "token", "publish", "admin", "pages", "metrics", "health"];
+
+/// route sets of a listener without `routes`, `token` is served only when asked for
+pub const DEFAULT_ROUTES: [&str; 6] = ["socket", "publish", "admin", "pages", "metrics", "health"];
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: String,     // `host:port`, TLS if configured, or `unix:/path/to/socket`
+    pub routes: Vec<String>, // see `ROUTES`
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            address: String::new(),
+            routes: DEFAULT_ROUTES.iter().map(|route| route.to_string()).collect(),
+        }
+    }
+}
+
+/// https and wss when `cert` and `key` are set, mutual TLS when `client_ca` is set too
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,      // PEM certificate chain
+    pub key: Option<PathBuf>,       // PEM private key
+    pub client_ca: Option<PathBuf>, // PEM roots the client certificates are verified against
+    pub reload_interval: u64,       // seconds between the checks of the files, reloaded when changed
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".into(),
+            port: 5000,
+            log: "info".into(),
+            redis: RedisConfig::default(),
+            auth: AuthConfig::default(),
+            pages: PagesConfig::default(),
+            admin: AdminConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            channels: ChannelsConfig::default(),
+            tls: TlsConfig::default(),
+            listeners: vec![],
+            origins: OriginsConfig::default(),
+            proxies: ProxiesConfig::default(),
+            limits: LimitsConfig::default(),
+            messages: MessagesConfig::default(),
+        }
+    }
+}
+
+impl Default for PagesConfig {
+    fn default() -> Self {
+        PagesConfig {
+            index: "/".into(),
+            admin: "/admin.html".into(),
+            dir: None,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig { stats_interval: 10 }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            event: "phx_close".into(),
+            payload: "{}".into(),
+            timeout: 10,
+        }
+    }
+}
+
+impl Default for ChannelsConfig {
+    fn default() -> Self {
+        ChannelsConfig {
+            special: SPECIAL_CHANNELS.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: None,
+            key: None,
+            client_ca: None,
+            reload_interval: 10,
+        }
+    }
+}
+
+/// keys applied on SIGHUP without dropping connections, a change of any other key needs a restart
+/// a section, e.g. `limits`, stands for all of its keys
+pub const RELOADABLE: [&str; 11] = [
+    "log",
+    "auth.jwt_secret",
+    "auth.jwt_algorithms",
+    "auth.jwt_keys",
+    "auth.jwt_issuer",
+    "auth.jwt_audience",
+    "channels.special",
+    "origins.allow",
+    "proxies.trusted",
+    "limits",
+    "messages",
+];
+
+const SECRETS: [&str; 2] = ["auth.api_key", "auth.jwt_secret"];
+
+/// every key settable from env and command line, with the type its raw value is parsed as
+const KEYS: [(&str, Kind); 37] = [
+    ("host", Kind::Str),
+    ("port", Kind::Int),
+    ("log", Kind::Str),
+    ("redis.url", Kind::Str),
+    ("redis.envelope", Kind::Bool),
+    ("redis.revocations", Kind::Str),
+    ("auth.api_key", Kind::Str),
+    ("auth.jwt_secret", Kind::Str),
+    ("auth.jwt_algorithms", Kind::List),
+    ("auth.jwt_keys", Kind::Str),
+    ("auth.jwt_issuer", Kind::Str),
+    ("auth.jwt_audience", Kind::List),
+    ("auth.jwt_reload_interval", Kind::Int),
+    ("auth.connect", Kind::Str),
+    ("auth.connect_param", Kind::Str),
+    ("auth.join", Kind::Str),
+    ("auth.join_channel", Kind::Str),
+    ("auth.join_timeout", Kind::Int),
+    ("pages.index", Kind::Str),
+    ("pages.admin", Kind::Str),
+    ("pages.dir", Kind::Str),
+    ("admin.stats_interval", Kind::Int),
+    ("shutdown.event", Kind::Str),
+    ("shutdown.payload", Kind::Str),
+    ("shutdown.timeout", Kind::Int),
+    ("channels.special", Kind::List),
+    ("tls.cert", Kind::Str),
+    ("tls.key", Kind::Str),
+    ("tls.client_ca", Kind::Str),
+    ("tls.reload_interval", Kind::Int),
+    ("origins.allow", Kind::List),
+    ("proxies.trusted", Kind::List),
+    ("messages.max_frame_bytes", Kind::Int),
+    ("messages.max_payload_bytes", Kind::Int),
+    ("messages.max_depth", Kind::Int),
+    ("messages.max_name_length", Kind::Int),
+    ("messages.name_chars", Kind::Str),
+];
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Int,
+    Bool,
+    List, // comma separated
+}
+
+/// a key changed by a reload, values as TOML, secrets masked
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl Change {
+    pub fn reloadable(&self) -> bool {
+        RELOADABLE
+            .iter()
+            .any(|key| self.key == *key || self.key.strip_prefix(key).is_some_and(|rest| rest.starts_with('.')))
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "`{}`: {} -> {}", self.key, self.old, self.new)
+    }
+}
+
+/// what is wrong, and where: the file, the env var or the key
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    Read { path: PathBuf, message: String },
+    Parse { source: String, message: String },
+    Invalid { key: String, message: String },
+}
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, message } => write!(formatter, "fail to read {}: {}", path.display(), message),
+            ConfigError::Parse { source, message } => write!(formatter, "{}: {}", source, message),
+            ConfigError::Invalid { key, message } => write!(formatter, "`{}`: {}", key, message),
+        }
+    }
+}
+
+/// env var of a key, e.g. `redis.url` is `CHANNELD_REDIS_URL`
+pub fn env_var(key: &str) -> String {
+    format!("CHANNELD_{}", key.replace('.', "_").to_uppercase())
+}
+
+impl Config {
+    /// layer the file, the env vars and the command line values on the defaults, then validate
+    /// `cli` holds the keys given on the command line, e.g. `("redis.url", "redis://...")`
+    pub fn load(path: Option<&Path>, env: impl Fn(&str) -> Option<String>, cli: &[(&str, String)]) -> Result<Config, ConfigError> {
+        let mut table = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Read {
+                    path: path.to_path_buf(),
+                    message: e.to_string(),
+                })?;
+                // parse errors of the file point at the line and the key
+                toml::from_str::<toml::Table>(&text).map_err(|e| ConfigError::Parse {
+                    source: path.display().to_string(),
+                    message: e.to_string(),
+                })?
+            }
+            None => toml::Table::new(),
+        };
+
+        for (key, kind) in KEYS {
+            if let Some(raw) = env(&env_var(key)) {
+                set(&mut table, key, kind, &raw).map_err(|message| ConfigError::Invalid { key: env_var(key), message })?;
+            }
+        }
+        for (key, raw) in cli {
+            let (_, kind) = KEYS.iter().find(|(k, _)| k == key).ok_or_else(|| ConfigError::Invalid {
+                key: key.to_string(),
+                message: "unknown key".into(),
+            })?;
+            set(&mut table, key, *kind, raw).map_err(|message| ConfigError::Invalid {
+                key: key.to_string(),
+                message,
+            })?;
+        }
+
+        let config: Config = serde_path_to_error::deserialize(toml::Value::Table(table)).map_err(|e| ConfigError::Invalid {
+            key: e.path().to_string(),
+            message: e.inner().to_string(),
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// checks serde cannot do
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: &str| {
+            Err(ConfigError::Invalid {
+                key: key.to_string(),
+                message: message.to_string(),
+            })
+        };
+        match &self.redis.url {
+            None => return invalid("redis.url", "required"),
+            Some(url) if redis::Client::open(url.as_str()).is_err() => return invalid("redis.url", "not a redis url"),
+            Some(_) => {}
+        }
+        if self.port == 0 {
+            return invalid("port", "must not be 0");
+        }
+        for (key, path) in [("pages.index", &self.pages.index), ("pages.admin", &self.pages.admin)] {
+            if !path.is_empty() && !path.starts_with('/') {
+                return invalid(key, "must start with `/`, or be empty to disable the page");
+            }
+        }
+        if !self.pages.index.is_empty() && self.pages.index == self.pages.admin {
+            return invalid("pages.admin", "must differ from `pages.index`");
+        }
+        if self.admin.stats_interval == 0 {
+            return invalid("admin.stats_interval", "must be at least 1 second");
+        }
+        if let Err(e) = serde_json::from_str::<serde_json::Value>(&self.shutdown.payload) {
+            return invalid("shutdown.payload", &format!("invalid JSON: {}", e));
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log) {
+            return invalid("log", &format!("invalid filter: {}", e));
+        }
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(_), None) => return invalid("tls.key", "required with `tls.cert`"),
+            (None, Some(_)) => return invalid("tls.cert", "required with `tls.key`"),
+            (None, None) if self.tls.client_ca.is_some() => return invalid("tls.client_ca", "requires `tls.cert` and `tls.key`"),
+            _ => {}
+        }
+        let mut asymmetric = false;
+        for name in self.auth.jwt_algorithms.iter() {
+            match name.parse::<jsonwebtoken::Algorithm>() {
+                Ok(algorithm) => asymmetric |= !is_hmac(algorithm),
+                Err(_) => return invalid("auth.jwt_algorithms", &format!("unknown algorithm `{}`", name)),
+            }
+        }
+        if self.auth.jwt_algorithms.is_empty() {
+            return invalid("auth.jwt_algorithms", "at least one algorithm is required");
+        }
+        match (asymmetric, &self.auth.jwt_keys) {
+            (true, None) => return invalid("auth.jwt_keys", "required by the RS*, PS*, ES* and EdDSA algorithms"),
+            (false, Some(_)) => return invalid("auth.jwt_algorithms", "`auth.jwt_keys` needs an RS*, PS*, ES* or EdDSA algorithm"),
+            _ => {}
+        }
+        if self.auth.connect != ConnectMode::Off && self.auth.connect_param.is_empty() {
+            return invalid("auth.connect_param", "required with `auth.connect`");
+        }
+        match (self.auth.join, &self.auth.join_channel) {
+            (JoinMode::Redis, None) => return invalid("auth.join_channel", "required with `auth.join = \"redis\"`"),
+            (JoinMode::Off | JoinMode::Jwt, Some(_)) => return invalid("auth.join", "must be `redis` with `auth.join_channel`"),
+            _ => {}
+        }
+        if self.auth.join == JoinMode::Redis && self.auth.join_timeout == 0 {
+            return invalid("auth.join_timeout", "must be at least 1 millisecond");
+        }
+        if self.auth.jwt_reload_interval == 0 {
+            return invalid("auth.jwt_reload_interval", "must be at least 1 second");
+        }
+        if self.tls.reload_interval == 0 {
+            return invalid("tls.reload_interval", "must be at least 1 second");
+        }
+        for pattern in self.origins.allow.iter() {
+            Origins::check(pattern).map_err(|message| ConfigError::Invalid {
+                key: "origins.allow".into(),
+                message,
+            })?;
+        }
+        for net in self.proxies.trusted.iter() {
+            Proxies::check(net).map_err(|message| ConfigError::Invalid {
+                key: "proxies.trusted".into(),
+                message,
+            })?;
+        }
+        let messages = &self.messages;
+        for (key, value) in [
+            ("messages.max_frame_bytes", messages.max_frame_bytes),
+            ("messages.max_payload_bytes", messages.max_payload_bytes),
+            ("messages.max_depth", messages.max_depth),
+            ("messages.max_name_length", messages.max_name_length),
+        ] {
+            if value == 0 {
+                return invalid(key, "must be at least 1");
+            }
+        }
+        if !messages.name_chars.chars().all(|c| c.is_ascii_punctuation()) {
+            return invalid("messages.name_chars", "only ascii punctuation, letters and digits are always allowed");
+        }
+        let limits = &self.limits;
+        let rates = [
+            ("limits.messages", &limits.messages),
+            ("limits.joins", &limits.joins),
+            ("limits.pushes", &limits.pushes),
+            ("limits.connections", &limits.connections),
+        ];
+        let channel_rates = limits.channels.iter().enumerate().flat_map(|(i, channel)| {
+            [("messages", &channel.messages), ("joins", &channel.joins), ("pushes", &channel.pushes)]
+                .map(|(name, rate)| (format!("limits.channels[{}].{}", i, name), rate))
+        });
+        for (key, rate) in rates.map(|(key, rate)| (key.to_string(), rate)).into_iter().chain(channel_rates) {
+            if rate.is_some_and(|rate| rate.per_second <= 0.0 || rate.burst == 0) {
+                return invalid(&key, "`per_second` must be positive, `burst` at least 1");
+            }
+        }
+        if let Some(i) = limits.channels.iter().position(|channel| channel.pattern.is_empty()) {
+            return invalid(&format!("limits.channels[{}].pattern", i), "must not be empty");
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if let Err(e) = ListenAddr::parse(&listener.address) {
+                return invalid(&format!("listeners[{}].address", i), &e);
+            }
+            if let Some(route) = listener.routes.iter().find(|route| !ROUTES.contains(&route.as_str())) {
+                return invalid(&format!("listeners[{}].routes", i), &format!("unknown route `{}`, expected one of {:?}", route, ROUTES));
+            }
+        }
+        for (i, name) in self.channels.special.iter().enumerate() {
+            if name.is_empty() || self.channels.special[..i].contains(name) {
+                return invalid("channels.special", &format!("empty or duplicated channel `{}`", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// the listeners, `host:port` with every route if none is configured
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![ListenerConfig {
+            address: format!("{}:{}", self.host, self.port),
+            ..Default::default()
+        }]
+    }
+
+    /// the keys whose values differ in `new`
+    pub fn diff(&self, new: &Config) -> Vec<Change> {
+        let (old, new) = (flatten(self), flatten(new));
+        let keys = old.keys().chain(new.keys()).collect::<std::collections::BTreeSet<&String>>();
+        let render = |key: &str, value: Option<&toml::Value>| match value {
+            None => "(unset)".to_string(),
+            Some(_) if SECRETS.contains(&key) => "********".to_string(),
+            Some(value) => value.to_string(),
+        };
+        keys.into_iter()
+            .filter(|key| old.get(*key) != new.get(*key))
+            .map(|key| Change {
+                key: key.clone(),
+                old: render(key, old.get(key)),
+                new: render(key, new.get(key)),
+            })
+            .collect()
+    }
+
+    /// the effective configuration as TOML, secrets masked
+    pub fn to_toml(&self) -> String {
+        let mut config = self.clone();
+        let mask = |secret: &mut Option<String>| {
+            if secret.is_some() {
+                *secret = Some("********".into());
+            }
+        };
+        mask(&mut config.auth.api_key);
+        mask(&mut config.auth.jwt_secret);
+        toml::to_string(&config).unwrap_or_default()
+    }
+}
+
+// dotted keys to values, e.g. `redis.url`
+fn flatten(config: &Config) -> BTreeMap<String, toml::Value> {
+    fn walk(prefix: &str, table: &toml::Table, out: &mut BTreeMap<String, toml::Value>) {
+        for (key, value) in table {
+            let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+            match value {
+                toml::Value::Table(table) => walk(&key, table, out),
+                value => {
+                    out.insert(key, value.clone());
+                }
+            }
+        }
+    }
+    let mut out = BTreeMap::new();
+    if let Ok(toml::Value::Table(table)) = toml::Value::try_from(config) {
+        walk("", &table, &mut out);
+    }
+    out
+}
+
+// `redis.url` => table["redis"]["url"]
+fn set(table: &mut toml::Table, key: &str, kind: Kind, raw: &str) -> Result<(), String> {
+    let value = match kind {
+        Kind::Str => toml::Value::String(raw.to_string()),
+        Kind::Int => toml::Value::Integer(raw.trim().parse::<i64>().map_err(|e| format!("`{}` is not an integer: {}", raw, e))?),
+        Kind::Bool => toml::Value::Boolean(raw.trim().parse::<bool>().map_err(|_| format!("`{}` is neither true nor false", raw))?),
+        Kind::List => toml::Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.to_string()))
+                .collect(),
+        ),
+    };
+    let mut parts = key.split('.').collect::<Vec<&str>>();
+    let last = parts.pop().unwrap_or(key);
+    let mut current = table;
+    for part in parts {
+        let entry = current.entry(part).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        current = entry.as_table_mut().ok_or_else(|| format!("`{}` is not a table", part))?;
+    }
+    current.insert(last.to_string(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn write(text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("channeld-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    const REDIS: &str = "[redis]\nurl = \"redis://localhost:6379\"\n";
+
+    #[test]
+    fn test_layers() {
+        let path = write(&format!("port = 6000\nhost = \"0.0.0.0\"\n{}[shutdown]\ntimeout = 3\n", REDIS));
+        let env = HashMap::from([
+            ("CHANNELD_PORT".to_string(), "7000".to_string()),
+            ("CHANNELD_LOG".to_string(), "debug".to_string()),
+            ("CHANNELD_REDIS_ENVELOPE".to_string(), "true".to_string()),
+            ("CHANNELD_AUTH_CONNECT".to_string(), "optional".to_string()),
+        ]);
+        let cli = [("port", "8000".to_string())];
+
+        let config = Config::load(Some(&path), |k| env.get(k).cloned(), &[]).unwrap();
+        assert_eq!(config.host, "0.0.0.0"); // file
+        assert_eq!(config.port, 7000); // env over file
+        assert_eq!(config.log, "debug");
+        assert_eq!(config.shutdown.timeout, 3);
+        assert_eq!(config.shutdown.event, "phx_close"); // default
+        assert!(config.redis.envelope);
+        assert_eq!(config.auth.connect, ConnectMode::Optional);
+        assert_eq!(config.auth.connect_param, "userToken");
+        assert_eq!(config.auth.join, JoinMode::Off);
+
+        let config = Config::load(Some(&path), |k| env.get(k).cloned(), &cli).unwrap();
+        assert_eq!(config.port, 8000); // cli over env
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_errors_point_at_keys() {
+        let no_env = |_: &str| None;
+
+        let path = write(&format!("{}[shutdown]\ntimout = 3\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert!(e.to_string().contains("timout"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+
+        let path = write(&format!("port = \"http\"\n{}", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "port"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+
+        let e = Config::load(None, |k| (k == "CHANNELD_PORT").then(|| "x".to_string()), &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "CHANNELD_PORT"), "{}", e);
+
+        let e = Config::load(None, no_env, &[]).unwrap_err();
+        assert_eq!(e.to_string(), "`redis.url`: required");
+
+        let env = |k: &str| match k {
+            "CHANNELD_REDIS_URL" => Some("redis://localhost".to_string()),
+            "CHANNELD_ORIGINS_ALLOW" => Some("https://example.com, example.org".to_string()),
+            _ => None,
+        };
+        let e = Config::load(None, env, &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "origins.allow"), "{}", e);
+
+        let path = write(&format!("{}[proxies]\ntrusted = [\"10.0.0.0/40\"]\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "proxies.trusted"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+
+        let path = write(&format!("{}[auth]\njoin = \"redis\"\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert_eq!(e.to_string(), "`auth.join_channel`: required with `auth.join = \"redis\"`");
+        std::fs::remove_file(path).unwrap();
+        let path = write(&format!("{}[auth]\njoin_channel = \"joins\"\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "auth.join"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+
+        let path = write(&format!("{}[pages]\nadmin = \"admin\"\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "pages.admin"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+
+        let path = write(&format!("{}[pages]\nindex = \"/\"\nadmin = \"/\"\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert_eq!(e.to_string(), "`pages.admin`: must differ from `pages.index`");
+        std::fs::remove_file(path).unwrap();
+
+        let path = write(&format!("{}[limits]\njoins = {{ per_second = 0, burst = 1 }}\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "limits.joins"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+
+        let path = write(&format!("{}[auth]\njwt_algorithms = [\"RS256\"]\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "auth.jwt_keys"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+
+        let path = write(&format!("{}[tls]\ncert = \"cert.pem\"\n", REDIS));
+        let e = Config::load(Some(&path), no_env, &[]).unwrap_err();
+        assert_eq!(e.to_string(), "`tls.key`: required with `tls.cert`");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_diff() {
+        let env = |k: &str| (k == "CHANNELD_CHANNELS_SPECIAL").then(|| "phoenix, admin,system,news".to_string());
+        let path = write(REDIS);
+        let old = Config::load(Some(&path), |_| None, &[]).unwrap();
+        let mut new = Config::load(Some(&path), env, &[("auth.jwt_secret", "new".to_string())]).unwrap();
+        new.port = 6000;
+        std::fs::remove_file(path).unwrap();
+
+        let changes = old.diff(&new);
+        let lines = changes.iter().map(|c| c.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            lines,
+            vec![
+                "`auth.jwt_secret`: (unset) -> ********",
+                "`channels.special`: [\"phoenix\", \"admin\", \"system\"] -> [\"phoenix\", \"admin\", \"system\", \"news\"]",
+                "`port`: 5000 -> 6000",
+            ]
+        );
+        assert_eq!(changes.iter().map(Change::reloadable).collect::<Vec<bool>>(), vec![true, true, false]);
+        let change = |key: &str| Change {
+            key: key.into(),
+            old: String::new(),
+            new: String::new(),
+        };
+        assert!(change("limits.messages.burst").reloadable());
+        assert!(!change("limitsx").reloadable());
+        assert!(old.diff(&old).is_empty());
+
+        let e = Config::load(None, |_| None, &[("redis.url", "redis://localhost".into()), ("log", "info,=x".into())]);
+        assert!(matches!(e, Err(ConfigError::Invalid { key, .. }) if key == "log"));
+    }
+
+    #[test]
+    fn test_listeners() {
+        let config = Config::load(None, |_| None, &[("redis.url", "redis://localhost".into())]).unwrap();
+        assert_eq!(
+            config.listeners(),
+            vec![ListenerConfig {
+                address: "127.0.0.1:5000".into(),
+                routes: DEFAULT_ROUTES.iter().map(|r| r.to_string()).collect()
+            }]
+        );
+
+        let path = write(&format!("{}[[listeners]]\naddress = \"unix:/tmp/channeld.sock\"\nroutes = [\"admin\"]\n", REDIS));
+        let config = Config::load(Some(&path), |_| None, &[]).unwrap();
+        assert_eq!(config.listeners()[0].routes, vec!["admin"]);
+        std::fs::remove_file(path).unwrap();
+
+        let path = write(&format!("{}[[listeners]]\naddress = \"0.0.0.0:80\"\n[[listeners]]\naddress = \"unix:/a\"\nroutes = [\"admn\"]\n", REDIS));
+        let e = Config::load(Some(&path), |_| None, &[]).unwrap_err();
+        assert!(matches!(&e, ConfigError::Invalid { key, .. } if key == "listeners[1].routes"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_to_toml_masks_secrets() {
+        let mut config = Config::default();
+        config.auth.api_key = Some("key".into());
+        let text = config.to_toml();
+        assert!(text.contains("api_key = \"********\""));
+        assert!(!text.contains("\"key\""));
+    }
+}
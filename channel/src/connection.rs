@@ -0,0 +1,362 @@
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::{fmt::Display, sync::Arc, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::channel::{ChannelMessage, ConnInfo};
+use crate::metrics::{Metrics, METRICS};
+use crate::websocket::{Phase, Session, State};
+
+/// a client that sends nothing, not even the phoenix heartbeat (every 30s by default), is disconnected
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// websocket frame, independent of the http framework serving the websocket
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>),
+}
+
+/// drive a websocket connection, from upgrade to cleanup
+///
+/// frames from the client are handled by the phoenix protocol, messages for the connection are sent back as text frames.
+/// the connection ends when the client closes, the transport fails or the heartbeat times out,
+/// all agents of the connection are cleaned up then.
+pub async fn drive<S, K>(state: Arc<State>, info: ConnInfo, mut stream: S, mut sink: K)
+where
+    S: Stream<Item = Frame> + Unpin + Send + 'static,
+    K: Sink<Frame> + Unpin + Send + 'static,
+    K::Error: Display,
+{
+    let conn_id = info.conn_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+    let identity = info.identity.clone();
+    let conn_rx = {
+        let ctl = state.ctl.lock().await;
+        ctl.conn_add_tx(conn_id.clone()).await;
+        ctl.conn_info_set(&conn_id, info).await;
+        ctl.conn_rx(conn_id.clone()).await
+    };
+    state.identity_set(&conn_id, identity.as_ref());
+    let Ok(mut conn_rx) = conn_rx else {
+        error!("CONN / {} is gone before it is driven", conn_id);
+        return;
+    };
+    info!("CONN / {} connected", conn_id);
+
+    // conn rx => ws tx
+    let ws_tx_conn_id = conn_id.clone();
+    let mut ws_tx_task = tokio::spawn(async move {
+        loop {
+            match conn_rx.recv().await {
+                Ok(ChannelMessage::Reply(reply_message)) => {
+                    let text = match serde_json::to_string(&reply_message) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            error!("CONN / {} fail to serialize reply message: {}", ws_tx_conn_id, e);
+                            break;
+                        }
+                    };
+                    if let Err(e) = sink.send(Frame::Text(text)).await {
+                        error!("CONN / {} websocket tx sending failed: {}", ws_tx_conn_id, e);
+                        break; // exit if the connection is lost
+                    }
+                    Metrics::inc(&METRICS.ws_out);
+                }
+                Ok(ChannelMessage::Close { code, reason }) => {
+                    info!("CONN / {} closing, {} {}", ws_tx_conn_id, code, reason);
+                    if let Err(e) = sink.send(Frame::Close(Some((code, reason)))).await {
+                        error!("CONN / {} websocket close failed: {}", ws_tx_conn_id, e);
+                    }
+                    break;
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("CONN / {} lagged, {} messages skipped", ws_tx_conn_id, n);
+                    Metrics::add(&METRICS.lagged, n);
+                }
+                Err(RecvError::Closed) => {
+                    error!("CONN / {} conn rx closed", ws_tx_conn_id);
+                    break;
+                }
+            }
+        }
+    });
+
+    // ws rx => session
+    let ws_rx_conn_id = conn_id.clone();
+    let mut session = Session::new(state.clone(), &conn_id, identity);
+    let mut ws_rx_task = tokio::spawn(async move {
+        loop {
+            let frame = match tokio::time::timeout(HEARTBEAT_TIMEOUT, stream.next()).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    info!("CONN / {} websocket rx ends", ws_rx_conn_id);
+                    break;
+                }
+                Err(_) => {
+                    warn!("CONN / {} heartbeat timeout, nothing received in {:?}", ws_rx_conn_id, HEARTBEAT_TIMEOUT);
+                    break;
+                }
+            };
+            if session.on_frame(frame).await == Phase::Closed {
+                break;
+            }
+        }
+    });
+
+    // Wait for either task to finish: 一个结束了总是等另外一个
+    tokio::select! {
+        _ = (&mut ws_tx_task) => {
+            debug!("CONN / {} ws_tx_task exits, ws_rx_task aborts", conn_id);
+            ws_rx_task.abort();
+        },
+        _ = (&mut ws_rx_task) => {
+            debug!("CONN / {} ws_rx_task exits, ws_tx_task aborts", conn_id);
+            ws_tx_task.abort();
+        },
+    }
+
+    let closing = state.ctl.lock().await.conn_cleanup(conn_id.clone()).await;
+    closing.terminate().await;
+    state.limits.conn_rm(&conn_id);
+    state.grants_conn_rm(&conn_id);
+    info!("CONN / {} closed", conn_id);
+}
+
+impl From<axum::extract::ws::Message> for Frame {
+    fn from(msg: axum::extract::ws::Message) -> Self {
+        use axum::extract::ws::Message;
+        match msg {
+            Message::Text(text) => Frame::Text(text),
+            Message::Binary(data) => Frame::Binary(data),
+            Message::Ping(data) => Frame::Ping(data),
+            Message::Pong(data) => Frame::Pong(data),
+            Message::Close(frame) => Frame::Close(frame.map(|f| (f.code, f.reason.into_owned()))),
+        }
+    }
+}
+
+impl From<Frame> for axum::extract::ws::Message {
+    fn from(frame: Frame) -> Self {
+        use axum::extract::ws::{CloseFrame, Message};
+        match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(data) => Message::Binary(data),
+            Frame::Ping(data) => Message::Ping(data),
+            Frame::Pong(data) => Message::Pong(data),
+            Frame::Close(close) => Message::Close(close.map(|(code, reason)| CloseFrame { code, reason: reason.into() })),
+        }
+    }
+}
+
+impl From<warp::ws::Message> for Frame {
+    fn from(msg: warp::ws::Message) -> Self {
+        if let Ok(text) = msg.to_str() {
+            Frame::Text(text.to_string())
+        } else if msg.is_close() {
+            Frame::Close(msg.close_frame().map(|(code, reason)| (code, reason.to_string())))
+        } else if msg.is_ping() {
+            Frame::Ping(msg.into_bytes())
+        } else if msg.is_pong() {
+            Frame::Pong(msg.into_bytes())
+        } else {
+            Frame::Binary(msg.into_bytes())
+        }
+    }
+}
+
+impl From<Frame> for warp::ws::Message {
+    fn from(frame: Frame) -> Self {
+        use warp::ws::Message;
+        match frame {
+            Frame::Text(text) => Message::text(text),
+            Frame::Binary(data) => Message::binary(data),
+            Frame::Ping(data) => Message::ping(data),
+            Frame::Pong(data) => Message::pong(data),
+            Frame::Close(Some((code, reason))) => Message::close_with(code, reason),
+            Frame::Close(None) => Message::close(),
+        }
+    }
+}
+
+impl From<tungstenite::Message> for Frame {
+    fn from(msg: tungstenite::Message) -> Self {
+        use tungstenite::Message;
+        match msg {
+            Message::Text(text) => Frame::Text(text.to_string()),
+            Message::Binary(data) => Frame::Binary(data.to_vec()),
+            Message::Ping(data) => Frame::Ping(data.to_vec()),
+            Message::Pong(data) => Frame::Pong(data.to_vec()),
+            Message::Close(frame) => Frame::Close(frame.map(|f| (f.code.into(), f.reason.to_string()))),
+            Message::Frame(frame) => Frame::Binary(frame.into_payload().to_vec()),
+        }
+    }
+}
+
+impl From<Frame> for tungstenite::Message {
+    fn from(frame: Frame) -> Self {
+        use tungstenite::protocol::CloseFrame;
+        use tungstenite::Message;
+        match frame {
+            Frame::Text(text) => Message::text(text),
+            Frame::Binary(data) => Message::binary(data),
+            Frame::Ping(data) => Message::Ping(data.into()),
+            Frame::Pong(data) => Message::Pong(data.into()),
+            Frame::Close(close) => Message::Close(close.map(|(code, reason)| CloseFrame {
+                code: code.into(),
+                reason: reason.into(),
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::socket::ChannelSocket;
+    use crate::websocket::{shutdown, tungstenite_on_connected};
+    use futures::channel::mpsc;
+    use serde_json::{json, Value};
+
+    // connection over plain channels, no http nor websocket involved
+    fn connect(state: Arc<State>) -> (mpsc::UnboundedSender<Frame>, mpsc::UnboundedReceiver<Frame>, tokio::task::JoinHandle<()>) {
+        let (client_tx, server_rx) = mpsc::unbounded();
+        let (server_tx, client_rx) = mpsc::unbounded();
+        let task = tokio::spawn(drive(state, ConnInfo::default(), server_rx, server_tx));
+        (client_tx, client_rx, task)
+    }
+
+    async fn request(tx: &mut mpsc::UnboundedSender<Frame>, rx: &mut mpsc::UnboundedReceiver<Frame>, text: &str) -> Value {
+        tx.send(Frame::Text(text.to_string())).await.unwrap();
+        match rx.next().await.unwrap() {
+            Frame::Text(text) => serde_json::from_str(&text).unwrap(),
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drive_join_leave_close() {
+        let state = ChannelSocket::builder().build().state();
+        let (mut tx, mut rx, task) = connect(state.clone());
+
+        let resp = request(&mut tx, &mut rx, r#"[null,"1","phoenix","heartbeat",{}]"#).await;
+        assert_eq!(resp[4]["status"], "ok");
+
+        let resp = request(&mut tx, &mut rx, r#"["1","2","room:1","phx_join",{}]"#).await;
+        assert_eq!(resp[1], "2");
+        assert_eq!(resp[4]["status"], "ok");
+        assert!(!state.ctl.lock().await.channels.lock().await.get("room:1").unwrap().empty());
+
+        // frames the protocol does not handle are ignored
+        tx.send(Frame::Binary(vec![1, 2, 3])).await.unwrap();
+        let resp = request(&mut tx, &mut rx, r#"["1","3","room:1","phx_leave",{}]"#).await;
+        assert_eq!(resp[1], "3");
+        assert_eq!(resp[4]["status"], "ok");
+        assert!(!state.ctl.lock().await.channel_exists("room:1").await);
+
+        let resp = request(&mut tx, &mut rx, r#"["4","4","room:2","phx_join",{}]"#).await;
+        assert_eq!(resp[4]["status"], "ok");
+
+        tx.send(Frame::Close(None)).await.unwrap();
+        task.await.unwrap();
+        assert!(state.ctl.lock().await.channels.lock().await.get("room:2").unwrap().empty());
+    }
+
+    #[tokio::test]
+    async fn test_drive_protocol_errors() {
+        let state = ChannelSocket::builder().build().state();
+        let (mut tx, mut rx, task) = connect(state.clone());
+
+        // ignored, the next request is answered
+        tx.send(Frame::Text("not json".into())).await.unwrap();
+        tx.send(Frame::Text(r#"["1","1","room:1"]"#.into())).await.unwrap();
+        let resp = request(&mut tx, &mut rx, r#"[null,"2","room:1","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "missing join_ref" } }));
+        let resp = request(&mut tx, &mut rx, r#"[null,"3","room:1","phx_leave",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "missing join_ref" } }));
+        let resp = request(&mut tx, &mut rx, r#"["1","4","room:1","phx_leave",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "unmatched topic" } }));
+
+        let resp = request(&mut tx, &mut rx, r#"["1","5","room:1","phx_join",{}]"#).await;
+        assert_eq!(resp[4]["status"], "ok");
+        let resp = request(&mut tx, &mut rx, r#"["2","6","room:1","phx_leave",{}]"#).await;
+        assert_eq!(resp[4]["response"]["reason"], "unmatched topic");
+        let resp = request(&mut tx, &mut rx, r#"["1","7","room:1","phx_leave",{}]"#).await;
+        assert_eq!(resp[4]["status"], "ok");
+
+        // a special channel which was never added
+        let resp = request(&mut tx, &mut rx, r#"["9","8","system","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "join_failed" } }));
+
+        // too deep, answered from the refs without parsing the payload
+        state.set_message_limits(crate::config::MessagesConfig {
+            max_depth: 1,
+            ..Default::default()
+        });
+        let resp = request(&mut tx, &mut rx, r#"["1","8","room:1","ping",{"a":{"b":1}}]"#).await;
+        assert_eq!(resp, json!(["1", "8", "room:1", "phx_reply", { "status": "error", "response": { "reason": "too_deep" } }]));
+
+        // closed with the code of the error, nothing is handled after
+        state.set_message_limits(crate::config::MessagesConfig {
+            max_frame_bytes: 16,
+            ..Default::default()
+        });
+        tx.send(Frame::Text(r#"["1","8","room:1","phx_join",{}]"#.into())).await.unwrap();
+        assert_eq!(rx.next().await.unwrap(), Frame::Close(Some((1009, "message too big".into()))));
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drive_shutdown() {
+        let state = ChannelSocket::builder().build().state();
+        let (mut tx, mut rx, task) = connect(state.clone());
+        let resp = request(&mut tx, &mut rx, r#"["7","1","room:1","phx_join",{}]"#).await;
+        assert_eq!(resp[4]["status"], "ok");
+
+        let remaining = shutdown(&state, "phx_close", json!({}), Duration::from_secs(1)).await;
+        assert_eq!(remaining, 0);
+        assert_eq!(rx.next().await.unwrap(), Frame::Text(r#"["7","7","room:1","phx_close",{}]"#.to_string()));
+        assert_eq!(rx.next().await.unwrap(), Frame::Close(Some((1001, "server shutdown".into()))));
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tungstenite_on_connected() {
+        use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+        let state = ChannelSocket::builder().build().state();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            tungstenite_on_connected(WebSocketStream::from_raw_socket(server, Role::Server, None).await, state, ConnInfo::default()).await
+        });
+
+        let mut ws = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        ws.send(tungstenite::Message::text(r#"[null,"1","phoenix","heartbeat",{}]"#))
+            .await
+            .unwrap();
+        let msg = ws.next().await.unwrap().unwrap();
+        let resp: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+        assert_eq!(resp, json!([null, "1", "phoenix", "phx_reply", { "status": "ok", "response": {} }]));
+    }
+
+    #[test]
+    fn test_frame_conversions() {
+        let close = Frame::Close(Some((1000, "bye".into())));
+        assert_eq!(Frame::from(axum::extract::ws::Message::from(close.clone())), close);
+        assert_eq!(Frame::from(warp::ws::Message::from(close.clone())), close);
+        assert_eq!(Frame::from(tungstenite::Message::from(close.clone())), close);
+
+        let text = Frame::Text("hello".into());
+        assert_eq!(Frame::from(axum::extract::ws::Message::from(text.clone())), text);
+        assert_eq!(Frame::from(warp::ws::Message::from(text.clone())), text);
+        assert_eq!(Frame::from(tungstenite::Message::from(text.clone())), text);
+
+        let binary = Frame::Binary(vec![1, 2, 3]);
+        assert_eq!(Frame::from(warp::ws::Message::from(binary.clone())), binary);
+        assert_eq!(Frame::from(tungstenite::Message::from(binary.clone())), binary);
+    }
+}
@@ -0,0 +1,100 @@
+use axum::http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderValue, Method,
+};
+use std::sync::Arc;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::websocket::State;
+
+/// origins allowed to open websockets and to call the http api from a browser
+/// `https://example.com` matches exactly, `https://*.example.com` any subdomain, `*` any origin
+/// an empty list allows any origin
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Origins {
+    patterns: Vec<String>,
+}
+
+impl Origins {
+    pub fn new(patterns: &[String]) -> Self {
+        Origins {
+            patterns: patterns.iter().map(|pattern| pattern.to_lowercase()).collect(),
+        }
+    }
+
+    /// a pattern is `*`, or `scheme://host[:port]` whose host may start with `*.`
+    pub fn check(pattern: &str) -> Result<(), String> {
+        if pattern == "*" {
+            return Ok(());
+        }
+        let Some((scheme, host)) = pattern.split_once("://") else {
+            return Err(format!("`{}` has no scheme, e.g. `https://example.com`", pattern));
+        };
+        let host = host.strip_prefix("*.").unwrap_or(host);
+        if scheme.is_empty() || host.is_empty() || host.contains(['*', '/']) {
+            return Err(format!("`{}` is not `scheme://host[:port]`, with an optional `*.` before the host", pattern));
+        }
+        Ok(())
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        self.patterns.is_empty() || self.patterns.iter().any(|pattern| matches(pattern, &origin))
+    }
+}
+
+fn matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" || pattern == origin {
+        return true;
+    }
+    // `https://*.example.com` matches `https://a.example.com` and `https://a.b.example.com`, not `https://example.com`
+    match (pattern.split_once("://*."), origin.split_once("://")) {
+        (Some((scheme, domain)), Some((origin_scheme, host))) => {
+            scheme == origin_scheme && host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+        }
+        _ => false,
+    }
+}
+
+/// CORS of the http api, for the origins allowed by the state, which are reloadable
+pub fn layer(state: Arc<State>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| origin.to_str().is_ok_and(|origin| state.origins().allows(origin))))
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let origins = Origins::new(&[
+            "https://example.com".into(),
+            "https://*.Example.org".into(),
+            "http://localhost:5000".into(),
+        ]);
+        assert!(origins.allows("https://example.com"));
+        assert!(!origins.allows("http://example.com"));
+        assert!(!origins.allows("https://a.example.com"));
+        assert!(origins.allows("https://a.example.org"));
+        assert!(origins.allows("https://a.b.example.org"));
+        assert!(!origins.allows("https://example.org"));
+        assert!(!origins.allows("https://evilexample.org"));
+        assert!(origins.allows("http://localhost:5000"));
+        assert!(!origins.allows("http://localhost:5001"));
+
+        assert!(Origins::default().allows("https://any.where"));
+        assert!(Origins::new(&["*".into()]).allows("https://any.where"));
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(Origins::check("*").is_ok());
+        assert!(Origins::check("https://*.example.com").is_ok());
+        assert!(Origins::check("example.com").is_err());
+        assert!(Origins::check("https://a.*.example.com").is_err());
+        assert!(Origins::check("https://example.com/path").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// what a token is held by, a joined agent with its grant or a connection with its identity
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Holder {
+    Agent(String), // agent_id
+    Conn(String),  // conn_id
+}
+
+/// the expiry and the token id of the agents and connections,
+/// the expired and the holders of a revoked token are found without walking all of them
+#[derive(Debug, Default)]
+pub struct TokenIndex {
+    expiry: BTreeSet<(u64, Holder)>,                         // unix time it expires at, holder
+    tokens: HashMap<String, HashSet<Holder>>,                // token id -> holders
+    holders: HashMap<Holder, (Option<u64>, Option<String>)>, // holder -> expiry and token id, as indexed
+}
+
+impl TokenIndex {
+    /// index the expiry and the token of the holder, in place of the previous ones
+    pub fn set(&mut self, holder: Holder, expires: Option<u64>, token_id: Option<String>) {
+        self.rm(&holder);
+        if expires.is_none() && token_id.is_none() {
+            return;
+        }
+        if let Some(expires) = expires {
+            self.expiry.insert((expires, holder.clone()));
+        }
+        if let Some(token_id) = &token_id {
+            self.tokens.entry(token_id.clone()).or_default().insert(holder.clone());
+        }
+        self.holders.insert(holder, (expires, token_id));
+    }
+
+    pub fn rm(&mut self, holder: &Holder) {
+        let Some((expires, token_id)) = self.holders.remove(holder) else {
+            return;
+        };
+        if let Some(expires) = expires {
+            self.expiry.remove(&(expires, holder.clone()));
+        }
+        if let Some(token_id) = token_id {
+            if let Some(holders) = self.tokens.get_mut(&token_id) {
+                holders.remove(holder);
+                if holders.is_empty() {
+                    self.tokens.remove(&token_id);
+                }
+            }
+        }
+    }
+
+    /// take the holders expired at `now`, unix time, they are not indexed anymore
+    pub fn expired(&mut self, now: u64) -> Vec<Holder> {
+        let expired = self
+            .expiry
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .map(|(_, holder)| holder.clone())
+            .collect::<Vec<Holder>>();
+        for holder in &expired {
+            self.rm(holder);
+        }
+        expired
+    }
+
+    /// the holders of the token, sorted
+    pub fn holding(&self, token_id: &str) -> Vec<Holder> {
+        let mut holders = self
+            .tokens
+            .get(token_id)
+            .map(|holders| holders.iter().cloned().collect::<Vec<Holder>>())
+            .unwrap_or_default();
+        holders.sort();
+        holders
+    }
+
+    pub fn len(&self) -> usize {
+        self.holders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.holders.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        let agent = |id: &str| Holder::Agent(id.into());
+        let conn = |id: &str| Holder::Conn(id.into());
+        let mut index = TokenIndex::default();
+        index.set(conn("conn1"), Some(100), Some("t1".into()));
+        index.set(agent("conn1:room:1:1"), Some(50), Some("t2".into()));
+        index.set(agent("conn1:room:2:2"), None, Some("t1".into()));
+        index.set(conn("conn2"), None, None); // nothing to index
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.holding("t1"), vec![agent("conn1:room:2:2"), conn("conn1")]);
+
+        // renewed, the previous expiry and token are forgotten
+        index.set(agent("conn1:room:1:1"), Some(150), Some("t3".into()));
+        assert!(index.holding("t2").is_empty());
+        assert!(index.expired(99).is_empty());
+        assert_eq!(index.expired(100), vec![conn("conn1")]);
+        assert_eq!(index.holding("t1"), vec![agent("conn1:room:2:2")]);
+
+        index.rm(&agent("conn1:room:2:2"));
+        index.rm(&conn("unknown"));
+        assert_eq!(index.expired(u64::MAX), vec![agent("conn1:room:1:1")]);
+        assert!(index.is_empty() && index.holding("t3").is_empty());
+    }
+}
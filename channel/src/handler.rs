@@ -0,0 +1,233 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::channel::{ChannelError, ChannelMessage};
+use crate::websocket::{ServerMessage, ServerPayload};
+
+/// what `ChannelHandler::handle_in` answers to a client event
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Ok(Value),    // phx_reply with status ok
+    Error(Value), // phx_reply with status error
+    NoReply,
+}
+
+/// a joined agent as seen by a channel handler
+/// it pushes to the client of the agent, or broadcasts to every agent of the channel
+#[derive(Clone, Debug)]
+pub struct Socket {
+    pub conn_id: String,
+    pub agent_id: String,
+    pub topic: String,
+    pub join_ref: Option<String>,
+    conn_tx: broadcast::Sender<ChannelMessage>,
+    channel_tx: broadcast::Sender<ChannelMessage>,
+}
+
+impl Socket {
+    pub fn new(
+        conn_id: String, agent_id: String, topic: String, join_ref: Option<String>, conn_tx: broadcast::Sender<ChannelMessage>,
+        channel_tx: broadcast::Sender<ChannelMessage>,
+    ) -> Self {
+        Socket {
+            conn_id,
+            agent_id,
+            topic,
+            join_ref,
+            conn_tx,
+            channel_tx,
+        }
+    }
+
+    /// push an event to the client of this agent only
+    pub fn push(&self, event: &str, payload: Value) -> Result<usize, ChannelError> {
+        let message = self.message(self.join_ref.clone(), event, payload);
+        self.conn_tx.send(message).map_err(|_| ChannelError::MessageSendError)
+    }
+
+    /// broadcast an event to every agent in the channel, this one included
+    /// it returns the number of agents who received the message
+    pub fn broadcast(&self, event: &str, payload: Value) -> Result<usize, ChannelError> {
+        let message = self.message(None, event, payload);
+        self.channel_tx.send(message).map_err(|_| ChannelError::ChannelEmpty)
+    }
+
+    fn message(&self, join_ref: Option<String>, event: &str, payload: Value) -> ChannelMessage {
+        ChannelMessage::Reply(ServerMessage {
+            join_ref,
+            event_ref: "0".into(),
+            topic: self.topic.clone(),
+            event: event.to_string(),
+            payload: ServerPayload::ServerJsonValue(payload),
+        })
+    }
+}
+
+/// in-process channel logic, the counterpart of a phoenix channel module
+/// handlers are registered per topic pattern with `ChannelControl::handler_add`,
+/// events of those topics are handled here instead of being published to redis
+#[async_trait]
+pub trait ChannelHandler: Send + Sync {
+    /// `phx_join` from the client, `Ok(response)` accepts the join, `Err(reason)` refuses it
+    async fn join(&self, topic: &str, payload: &Value, socket: &Socket) -> Result<Value, Value>;
+
+    /// any other event pushed by a joined client
+    async fn handle_in(&self, event: &str, payload: &Value, socket: &Socket) -> Reply;
+
+    /// messages sent to the channel with `websocket::channel_info`
+    async fn handle_info(&self, _message: &Value, _socket: &Socket) {}
+
+    /// the agent is gone: `leave`, `kicked`, `closed` when the connection ends, `removed` with the channel,
+    /// or `error` when the join fails after the handler accepted it
+    /// it is called once the locks of the control are released
+    async fn terminate(&self, _reason: &str, _socket: &Socket) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::ChannelControl;
+    use crate::utils::topic_matches;
+    use crate::websocket::channel_info;
+    use serde_json::json;
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+    use tokio::sync::Mutex;
+    use tokio::time::timeout;
+
+    #[derive(Default)]
+    struct RoomHandler {
+        infos: AtomicU32,
+        terminated: AtomicU32,
+    }
+
+    #[async_trait]
+    impl ChannelHandler for RoomHandler {
+        async fn join(&self, topic: &str, _payload: &Value, _socket: &Socket) -> Result<Value, Value> {
+            Ok(json!({ "topic": topic }))
+        }
+
+        async fn handle_in(&self, event: &str, payload: &Value, _socket: &Socket) -> Reply {
+            match event {
+                "ping" => Reply::Ok(payload.clone()),
+                _ => Reply::NoReply,
+            }
+        }
+
+        async fn handle_info(&self, message: &Value, socket: &Socket) {
+            self.infos.fetch_add(1, Ordering::SeqCst);
+            socket.push("info", message.clone()).unwrap();
+        }
+
+        async fn terminate(&self, _reason: &str, _socket: &Socket) {
+            self.terminated.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("room:*", "room:42"));
+        assert!(topic_matches("room:*", "room:"));
+        assert!(!topic_matches("room:*", "lobby"));
+        assert!(topic_matches("lobby", "lobby"));
+        assert!(!topic_matches("lobby", "lobby:1"));
+        assert!(topic_matches("*", "anything"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_lookup() {
+        let ctl = ChannelControl::new();
+        assert!(ctl.handler_for("room:1").await.is_none());
+
+        ctl.handler_add("room:*", Arc::new(RoomHandler::default())).await;
+        assert!(ctl.handler_for("room:1").await.is_some());
+        assert!(ctl.handler_for("lobby").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_socket_push_and_info() {
+        let ctl = Mutex::new(ChannelControl::new());
+        let handler = Arc::new(RoomHandler::default());
+        ctl.lock().await.channel_add("room:1".into(), None).await;
+        ctl.lock().await.conn_add_tx("conn1".into()).await;
+        let mut conn_rx = ctl.lock().await.conn_rx("conn1".into()).await.unwrap();
+
+        let socket = ctl
+            .lock()
+            .await
+            .socket_new("conn1", "conn1:room:1:1", "room:1", Some("1".into()))
+            .await
+            .unwrap();
+        let response = handler.join("room:1", &json!({}), &socket).await.unwrap();
+        assert_eq!(response, json!({ "topic": "room:1" }));
+        ctl.lock().await.socket_add(socket, handler.clone()).await;
+
+        let socket = ctl.lock().await.socket_get("conn1:room:1:1").await.unwrap();
+        let reply = handler.handle_in("ping", &json!({ "n": 1 }), &socket).await;
+        assert_eq!(reply, Reply::Ok(json!({ "n": 1 })));
+
+        assert_eq!(channel_info(&ctl, "room:1", json!({ "hello": "world" })).await.unwrap(), 1);
+        assert_eq!(handler.infos.load(Ordering::SeqCst), 1);
+        let ChannelMessage::Reply(message) = conn_rx.recv().await.unwrap() else {
+            panic!("reply expected");
+        };
+        assert_eq!(message.event, "info");
+        assert_eq!(message.join_ref, Some("1".into()));
+
+        let closing = ctl.lock().await.conn_cleanup("conn1".into()).await;
+        assert_eq!(handler.terminated.load(Ordering::SeqCst), 0);
+        closing.terminate().await;
+        assert_eq!(handler.terminated.load(Ordering::SeqCst), 1);
+        assert!(ctl.lock().await.socket_get("conn1:room:1:1").await.is_none());
+    }
+
+    /// a handler calling back into the control from its callbacks
+    struct ReentrantHandler {
+        ctl: Arc<Mutex<ChannelControl>>,
+    }
+
+    #[async_trait]
+    impl ChannelHandler for ReentrantHandler {
+        async fn join(&self, _topic: &str, _payload: &Value, _socket: &Socket) -> Result<Value, Value> {
+            Ok(json!({}))
+        }
+
+        async fn handle_in(&self, _event: &str, _payload: &Value, _socket: &Socket) -> Reply {
+            Reply::NoReply
+        }
+
+        async fn handle_info(&self, _message: &Value, socket: &Socket) {
+            self.ctl.lock().await.socket_get(&socket.agent_id).await;
+        }
+
+        async fn terminate(&self, _reason: &str, socket: &Socket) {
+            self.ctl.lock().await.socket_get(&socket.agent_id).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_reentrant() {
+        let ctl = Arc::new(Mutex::new(ChannelControl::new()));
+        let handler = Arc::new(ReentrantHandler { ctl: ctl.clone() });
+        ctl.lock().await.channel_add("room:1".into(), None).await;
+        ctl.lock().await.conn_add_tx("conn1".into()).await;
+        let socket = ctl.lock().await.socket_new("conn1", "conn1:room:1:1", "room:1", None).await.unwrap();
+        ctl.lock().await.socket_add(socket, handler).await;
+
+        let info = channel_info(&ctl, "room:1", json!({}));
+        assert_eq!(timeout(Duration::from_secs(1), info).await.unwrap().unwrap(), 1);
+        let closing = ctl.lock().await.channel_rm("room:1".into()).await;
+        timeout(Duration::from_secs(1), closing.terminate()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_channel_info_not_found() {
+        let ctl = Mutex::new(ChannelControl::new());
+        let result = channel_info(&ctl, "nonexistent", json!({})).await;
+        assert!(matches!(result.unwrap_err(), ChannelError::ChannelNotFound));
+    }
+}
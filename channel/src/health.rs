@@ -0,0 +1,95 @@
+use std::time::Duration;
+use tracing::warn;
+
+use crate::websocket::State;
+
+/// the broker has to answer in time for the node to be ready
+pub const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// readiness of the node: the broker answers `PING`, and every special channel relays messages from it
+/// `Err` lists the failing subsystems, e.g. `redis: connection refused` or `channel system: listener stopped`
+pub async fn readiness(state: &State) -> Result<(), Vec<String>> {
+    let mut failing = vec![];
+
+    match tokio::time::timeout(PING_TIMEOUT, state.broker.ping()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => failing.push(format!("redis: {}", e)),
+        Err(_) => failing.push(format!("redis: no answer to PING in {:?}", PING_TIMEOUT)),
+    }
+
+    let ctl = state.ctl.lock().await;
+    let channels = ctl.channels.lock().await;
+    for name in state.special_channels() {
+        match channels.get(&name) {
+            None => failing.push(format!("channel {}: not found", name)),
+            // brokers without listeners, like LocalBroker, have no task
+            Some(channel) if channel.redis_listen_task.as_ref().is_some_and(|task| task.is_finished()) => {
+                failing.push(format!("channel {}: listener stopped", name))
+            }
+            Some(_) => {}
+        }
+    }
+
+    if failing.is_empty() {
+        Ok(())
+    } else {
+        warn!("HEALTH / not ready: {:?}", failing);
+        Err(failing)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::broker::Broker;
+    use crate::channel::{ChannelMessage, ControlEvent};
+    use crate::socket::ChannelSocket;
+    use crate::websocket::{add_channel, SPECIAL_CHANNELS};
+    use async_trait::async_trait;
+    use redis::RedisResult;
+    use tokio::{sync::broadcast, task::JoinHandle};
+
+    // a broker whose listeners stop at once, and which never answers
+    struct DownBroker;
+
+    #[async_trait]
+    impl Broker for DownBroker {
+        async fn publish(&self, _: &str, _: &str, _: String) -> RedisResult<()> {
+            Ok(())
+        }
+
+        fn listen(&self, _: &str, _: broadcast::Sender<ChannelMessage>, _: broadcast::Sender<ControlEvent>) -> Option<JoinHandle<RedisResult<()>>> {
+            Some(tokio::spawn(async { Ok(()) }))
+        }
+
+        async fn ping(&self) -> RedisResult<()> {
+            Err((redis::ErrorKind::IoError, "connection refused").into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let state = ChannelSocket::builder().build().state();
+        let failing = readiness(&state).await.unwrap_err();
+        assert_eq!(failing, vec!["channel phoenix: not found", "channel admin: not found", "channel system: not found"]);
+
+        for name in SPECIAL_CHANNELS {
+            add_channel(&state.ctl, state.broker.clone(), name.to_string()).await;
+        }
+        assert!(readiness(&state).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_readiness_broker_down() {
+        let state = ChannelSocket::builder().broker(DownBroker).build().state();
+        for name in SPECIAL_CHANNELS {
+            add_channel(&state.ctl, state.broker.clone(), name.to_string()).await;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await; // listeners stop
+
+        let failing = readiness(&state).await.unwrap_err();
+        assert_eq!(failing.len(), 4);
+        assert!(failing[0].starts_with("redis: "));
+        assert_eq!(failing[3], "channel system: listener stopped");
+    }
+}
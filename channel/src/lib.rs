@@ -1,3 +1,19 @@
+pub mod admin;
+pub mod assets;
+pub mod auth;
+pub mod broker;
 pub mod channel;
+pub mod config;
+pub mod connection;
+pub mod cors;
+pub mod expiry;
+pub mod handler;
+pub mod health;
+pub mod listener;
+pub mod metrics;
+pub mod proxy;
+pub mod ratelimit;
+pub mod socket;
+pub mod tls;
 pub mod utils;
 pub mod websocket;
@@ -0,0 +1,241 @@
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use std::{fmt, future::Future, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::watch,
+};
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use crate::tls::{Acceptor, PeerIdentity, HANDSHAKE_TIMEOUT};
+
+/// where to listen, `host:port` or `unix:/path/to/socket`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn parse(address: &str) -> Result<ListenAddr, String> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("empty unix socket path".into());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        match address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(ListenAddr::Tcp(address.to_string())),
+            _ => Err(format!("`{}` is neither `host:port` nor `unix:/path`", address)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(address) => write!(formatter, "{}", address),
+            ListenAddr::Unix(path) => write!(formatter, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// a stale unix socket file, left by a process killed before removing it, is replaced,
+    /// the socket of a live server is not, `AddrInUse` then
+    pub async fn bind(address: &ListenAddr) -> std::io::Result<Listener> {
+        match address {
+            ListenAddr::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                        let message = format!("{} is the socket of a running server", path.display());
+                        return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, message));
+                    }
+                    warn!("SERVE / removing the stale socket {}", path.display());
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "unix sockets are not supported")),
+        }
+    }
+}
+
+/// the longest pause after failed accepts, e.g. out of file descriptors
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// serve the app until `signal`, like `axum::serve(..).with_graceful_shutdown(signal)`
+/// tcp connections are TLS with an acceptor, and their requests carry `ConnectInfo<SocketAddr>`,
+/// and the `PeerIdentity` of mutual TLS
+pub async fn serve(listener: Listener, acceptor: Option<Arc<Acceptor>>, app: Router, signal: impl Future<Output = ()>) {
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    tokio::pin!(signal);
+    let mut backoff = Duration::ZERO;
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept(&listener) => accepted,
+            _ = &mut signal => break,
+        };
+        if accepted.is_ok() {
+            backoff = Duration::ZERO;
+        }
+        let app = app.clone();
+        let shutdown_rx = shutdown_rx.clone();
+        match accepted {
+            Ok(Accepted::Tcp(stream, addr)) => match &acceptor {
+                Some(acceptor) => {
+                    let tls = acceptor.acceptor();
+                    // the handshake is done in the task, a slow client does not hold the others
+                    tokio::spawn(async move {
+                        let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                            Ok(Ok(stream)) => stream,
+                            Ok(Err(e)) => return debug!("TLS / handshake with {} failed: {}", addr, e),
+                            Err(_) => return debug!("TLS / handshake with {} timed out", addr),
+                        };
+                        let identity = PeerIdentity::of(stream.get_ref().1);
+                        serve_connection(stream, app, Some(addr), identity, shutdown_rx).await
+                    });
+                }
+                None => {
+                    tokio::spawn(serve_connection(stream, app, Some(addr), None, shutdown_rx));
+                }
+            },
+            #[cfg(unix)]
+            Ok(Accepted::Unix(stream)) => {
+                tokio::spawn(serve_connection(stream, app, None, None, shutdown_rx));
+            }
+            Err(e) => {
+                // the error lasts until connections are closed, retrying at once spins
+                backoff = (backoff * 2).clamp(Duration::from_millis(5), MAX_ACCEPT_BACKOFF);
+                warn!("SERVE / fail to accept, retrying in {:?}: {}", backoff, e);
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = &mut signal => break,
+                }
+            }
+        }
+    }
+
+    // websockets are upgraded already, they are closed by `ChannelSocket::shutdown`
+    drop(shutdown_rx);
+    let _ = shutdown_tx.send(());
+    shutdown_tx.closed().await;
+    #[cfg(unix)]
+    if let Listener::Unix(listener) = &listener {
+        if let Some(path) = listener
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(|path| path.to_path_buf()))
+        {
+            let _ = std::fs::remove_file(&path);
+            info!("SERVE / {} removed", path.display());
+        }
+    }
+}
+
+enum Accepted {
+    Tcp(tokio::net::TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+async fn accept(listener: &Listener) -> std::io::Result<Accepted> {
+    match listener {
+        Listener::Tcp(listener) => listener.accept().await.map(|(stream, addr)| Accepted::Tcp(stream, addr)),
+        #[cfg(unix)]
+        Listener::Unix(listener) => listener.accept().await.map(|(stream, _)| Accepted::Unix(stream)),
+    }
+}
+
+async fn serve_connection<I>(io: I, app: Router, addr: Option<SocketAddr>, identity: Option<PeerIdentity>, mut shutdown_rx: watch::Receiver<()>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        if let Some(addr) = addr {
+            request.extensions_mut().insert(ConnectInfo(addr));
+        }
+        if let Some(identity) = &identity {
+            request.extensions_mut().insert(identity.clone());
+        }
+        app.clone().oneshot(request)
+    });
+    let builder = Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+    tokio::pin!(conn);
+    tokio::select! {
+        result = conn.as_mut() => {
+            if let Err(e) = result {
+                debug!("SERVE / connection {:?} error: {}", addr, e);
+            }
+        }
+        _ = shutdown_rx.changed() => {
+            conn.as_mut().graceful_shutdown();
+            let _ = conn.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ListenAddr::parse("0.0.0.0:5000"), Ok(ListenAddr::Tcp("0.0.0.0:5000".into())));
+        assert_eq!(ListenAddr::parse("[::1]:5000"), Ok(ListenAddr::Tcp("[::1]:5000".into())));
+        assert_eq!(ListenAddr::parse("unix:/run/channeld.sock"), Ok(ListenAddr::Unix("/run/channeld.sock".into())));
+        assert!(ListenAddr::parse("unix:").is_err());
+        assert!(ListenAddr::parse("localhost").is_err());
+        assert!(ListenAddr::parse(":5000").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("channel-{}.sock", uuid::Uuid::new_v4()));
+        let address = ListenAddr::Unix(path.clone());
+        drop(Listener::bind(&address).await.unwrap()); // leaves a stale socket file
+        let listener = Listener::bind(&address).await.unwrap();
+        let e = Listener::bind(&address).await.err().unwrap(); // live
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+
+        let (signal_tx, signal_rx) = tokio::sync::oneshot::channel::<()>();
+        let app = Router::new().route("/", get(|| async { "local" }));
+        let server = tokio::spawn(serve(listener, None, app, async move {
+            let _ = signal_rx.await;
+        }));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("local"), "{}", response);
+
+        signal_tx.send(()).unwrap();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+}
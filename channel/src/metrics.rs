@@ -0,0 +1,162 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::channel::ChannelControl;
+
+/// process wide counters, exported with the gauges of `ChannelControl` by `render`
+pub struct Metrics {
+    pub redis_in: AtomicU64,                        // messages received from redis
+    pub ws_out: AtomicU64,                          // messages sent to websockets
+    pub redis_out: AtomicU64,                       // client events dispatched to redis
+    pub lagged: AtomicU64,                          // messages skipped by slow receivers
+    pub dropped: AtomicU64,                         // messages nobody received, or failed to be dispatched
+    join_failures: Mutex<BTreeMap<String, u64>>,    // reason -> count
+    rate_limited: Mutex<BTreeMap<String, u64>>,     // limit -> count
+    invalid_messages: Mutex<BTreeMap<String, u64>>, // reason -> count
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            redis_in: AtomicU64::new(0),
+            ws_out: AtomicU64::new(0),
+            redis_out: AtomicU64::new(0),
+            lagged: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            join_failures: Mutex::new(BTreeMap::new()),
+            rate_limited: Mutex::new(BTreeMap::new()),
+            invalid_messages: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// a join refused or failed, `reason` is a label like `unauthorized`
+    pub fn join_failed(&self, reason: &str) {
+        let mut failures = self.join_failures.lock().unwrap();
+        *failures.entry(reason.to_string()).or_default() += 1;
+    }
+
+    pub fn join_failures(&self) -> Vec<(String, u64)> {
+        self.join_failures.lock().unwrap().iter().map(|(k, v)| (k.clone(), *v)).collect()
+    }
+
+    /// a message or a connection refused by a rate limit, `limit` is like `messages` or `connections`
+    pub fn rate_limited(&self, limit: &str) {
+        let mut limited = self.rate_limited.lock().unwrap();
+        *limited.entry(limit.to_string()).or_default() += 1;
+    }
+
+    pub fn rate_limits(&self) -> Vec<(String, u64)> {
+        self.rate_limited.lock().unwrap().iter().map(|(k, v)| (k.clone(), *v)).collect()
+    }
+
+    /// an inbound message refused, `reason` is a label like `payload_too_large`
+    pub fn invalid_message(&self, reason: &str) {
+        let mut invalid = self.invalid_messages.lock().unwrap();
+        *invalid.entry(reason.to_string()).or_default() += 1;
+    }
+
+    pub fn invalid_messages(&self) -> Vec<(String, u64)> {
+        self.invalid_messages.lock().unwrap().iter().map(|(k, v)| (k.clone(), *v)).collect()
+    }
+}
+
+/// prometheus text exposition format, version 0.0.4
+pub async fn render(ctl: &ChannelControl) -> String {
+    let mut out = String::new();
+
+    gauge(&mut out, "channel_connections", "Open websocket connections", ctl.conn_count().await as u64);
+    let channels = ctl
+        .channels
+        .lock()
+        .await
+        .iter()
+        .map(|(name, channel)| (name.clone(), channel.count.load(Ordering::SeqCst) as u64))
+        .collect::<BTreeMap<String, u64>>();
+    gauge(&mut out, "channel_channels", "Channels", channels.len() as u64);
+    header(&mut out, "channel_agents", "Agents joined per channel", "gauge");
+    for (name, count) in channels.iter() {
+        let _ = writeln!(out, "channel_agents{{channel=\"{}\"}} {}", escape(name), count);
+    }
+    gauge(&mut out, "channel_relay_tasks", "Tasks relaying channel messages to agents", ctl.relay_task_count().await as u64);
+
+    counter(&mut out, "channel_redis_in_total", "Messages received from redis", &METRICS.redis_in);
+    counter(&mut out, "channel_ws_out_total", "Messages sent to websockets", &METRICS.ws_out);
+    counter(&mut out, "channel_redis_out_total", "Client events dispatched to redis", &METRICS.redis_out);
+    counter(&mut out, "channel_lagged_total", "Messages skipped by lagging receivers", &METRICS.lagged);
+    counter(&mut out, "channel_dropped_total", "Messages without receivers or failed to be dispatched", &METRICS.dropped);
+    header(&mut out, "channel_join_failures_total", "Failed joins by reason", "counter");
+    for (reason, count) in METRICS.join_failures() {
+        let _ = writeln!(out, "channel_join_failures_total{{reason=\"{}\"}} {}", escape(&reason), count);
+    }
+    header(&mut out, "channel_rate_limited_total", "Messages and connections refused by rate limits", "counter");
+    for (limit, count) in METRICS.rate_limits() {
+        let _ = writeln!(out, "channel_rate_limited_total{{limit=\"{}\"}} {}", escape(&limit), count);
+    }
+    header(&mut out, "channel_invalid_messages_total", "Inbound messages refused by reason", "counter");
+    for (reason, count) in METRICS.invalid_messages() {
+        let _ = writeln!(out, "channel_invalid_messages_total{{reason=\"{}\"}} {}", escape(&reason), count);
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render() {
+        let ctl = ChannelControl::new();
+        ctl.channel_add("room:\"1\"".into(), None).await;
+        ctl.conn_add_tx("conn1".into()).await;
+        ctl.agent_add("conn1:room:\"1\":1".into(), None).await;
+        ctl.channel_join("room:\"1\"", "conn1:room:\"1\":1".into()).await.unwrap();
+        METRICS.join_failed("unauthorized");
+        METRICS.rate_limited("joins");
+        METRICS.invalid_message("too_deep");
+
+        let text = render(&ctl).await;
+        assert!(text.contains("# TYPE channel_connections gauge\nchannel_connections 1\n"));
+        assert!(text.contains("channel_channels 1\n"));
+        assert!(text.contains("channel_agents{channel=\"room:\\\"1\\\"\"} 1\n"));
+        assert!(text.contains("channel_relay_tasks 1\n"));
+        assert!(text.contains("# TYPE channel_ws_out_total counter\n"));
+        assert!(text.contains("channel_join_failures_total{reason=\"unauthorized\"} "));
+        assert!(text.contains("channel_rate_limited_total{limit=\"joins\"} "));
+        assert!(text.contains("channel_invalid_messages_total{reason=\"too_deep\"} "));
+    }
+}
@@ -0,0 +1,137 @@
+use axum::http::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// reverse proxies whose `x-forwarded-for` is trusted, `10.0.0.1`, `10.0.0.0/8`, `fd00::/8`,
+/// or `unix` for the connections of the unix socket listeners
+/// the header is ignored on the connections of anybody else, the client is the peer then
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Proxies {
+    nets: Vec<(IpAddr, u8)>,
+    unix: bool,
+}
+
+impl Proxies {
+    /// the invalid entries are skipped, see `check`
+    pub fn new(trusted: &[String]) -> Self {
+        Proxies {
+            nets: trusted.iter().filter_map(|net| parse(net).ok()).collect(),
+            unix: trusted.iter().any(|net| net == "unix"),
+        }
+    }
+
+    /// an entry is `unix`, an address, or a network as `address/prefix`
+    pub fn check(net: &str) -> Result<(), String> {
+        if net == "unix" {
+            return Ok(());
+        }
+        parse(net).map(|_| ())
+    }
+
+    /// the peer is a trusted proxy, `None` for the unix sockets
+    pub fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(ip) => self.nets.iter().any(|net| contains(net, ip.to_canonical())),
+            None => self.unix,
+        }
+    }
+
+    /// the address of the client, the last one of `x-forwarded-for` which is not a trusted proxy,
+    /// when the peer is a trusted proxy, the peer otherwise
+    pub fn client(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer.map(|addr| addr.ip().to_canonical());
+        if !self.trusts(peer) {
+            return peer;
+        }
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|ip| ip.trim().parse::<IpAddr>().map(|ip| ip.to_canonical()))
+            .collect::<Vec<_>>();
+        // a hop which is not an address ends the trusted chain, the proxy next to it is the client then
+        let mut client = peer;
+        for hop in forwarded.into_iter().rev() {
+            match hop {
+                Ok(ip) if self.trusts(client) => client = Some(ip),
+                _ => break,
+            }
+        }
+        client
+    }
+}
+
+fn parse(net: &str) -> Result<(IpAddr, u8), String> {
+    let (addr, prefix) = net.split_once('/').unwrap_or((net, ""));
+    let ip = addr
+        .parse::<IpAddr>()
+        .map_err(|_| format!("`{}` is not an address, e.g. `10.0.0.0/8`", net))?;
+    let bits = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        "" => bits,
+        prefix => prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|prefix| *prefix <= bits)
+            .ok_or(format!("`{}` has an invalid prefix", net))?,
+    };
+    Ok((ip.to_canonical(), prefix))
+}
+
+fn contains((net, prefix): &(IpAddr, u8), ip: IpAddr) -> bool {
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+            u32::from(*net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+            u128::from(*net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(forwarded_for: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(Proxies::check("10.0.0.1").is_ok());
+        assert!(Proxies::check("10.0.0.0/8").is_ok());
+        assert!(Proxies::check("fd00::/8").is_ok());
+        assert!(Proxies::check("unix").is_ok());
+        assert!(Proxies::check("10.0.0.0/33").is_err());
+        assert!(Proxies::check("proxy.local").is_err());
+    }
+
+    #[test]
+    fn test_client() {
+        let peer = |addr: &str| Some(addr.parse::<SocketAddr>().unwrap());
+        let proxies = Proxies::new(&["10.0.0.0/8".into(), "::1".into()]);
+
+        // anybody else sets whatever they like
+        assert_eq!(proxies.client(peer("1.2.3.4:5000"), &headers("9.9.9.9")), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(Proxies::default().client(peer("10.0.0.1:5000"), &headers("9.9.9.9")), Some("10.0.0.1".parse().unwrap()));
+
+        // the last hop which is not a trusted proxy
+        assert_eq!(proxies.client(peer("10.0.0.1:5000"), &headers("9.9.9.9, 1.2.3.4")), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(proxies.client(peer("10.0.0.1:5000"), &headers("9.9.9.9, 1.2.3.4, 10.1.1.1")), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(proxies.client(peer("[::1]:5000"), &headers("1.2.3.4")), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(proxies.client(peer("10.0.0.1:5000"), &headers("garbage, 1.2.3.4")), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(proxies.client(peer("10.0.0.1:5000"), &headers("1.2.3.4, garbage")), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(proxies.client(peer("10.0.0.1:5000"), &HeaderMap::new()), Some("10.0.0.1".parse().unwrap()));
+
+        // the unix sockets
+        assert_eq!(proxies.client(None, &headers("1.2.3.4")), None);
+        let proxies = Proxies::new(&["unix".into()]);
+        assert_eq!(proxies.client(None, &headers("1.2.3.4")), Some("1.2.3.4".parse().unwrap()));
+    }
+}
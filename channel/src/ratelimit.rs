@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::Instant,
+};
+
+use crate::config::{LimitsConfig, Rate};
+use crate::utils::topic_matches;
+
+/// buckets kept per map before the full ones, which limit nothing, are dropped
+const PRUNE_AT: usize = 10_000;
+
+/// token bucket, `burst` tokens at first, refilled at `per_second`
+#[derive(Debug)]
+struct Bucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Bucket {
+            rate,
+            tokens: rate.burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst as f64);
+        self.last = now;
+    }
+
+    fn ready(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        if !self.ready(now) {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate.burst as f64
+    }
+}
+
+fn prune<K>(buckets: &mut HashMap<K, Bucket>, now: Instant) {
+    if buckets.len() >= PRUNE_AT {
+        buckets.retain(|_, bucket| !bucket.full(now));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Limit {
+    Messages, // inbound messages per connection
+    Joins,    // joins per connection
+    Pushes,   // client events per topic, from every connection
+}
+
+impl Limit {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Limit::Messages => "messages",
+            Limit::Joins => "joins",
+            Limit::Pushes => "pushes",
+        }
+    }
+}
+
+/// a limit was exceeded, `disconnect` when the connection exceeded its limits too often
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub limit: &'static str,
+    pub disconnect: bool,
+}
+
+#[derive(Default)]
+struct ConnBuckets {
+    buckets: HashMap<(Limit, String), Bucket>, // (limit, pattern of the rule, empty for the defaults) -> bucket
+    violations: Option<Bucket>,
+}
+
+/// token bucket limits of the connections, see `LimitsConfig`
+pub struct RateLimits {
+    config: RwLock<LimitsConfig>,
+    conns: Mutex<HashMap<String, ConnBuckets>>, // conn_id -> buckets
+    topics: Mutex<HashMap<String, Bucket>>,     // topic -> pushes
+    ips: Mutex<HashMap<String, Bucket>>,        // ip -> connections
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits::new(LimitsConfig::default())
+    }
+}
+
+impl RateLimits {
+    pub fn new(config: LimitsConfig) -> Self {
+        RateLimits {
+            config: RwLock::new(config),
+            conns: Mutex::new(HashMap::new()),
+            topics: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// replace the limits, every bucket starts full again
+    pub fn set_config(&self, config: LimitsConfig) {
+        *self.config.write().unwrap() = config;
+        self.conns.lock().unwrap().clear();
+        self.topics.lock().unwrap().clear();
+        self.ips.lock().unwrap().clear();
+    }
+
+    /// take a token of every limit for a message of the connection to the topic,
+    /// none is taken when one of them is exceeded
+    pub fn check(&self, conn_id: &str, topic: &str, limits: &[Limit]) -> Result<(), Violation> {
+        self.check_at(conn_id, topic, limits, Instant::now())
+    }
+
+    fn check_at(&self, conn_id: &str, topic: &str, limits: &[Limit], now: Instant) -> Result<(), Violation> {
+        let config = self.config.read().unwrap();
+        let rules = limits
+            .iter()
+            .filter_map(|limit| rule(&config, topic, *limit).map(|(rule, rate)| (*limit, rule, rate)))
+            .collect::<Vec<_>>();
+        if rules.is_empty() {
+            return Ok(());
+        }
+
+        // conns before topics, the only place holding both
+        let mut conns = self.conns.lock().unwrap();
+        let mut topics = self.topics.lock().unwrap();
+        prune(&mut topics, now);
+        let conn = conns.entry(conn_id.to_string()).or_default();
+        let mut bucket = |limit: Limit, rule: &str, rate: Rate| match limit {
+            Limit::Pushes => topics.entry(topic.to_string()).or_insert_with(|| Bucket::new(rate, now)).ready(now),
+            Limit::Messages | Limit::Joins => conn
+                .buckets
+                .entry((limit, rule.to_string()))
+                .or_insert_with(|| Bucket::new(rate, now))
+                .ready(now),
+        };
+        let exceeded = rules
+            .iter()
+            .find(|(limit, rule, rate)| !bucket(*limit, rule, *rate))
+            .map(|(limit, _, _)| *limit);
+        let Some(limit) = exceeded else {
+            for (limit, rule, _) in &rules {
+                let bucket = match limit {
+                    Limit::Pushes => topics.get_mut(topic),
+                    Limit::Messages | Limit::Joins => conn.buckets.get_mut(&(*limit, rule.clone())),
+                };
+                if let Some(bucket) = bucket {
+                    bucket.take(now);
+                }
+            }
+            return Ok(());
+        };
+        drop(topics);
+
+        // `max_violations` per minute, then the connection is closed
+        let disconnect = config.max_violations > 0 && {
+            let rate = Rate {
+                per_second: config.max_violations as f64 / 60.0,
+                burst: config.max_violations,
+            };
+            let violations = conn.violations.get_or_insert_with(|| Bucket::new(rate, now));
+            !violations.take(now)
+        };
+        Err(Violation {
+            limit: limit.name(),
+            disconnect,
+        })
+    }
+
+    /// take a token for a new connection from the ip
+    pub fn check_connection(&self, ip: &str) -> bool {
+        self.check_connection_at(ip, Instant::now())
+    }
+
+    fn check_connection_at(&self, ip: &str, now: Instant) -> bool {
+        let Some(rate) = self.config.read().unwrap().connections else {
+            return true;
+        };
+        let mut ips = self.ips.lock().unwrap();
+        prune(&mut ips, now);
+        ips.entry(ip.to_string()).or_insert_with(|| Bucket::new(rate, now)).take(now)
+    }
+
+    /// connections allowed per user, none if 0
+    pub fn max_sessions(&self) -> u32 {
+        self.config.read().unwrap().max_sessions
+    }
+
+    /// forget the buckets of a closed connection
+    pub fn conn_rm(&self, conn_id: &str) {
+        self.conns.lock().unwrap().remove(conn_id);
+    }
+}
+
+// the first channel rule matching the topic, falling back to the defaults for the limits it does not set
+fn rule(config: &LimitsConfig, topic: &str, limit: Limit) -> Option<(String, Rate)> {
+    let pick = |messages: Option<Rate>, joins: Option<Rate>, pushes: Option<Rate>| match limit {
+        Limit::Messages => messages,
+        Limit::Joins => joins,
+        Limit::Pushes => pushes,
+    };
+    if let Some(channel) = config.channels.iter().find(|channel| topic_matches(&channel.pattern, topic)) {
+        if let Some(rate) = pick(channel.messages, channel.joins, channel.pushes) {
+            return Some((channel.pattern.clone(), rate));
+        }
+    }
+    pick(config.messages, config.joins, config.pushes).map(|rate| (String::new(), rate))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ChannelLimits;
+    use std::time::Duration;
+
+    fn rate(per_second: f64, burst: u32) -> Option<Rate> {
+        Some(Rate { per_second, burst })
+    }
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(rate(2.0, 3).unwrap(), now);
+        assert!(bucket.take(now) && bucket.take(now) && bucket.take(now));
+        assert!(!bucket.take(now));
+        assert!(bucket.take(now + Duration::from_millis(500)));
+        assert!(!bucket.take(now + Duration::from_millis(500)));
+        assert!(bucket.full(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_channel_rules() {
+        let limits = RateLimits::new(LimitsConfig {
+            messages: rate(1.0, 1),
+            channels: vec![ChannelLimits {
+                pattern: "room:*".into(),
+                messages: rate(1.0, 2),
+                joins: None,
+                pushes: rate(1.0, 2),
+            }],
+            max_violations: 0,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(limits.check_at("conn1", "lobby", &[Limit::Messages], now).is_ok());
+        assert!(limits.check_at("conn1", "lobby", &[Limit::Messages], now).is_err());
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Messages], now).is_ok()); // its own bucket
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Messages], now).is_ok());
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Messages], now).is_err());
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Joins], now).is_ok()); // no limit
+
+        // pushes are per topic, whatever the connection
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Pushes], now).is_ok());
+        assert!(limits.check_at("conn2", "room:1", &[Limit::Pushes], now).is_ok());
+        assert_eq!(
+            limits.check_at("conn3", "room:1", &[Limit::Pushes], now),
+            Err(Violation {
+                limit: "pushes",
+                disconnect: false
+            })
+        );
+        assert!(limits.check_at("conn3", "room:2", &[Limit::Pushes], now).is_ok());
+    }
+
+    #[test]
+    fn test_all_or_nothing() {
+        let limits = RateLimits::new(LimitsConfig {
+            messages: rate(1.0, 2),
+            pushes: rate(1.0, 1),
+            max_violations: 0,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Messages, Limit::Pushes], now).is_ok());
+        assert_eq!(
+            limits
+                .check_at("conn1", "room:1", &[Limit::Messages, Limit::Pushes], now)
+                .unwrap_err()
+                .limit,
+            "pushes"
+        );
+
+        // the refused push took no message token
+        assert!(limits.check_at("conn1", "room:2", &[Limit::Messages], now).is_ok());
+        assert!(limits.check_at("conn1", "room:2", &[Limit::Messages], now).is_err());
+    }
+
+    #[test]
+    fn test_disconnect_after_violations() {
+        let limits = RateLimits::new(LimitsConfig {
+            joins: rate(1.0, 1),
+            max_violations: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Joins], now).is_ok());
+        assert!(!limits.check_at("conn1", "room:1", &[Limit::Joins], now).unwrap_err().disconnect);
+        assert!(!limits.check_at("conn1", "room:1", &[Limit::Joins], now).unwrap_err().disconnect);
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Joins], now).unwrap_err().disconnect);
+
+        limits.conn_rm("conn1");
+        assert!(limits.check_at("conn1", "room:1", &[Limit::Joins], now).is_ok());
+    }
+
+    #[test]
+    fn test_connections_per_ip() {
+        let limits = RateLimits::default();
+        assert!((0..100).all(|_| limits.check_connection("10.0.0.1")));
+
+        limits.set_config(LimitsConfig {
+            connections: rate(1.0, 1),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(limits.check_connection_at("10.0.0.1", now));
+        assert!(!limits.check_connection_at("10.0.0.1", now));
+        assert!(limits.check_connection_at("10.0.0.2", now));
+        assert!(limits.check_connection_at("10.0.0.1", now + Duration::from_secs(1)));
+    }
+}
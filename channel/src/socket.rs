@@ -0,0 +1,870 @@
+use axum::{
+    extract::{ConnectInfo, Query, State as AxumState, WebSocketUpgrade},
+    http::{
+        header::{ORIGIN, USER_AGENT},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tracing::warn;
+
+use crate::admin;
+use crate::auth::{Claims, ConnectAuth, Identity, JoinAuth, JwtAuth, JwtConnectAuth, RedisJoinAuth};
+use crate::broker::{Broker, LocalBroker};
+use crate::channel::{ChannelControl, ConnInfo};
+use crate::config::{Config, ConnectMode, JoinMode, LimitsConfig, MessagesConfig};
+use crate::cors::Origins;
+use crate::handler::ChannelHandler;
+use crate::metrics::METRICS;
+use crate::proxy::Proxies;
+use crate::tls::PeerIdentity;
+use crate::utils::random_string;
+use crate::websocket::{axum_on_connected, shutdown, State};
+
+/// phoenix compatible socket endpoint, to be mounted in any axum app
+///
+/// ```ignore
+/// let socket = ChannelSocket::builder()
+///     .broker(RedisBroker::new(redis_client))
+///     .auth(JwtAuth::new(&secret))
+///     .handler("room:*", Arc::new(RoomHandler))
+///     .into_router();
+/// let app = Router::new().nest("/socket", socket); // phoenix.js: new Socket("/socket")
+/// ```
+pub struct ChannelSocket {
+    state: Arc<State>,
+}
+
+impl ChannelSocket {
+    pub fn builder() -> ChannelSocketBuilder {
+        ChannelSocketBuilder::default()
+    }
+
+    /// shared state, to add channels or broadcast from the application
+    pub fn state(&self) -> Arc<State> {
+        self.state.clone()
+    }
+
+    /// router serving the websocket at `/websocket`, as phoenix.js expects
+    pub fn router(&self) -> Router {
+        Router::new().route("/websocket", get(websocket_handler)).with_state(self.state.clone())
+    }
+
+    /// router issuing channel tokens at `POST /token`, `{"channel": ...}` -> `{"token": ...}`
+    /// the token is valid for 24 hours, for channels which exist
+    /// callers need `Authorization: Bearer {api_key}`, or a JWT signed with the secret whose scope has `token`
+    pub fn token_router(&self, api_key: Option<&str>) -> Router {
+        let router = Router::new().route("/token", post(token_handler));
+        admin::with_api_auth(router, &self.state, api_key, "token").with_state(self.state.clone())
+    }
+
+    pub fn into_router(self) -> Router {
+        self.router()
+    }
+
+    /// close every connection gracefully, see `websocket::shutdown`
+    pub async fn shutdown(&self, event: &str, payload: Value, deadline: Duration) -> usize {
+        shutdown(&self.state, event, payload, deadline).await
+    }
+}
+
+#[derive(Default)]
+pub struct ChannelSocketBuilder {
+    broker: Option<Arc<dyn Broker>>,
+    auth: Option<Arc<dyn JoinAuth>>,
+    connect_auth: Option<Arc<dyn ConnectAuth>>,
+    api_key: Option<String>,
+    handlers: Vec<(String, Arc<dyn ChannelHandler>)>,
+    jwt_secret: Option<String>,
+    origins: Origins,
+    proxies: Proxies,
+    limits: LimitsConfig,
+    message_limits: MessagesConfig,
+}
+
+impl ChannelSocketBuilder {
+    /// backend of the channels, `LocalBroker` if not set
+    pub fn broker(mut self, broker: impl Broker + 'static) -> Self {
+        self.broker = Some(Arc::new(broker));
+        self
+    }
+
+    /// join authorization, every join is accepted if not set
+    pub fn auth(mut self, auth: impl JoinAuth + 'static) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// authentication of the connections by their params, e.g. `JwtConnectAuth`, anonymous if not set
+    /// the identity is given to the join authorization and the broker
+    pub fn connect_auth(mut self, auth: impl ConnectAuth + 'static) -> Self {
+        self.connect_auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// key of the http api, it joins the admin channel too, see `State::may_join_admin`
+    pub fn api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_string());
+        self
+    }
+
+    /// in-process handler of the topics matching the pattern, see `ChannelControl::handler_add`
+    pub fn handler(mut self, pattern: &str, handler: Arc<dyn ChannelHandler>) -> Self {
+        self.handlers.push((pattern.to_string(), handler));
+        self
+    }
+
+    /// secret to sign channel tokens with, a random one if not set
+    pub fn jwt_secret(mut self, secret: &str) -> Self {
+        self.jwt_secret = Some(secret.to_string());
+        self
+    }
+
+    /// limits on inbound messages, see `MessagesConfig` for the defaults
+    pub fn message_limits(mut self, limits: MessagesConfig) -> Self {
+        self.message_limits = limits;
+        self
+    }
+
+    /// rate limits, none if not set
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// origins allowed to open websockets, any if not set, see `Origins`
+    pub fn origins(mut self, origins: Origins) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    /// the limits, the secret and the authentications of the configuration, as `channeld` runs them,
+    /// the broker and the handlers are left to the caller, the joins of `auth.join = "redis"` are asked through the broker
+    pub fn config(mut self, config: &Config) -> Self {
+        self = self
+            .limits(config.limits.clone())
+            .message_limits(config.messages.clone())
+            .proxies(Proxies::new(&config.proxies.trusted));
+        if let Some(secret) = &config.auth.jwt_secret {
+            self = self.jwt_secret(secret);
+        }
+        if let Some(key) = &config.auth.api_key {
+            self = self.api_key(key);
+        }
+        match (config.auth.join, &config.auth.join_channel) {
+            (JoinMode::Jwt, _) => self = self.auth(JwtAuth::default()),
+            (JoinMode::Redis, Some(channel)) => self = self.auth(RedisJoinAuth::new(channel, Duration::from_millis(config.auth.join_timeout))),
+            _ => {} // see `Config::validate`
+        }
+        if config.auth.connect != ConnectMode::Off {
+            self = self.connect_auth(JwtConnectAuth::new(&config.auth.connect_param, config.auth.connect == ConnectMode::Required));
+        }
+        self
+    }
+
+    /// proxies whose `x-forwarded-for` is trusted, none if not set, see `Proxies`
+    pub fn proxies(mut self, proxies: Proxies) -> Self {
+        self.proxies = proxies;
+        self
+    }
+
+    pub fn build(self) -> ChannelSocket {
+        let mut state = State::new(
+            ChannelControl::with_handlers(self.handlers),
+            self.broker.unwrap_or_else(|| Arc::new(LocalBroker::default())),
+            self.auth,
+            self.jwt_secret.unwrap_or_else(|| random_string(8)),
+        );
+        state.connect_auth = self.connect_auth;
+        state.api_key = self.api_key;
+        state.set_origins(self.origins);
+        state.set_proxies(self.proxies);
+        state.limits.set_config(self.limits);
+        state.set_message_limits(self.message_limits);
+        ChannelSocket { state: Arc::new(state) }
+    }
+
+    pub fn into_router(self) -> Router {
+        self.build().into_router()
+    }
+}
+
+// the remote address is known when served with `into_make_service_with_connect_info::<SocketAddr>` or `listener::serve`
+async fn websocket_handler(
+    ws: WebSocketUpgrade, AxumState(state): AxumState<Arc<State>>, connect_info: Option<ConnectInfo<SocketAddr>>,
+    peer: Option<Extension<PeerIdentity>>, Query(params): Query<HashMap<String, String>>, headers: HeaderMap,
+) -> Response {
+    // browsers always send the origin, other clients are not subject to cross-site hijacking
+    if let Some(origin) = headers.get(ORIGIN) {
+        let origin = origin.to_str().unwrap_or_default();
+        if !state.origins().allows(origin) {
+            warn!("CONN / websocket from origin {:?} refused", origin);
+            return (StatusCode::FORBIDDEN, "origin not allowed").into_response();
+        }
+    }
+
+    // x-forwarded-for is trusted from the configured proxies only
+    let peer_addr = connect_info.map(|ConnectInfo(addr)| addr);
+    let ip = state.proxies().client(peer_addr, &headers);
+    if let Some(ip) = ip {
+        if !state.limits.check_connection(&ip.to_string()) {
+            warn!("CONN / too many connections from {}", ip);
+            METRICS.rate_limited("connections");
+            return (StatusCode::TOO_MANY_REQUESTS, "too many connections").into_response();
+        }
+    }
+    let remote_addr = match (ip, peer_addr) {
+        (Some(ip), Some(addr)) if ip == addr.ip().to_canonical() => Some(addr.to_string()),
+        (ip, _) => ip.map(|ip| ip.to_string()),
+    };
+
+    let mut identity = None;
+    if let Some(connect_auth) = &state.connect_auth {
+        match connect_auth.authenticate(&state, &params).await {
+            Ok(Some(authenticated)) if authenticated.token_id().is_some_and(|id| state.is_revoked(id)) => {
+                warn!("AUTH / websocket from {:?} refused: token revoked", remote_addr);
+                return (StatusCode::UNAUTHORIZED, "token revoked").into_response();
+            }
+            Ok(authenticated) => identity = authenticated,
+            Err(reason) => {
+                warn!("AUTH / websocket from {:?} refused: {}", remote_addr, reason);
+                return (StatusCode::UNAUTHORIZED, reason).into_response();
+            }
+        }
+    }
+    // mutual TLS, see `listener::serve`
+    let identity = identity.or_else(|| {
+        peer.map(|Extension(peer)| Identity {
+            user_id: peer.name(),
+            claims: json!({ "subject": peer.subject }),
+        })
+    });
+    // the session is taken now, concurrent connections of the user can't all pass the check
+    let conn_id = uuid::Uuid::new_v4().to_string();
+    let max_sessions = state.limits.max_sessions() as usize;
+    let reserved = match &identity {
+        Some(identity) if max_sessions > 0 => {
+            if !state.ctl.lock().await.user_reserve(&identity.user_id, &conn_id, max_sessions).await {
+                warn!("CONN / too many sessions of user {}", identity.user_id);
+                METRICS.rate_limited("sessions");
+                return (StatusCode::TOO_MANY_REQUESTS, "too many sessions").into_response();
+            }
+            Some(identity.user_id.clone())
+        }
+        _ => None,
+    };
+    let info = ConnInfo {
+        remote_addr,
+        user_agent: headers.get(USER_AGENT).and_then(|v| v.to_str().ok()).map(|v| v.to_string()),
+        identity,
+        conn_id: Some(conn_id.clone()),
+        ..Default::default()
+    };
+    // the session closes the connections with 1009 for frames over the limit,
+    // the transport drops them without buffering frames over twice the limit
+    let transport_cap = state.message_limits().max_frame_bytes.saturating_mul(2);
+    let released = state.clone();
+    ws.max_message_size(transport_cap)
+        .max_frame_size(transport_cap)
+        .on_failed_upgrade(move |e| {
+            warn!("CONN / {} upgrade failed: {}", conn_id, e);
+            if let Some(user_id) = reserved {
+                tokio::spawn(async move { released.ctl.lock().await.user_release(&user_id, &conn_id).await });
+            }
+        })
+        .on_upgrade(move |socket| axum_on_connected(socket, state, info))
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    channel: String,
+}
+
+async fn token_handler(AxumState(state): AxumState<Arc<State>>, Json(request): Json<TokenRequest>) -> Response {
+    if !state.ctl.lock().await.channel_exists(&request.channel).await {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "channel not found" }))).into_response();
+    }
+    let claims = Claims {
+        id: uuid::Uuid::new_v4().to_string(),
+        channel: request.channel,
+        exp: (chrono::Utc::now() + chrono::Duration::hours(24)).timestamp() as usize,
+        ..Default::default()
+    };
+    let key = EncodingKey::from_secret(state.jwt_secret().as_bytes());
+    match encode(&Header::default(), &claims, &key) {
+        Ok(token) => Json(json!({ "token": token })).into_response(),
+        Err(e) => {
+            warn!("CONN / fail to issue a token for {}: {}", claims.channel, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "token generation failed" }))).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::{Grant, JwtAuth};
+    use crate::handler::{Reply, Socket};
+    use async_trait::async_trait;
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+    struct EchoHandler;
+
+    #[async_trait]
+    impl ChannelHandler for EchoHandler {
+        async fn join(&self, _topic: &str, _payload: &Value, _socket: &Socket) -> Result<Value, Value> {
+            Ok(json!({ "welcome": true }))
+        }
+
+        async fn handle_in(&self, _event: &str, payload: &Value, _socket: &Socket) -> Reply {
+            Reply::Ok(payload.clone())
+        }
+    }
+
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, Router::new().nest("/socket", router)).await });
+        format!("ws://{}/socket/websocket", addr)
+    }
+
+    async fn request(ws: &mut tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, text: &str) -> Value {
+        ws.send(Message::text(text)).await.unwrap();
+        let msg = ws.next().await.unwrap().unwrap();
+        serde_json::from_str(msg.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_socket_with_handler() {
+        let addr = serve(ChannelSocket::builder().handler("room:*", Arc::new(EchoHandler)).into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+
+        let resp = request(&mut ws, r#"[null,"1","phoenix","heartbeat",{}]"#).await;
+        assert_eq!(resp[2], "phoenix");
+        assert_eq!(resp[4]["status"], "ok");
+
+        let resp = request(&mut ws, r#"["1","2","room:1","phx_join",{}]"#).await;
+        assert_eq!(resp[3], "phx_reply");
+        assert_eq!(resp[4], json!({ "status": "ok", "response": { "welcome": true } }));
+
+        let resp = request(&mut ws, r#"["1","3","room:1","echo",{"n":1}]"#).await;
+        assert_eq!(resp[1], "3");
+        assert_eq!(resp[4], json!({ "status": "ok", "response": { "n": 1 } }));
+    }
+
+    #[tokio::test]
+    async fn test_socket_handler_without_channel() {
+        // "system" is special, it is never added, so there is no socket for the handler
+        let addr = serve(ChannelSocket::builder().handler("system", Arc::new(EchoHandler)).into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+
+        let resp = request(&mut ws, r#"["1","2","system","phx_join",{}]"#).await;
+        assert_eq!(resp[3], "phx_reply");
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "join_failed" } }));
+    }
+
+    #[tokio::test]
+    async fn test_socket_with_auth() {
+        let addr = serve(ChannelSocket::builder().auth(JwtAuth::new("secret")).into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+
+        let resp = request(&mut ws, r#"["1","1","room:1","phx_join",{"token":"invalid"}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "unauthorized" } }));
+    }
+
+    // members of the rooms as the application knows them
+    struct MemberAuth;
+
+    #[async_trait]
+    impl JoinAuth for MemberAuth {
+        async fn authorize(
+            &self, _state: &State, _conn_id: &str, _identity: Option<&Identity>, topic: &str, _payload: &Value,
+        ) -> Result<Grant, Value> {
+            match topic {
+                "room:42" => Ok(Grant {
+                    response: Some(json!({ "role": "member" })),
+                    ..Default::default()
+                }),
+                _ => Err(json!({ "reason": "not a member" })),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_auth_response() {
+        let addr = serve(ChannelSocket::builder().auth(MemberAuth).into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+
+        let resp = request(&mut ws, r#"["1","1","room:42","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "ok", "response": { "role": "member" } }));
+        let resp = request(&mut ws, r#"["2","2","room:1","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "not a member" } }));
+    }
+
+    #[tokio::test]
+    async fn test_config_join_redis() {
+        let mut config = Config::default();
+        config.auth.join = JoinMode::Redis;
+        config.auth.join_channel = Some("joins".into());
+        config.auth.join_timeout = 200;
+        let socket = ChannelSocket::builder().config(&config).build();
+
+        // the application, answering through the in-process broker
+        let broker = socket.state().broker.clone();
+        let mut requests = broker.subscribe("joins").await.unwrap();
+        tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                let request: Value = serde_json::from_str(&request).unwrap();
+                let answer = match request["topic"].as_str() {
+                    Some("room:slow") => continue, // never answered
+                    Some("room:1") => {
+                        json!({ "id": request["id"], "allow": true, "response": { "seat": request["payload"]["seat"] }, "events": ["new_msg"] })
+                    }
+                    _ => json!({ "id": request["id"], "allow": false, "response": { "reason": "full" } }),
+                };
+                broker.send(request["reply_to"].as_str().unwrap(), answer.to_string()).await.unwrap();
+            }
+        });
+
+        let addr = serve(socket.into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+        let resp = request(&mut ws, r#"["1","1","room:1","phx_join",{"seat":7}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "ok", "response": { "seat": 7 } }));
+        let resp = request(&mut ws, r#"["1","2","room:1","shout",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "unauthorized" } }));
+        let resp = request(&mut ws, r#"["2","3","room:2","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "full" } }));
+        let resp = request(&mut ws, r#"["3","4","room:slow","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "timeout" } }));
+    }
+
+    #[tokio::test]
+    async fn test_config_join_jwt() {
+        let mut config = Config::default();
+        config.auth.jwt_secret = Some("secret".into());
+        config.auth.join = JoinMode::Jwt;
+        let addr = serve(ChannelSocket::builder().config(&config).into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+        let claims = Claims {
+            id: "1".into(),
+            subscribe: vec!["room:*".into()],
+            publish: Some([("room:*".to_string(), vec!["new_msg".to_string()])].into()),
+            exp: chrono::Utc::now().timestamp() as usize + 60,
+            ..Default::default()
+        };
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+
+        let resp = request(&mut ws, &json!(["1", "1", "room:1", "phx_join", { "token": token }]).to_string()).await;
+        assert_eq!(resp[4]["status"], "ok");
+        let resp = request(&mut ws, r#"["2","2","lobby","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "unauthorized" } }));
+        let resp = request(&mut ws, r#"["1","3","room:1","delete",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "unauthorized" } }));
+    }
+
+    #[tokio::test]
+    async fn test_admin_join() {
+        let socket = ChannelSocket::builder().jwt_secret("secret").api_key("key").build();
+        crate::websocket::add_channel(&socket.state().ctl, socket.state().broker.clone(), "admin".into()).await;
+        let addr = serve(socket.into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+        let unauthorized = json!({ "status": "error", "response": { "reason": "unauthorized" } });
+
+        let resp = request(&mut ws, r#"["1","1","admin","phx_join",{}]"#).await;
+        assert_eq!(resp[4], unauthorized);
+        let claims = Claims {
+            id: "1".into(),
+            channel: "admin".into(),
+            exp: chrono::Utc::now().timestamp() as usize + 60,
+            ..Default::default()
+        };
+        let channel_token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let resp = request(&mut ws, &json!(["2", "2", "admin", "phx_join", { "token": channel_token }]).to_string()).await;
+        assert_eq!(resp[4], unauthorized);
+
+        let resp = request(&mut ws, r#"["3","3","admin","phx_join",{"token":"key"}]"#).await;
+        assert_eq!(resp[4]["status"], "ok");
+        let claims = json!({ "sub": "ops", "scope": "admin", "exp": chrono::Utc::now().timestamp() + 60 });
+        let admin_token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let resp = request(&mut ws, &json!(["4", "4", "admin", "phx_join", { "token": admin_token }]).to_string()).await;
+        assert_eq!(resp[4]["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_topic_grants() {
+        let addr = serve(ChannelSocket::builder().auth(JwtAuth::new("secret")).into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+        let claims = Claims {
+            id: "1".into(),
+            subscribe: vec!["room:*".into()],
+            publish: Some([("room:*".to_string(), vec!["new_msg".to_string()])].into()),
+            exp: chrono::Utc::now().timestamp() as usize + 60,
+            ..Default::default()
+        };
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+
+        let join = |join_ref: &str, topic: &str| json!([join_ref, join_ref, topic, "phx_join", { "token": token }]).to_string();
+        let resp = request(&mut ws, &join("1", "room:1")).await;
+        assert_eq!(resp[4]["status"], "ok");
+        let resp = request(&mut ws, &join("2", "lobby")).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "unauthorized" } }));
+
+        let unauthorized = json!({ "status": "error", "response": { "reason": "unauthorized" } });
+        let resp = request(&mut ws, r#"["1","3","room:1","delete",{}]"#).await;
+        assert_eq!(resp[4], unauthorized);
+        let resp = request(&mut ws, r#"["1","4","room:2","new_msg",{}]"#).await; // not joined
+        assert_eq!(resp[4], unauthorized);
+
+        // allowed pushes are dispatched without a reply
+        ws.send(Message::text(r#"["1","5","room:1","new_msg",{"body":"hi"}]"#)).await.unwrap();
+        let resp = request(&mut ws, r#"[null,"6","phoenix","heartbeat",{}]"#).await;
+        assert_eq!(resp[1], "6");
+    }
+
+    #[tokio::test]
+    async fn test_connect_auth() {
+        use crate::auth::JwtConnectAuth;
+
+        let socket = ChannelSocket::builder()
+            .jwt_secret("secret")
+            .auth(JwtAuth::new("secret"))
+            .connect_auth(JwtConnectAuth::new("userToken", true))
+            .build();
+        let state = socket.state();
+        let addr = serve(socket.into_router()).await;
+        match connect_async(addr.clone()).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::UNAUTHORIZED),
+            other => panic!("401 expected: {:?}", other.map(|(_, response)| response)),
+        }
+        assert!(connect_async(format!("{}?userToken=invalid", addr)).await.is_err());
+
+        let claims = json!({ "sub": "u1", "typ": "connect", "subscribe": ["room:*"], "exp": chrono::Utc::now().timestamp() + 60 });
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let (mut ws, _) = connect_async(format!("{}?userToken={}&vsn=2.0.0", addr, token)).await.unwrap();
+
+        // the connect token grants the joins without a token of their own
+        let resp = request(&mut ws, r#"["1","1","room:1","phx_join",{}]"#).await;
+        assert_eq!(resp[4]["status"], "ok");
+        let resp = request(&mut ws, r#"["2","2","lobby","phx_join",{}]"#).await;
+        assert_eq!(resp[4]["status"], "error");
+
+        let conns = state.ctl.lock().await.conn_list().await;
+        assert_eq!(conns[0].info.identity.as_ref().map(|identity| identity.user_id.as_str()), Some("u1"));
+        assert!(state.ctl.lock().await.user_online("u1").await);
+
+        // the connection authenticates again as the same user only
+        let reauth = json!([null, "3", "phoenix", "phx_reauth", { "userToken": token }]);
+        assert_eq!(request(&mut ws, &reauth.to_string()).await[4]["status"], "ok");
+        let claims = json!({ "sub": "u2", "typ": "connect", "exp": chrono::Utc::now().timestamp() + 60 });
+        let other = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let reauth = json!([null, "4", "phoenix", "phx_reauth", { "userToken": other }]);
+        assert_eq!(request(&mut ws, &reauth.to_string()).await[4]["response"]["reason"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn test_token_expiry_and_revocation() {
+        use crate::websocket::{expire_sessions, revoke_token};
+
+        let socket = ChannelSocket::builder().auth(JwtAuth::new("secret")).build();
+        let state = socket.state();
+        let addr = serve(socket.into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+        let now = chrono::Utc::now().timestamp() as usize;
+        let token = |id: &str, channel: &str, exp: usize| {
+            let claims = Claims {
+                id: id.into(),
+                channel: channel.into(),
+                exp,
+                ..Default::default()
+            };
+            encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+        };
+
+        // expired, still within the leeway of the validation
+        for (join_ref, topic) in [("1", "room:1"), ("2", "room:2")] {
+            let join = json!([join_ref, join_ref, topic, "phx_join", { "token": token(topic, topic, now - 1) }]);
+            let resp = request(&mut ws, &join.to_string()).await;
+            assert_eq!(resp[4]["status"], "ok");
+        }
+
+        // room:1 is renewed, room:2 is not
+        let reauth = json!(["1", "3", "room:1", "phx_reauth", { "token": token("t1", "room:1", now + 60) }]);
+        let resp = request(&mut ws, &reauth.to_string()).await;
+        assert_eq!(resp[4]["status"], "ok");
+        let reauth = json!(["2", "4", "room:2", "phx_reauth", { "token": "invalid" }]);
+        let resp = request(&mut ws, &reauth.to_string()).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "unauthorized" } }));
+
+        assert_eq!(expire_sessions(&state).await, 1);
+        let msg = ws.next().await.unwrap().unwrap();
+        let close: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+        assert_eq!(close, json!(["2", "2", "room:2", "phx_close", { "reason": "token expired" }]));
+
+        assert_eq!(revoke_token(&state, "t1", now as u64 + 60).await, 1);
+        let msg = ws.next().await.unwrap().unwrap();
+        let close: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+        assert_eq!(close[3], "phx_close");
+        assert_eq!(close[4], json!({ "reason": "token revoked" }));
+
+        let join = json!(["5", "5", "room:1", "phx_join", { "token": token("t1", "room:1", now + 60) }]);
+        let resp = request(&mut ws, &join.to_string()).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "unauthorized" } }));
+    }
+
+    #[tokio::test]
+    async fn test_max_sessions() {
+        use crate::auth::JwtConnectAuth;
+
+        let limits = LimitsConfig {
+            max_sessions: 2,
+            ..Default::default()
+        };
+        let socket = ChannelSocket::builder()
+            .jwt_secret("secret")
+            .limits(limits)
+            .connect_auth(JwtConnectAuth::new("userToken", false))
+            .build();
+        let state = socket.state();
+        let addr = serve(socket.into_router()).await;
+        let token = |sub: &str| {
+            let claims = json!({ "sub": sub, "typ": "connect", "exp": chrono::Utc::now().timestamp() + 60 });
+            encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+        };
+        let connect = |sub: &str| connect_async(format!("{}?userToken={}", addr, token(sub)));
+
+        let (mut ws1, _) = connect("u1").await.unwrap();
+        let (mut ws2, _) = connect("u1").await.unwrap();
+        // registered once connected
+        request(&mut ws1, r#"[null,"1","phoenix","heartbeat",{}]"#).await;
+        request(&mut ws2, r#"[null,"1","phoenix","heartbeat",{}]"#).await;
+        match connect("u1").await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS),
+            other => panic!("429 expected: {:?}", other.map(|(_, response)| response)),
+        }
+        assert!(connect("u2").await.is_ok());
+        assert!(connect_async(addr.clone()).await.is_ok()); // anonymous
+
+        // to every session of the user
+        assert_eq!(state.ctl.lock().await.user_send("u1", "user:u1", "notice", json!({ "n": 1 })).await, 2);
+        for ws in [&mut ws1, &mut ws2] {
+            let msg: Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+            assert_eq!(msg, json!([null, "0", "user:u1", "notice", { "n": 1 }]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_sessions_concurrent() {
+        use crate::auth::JwtConnectAuth;
+
+        let limits = LimitsConfig {
+            max_sessions: 2,
+            ..Default::default()
+        };
+        let socket = ChannelSocket::builder()
+            .jwt_secret("secret")
+            .limits(limits)
+            .connect_auth(JwtConnectAuth::new("userToken", false))
+            .build();
+        let state = socket.state();
+        let addr = serve(socket.into_router()).await;
+        let claims = json!({ "sub": "u1", "typ": "connect", "exp": chrono::Utc::now().timestamp() + 60 });
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let url = format!("{}?userToken={}", addr, token);
+
+        let results = futures::future::join_all((0..10).map(|_| connect_async(url.clone()))).await;
+        let connected = results.into_iter().filter_map(Result::ok).collect::<Vec<_>>();
+        assert_eq!(connected.len(), 2);
+
+        // given back once closed
+        for (mut ws, _) in connected {
+            ws.close(None).await.unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !state.ctl.lock().await.user_conns("u1").await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(connect_async(url).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_origin_allowlist() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let origins = Origins::new(&["https://*.example.com".into()]);
+        let addr = serve(ChannelSocket::builder().origins(origins).into_router()).await;
+        let connect = |origin: &'static str| {
+            let mut request = addr.clone().into_client_request().unwrap();
+            request.headers_mut().insert(ORIGIN, origin.parse().unwrap());
+            connect_async(request)
+        };
+
+        assert!(connect("https://app.example.com").await.is_ok());
+        match connect("https://evil.com").await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+            other => panic!("403 expected: {:?}", other.map(|(_, response)| response)),
+        }
+        assert!(connect_async(addr.clone()).await.is_ok()); // no origin, not a browser
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxies() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let limits = LimitsConfig {
+            connections: Some(crate::config::Rate { per_second: 0.01, burst: 1 }),
+            ..Default::default()
+        };
+        let serve = |proxies: Proxies| async {
+            let router = ChannelSocket::builder().limits(limits.clone()).proxies(proxies).into_router();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let app = Router::new().nest("/socket", router).into_make_service_with_connect_info::<SocketAddr>();
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            format!("ws://{}/socket/websocket", addr)
+        };
+        let connect = |addr: &str, forwarded_for: &'static str| {
+            let mut request = addr.into_client_request().unwrap();
+            request.headers_mut().insert("x-forwarded-for", forwarded_for.parse().unwrap());
+            connect_async(request)
+        };
+        let refused = |result: Result<_, tokio_tungstenite::tungstenite::Error>| matches!(result, Err(tokio_tungstenite::tungstenite::Error::Http(response)) if response.status() == StatusCode::TOO_MANY_REQUESTS);
+
+        // the header of an untrusted peer is ignored, the peer is limited
+        let addr = serve(Proxies::default()).await;
+        assert!(connect(&addr, "1.1.1.1").await.is_ok());
+        assert!(refused(connect(&addr, "2.2.2.2").await.map(|_| ())));
+
+        // behind a trusted proxy, the clients it forwards are
+        let addr = serve(Proxies::new(&["127.0.0.1".into()])).await;
+        assert!(connect(&addr, "1.1.1.1").await.is_ok());
+        assert!(connect(&addr, "2.2.2.2").await.is_ok());
+        assert!(refused(connect(&addr, "2.2.2.2").await.map(|_| ())));
+    }
+
+    #[tokio::test]
+    async fn test_token_router() {
+        use tower::ServiceExt;
+
+        let socket = ChannelSocket::builder().jwt_secret("secret").build();
+        let post_token = |channel: &str, bearer: &str| {
+            axum::http::Request::post("/token")
+                .header("content-type", "application/json")
+                .header("authorization", format!("Bearer {}", bearer))
+                .body(axum::body::Body::from(json!({ "channel": channel }).to_string()))
+                .unwrap()
+        };
+
+        let response = socket.token_router(Some("key")).oneshot(post_token("room:1", "key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        socket.state().ctl.lock().await.channel_add("room:1".into(), None).await;
+        let response = socket.token_router(Some("key")).oneshot(post_token("room:1", "key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let token = serde_json::from_slice::<Value>(&body).unwrap()["token"].as_str().unwrap().to_string();
+        assert_eq!(JwtAuth::default().verify(&socket.state(), &token).unwrap().channel, "room:1");
+
+        // the issued tokens do not issue tokens, nor does anybody without the key
+        for bearer in [token.as_str(), "nope"] {
+            let response = socket.token_router(Some("key")).oneshot(post_token("room:1", bearer)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = socket.token_router(None).oneshot(post_token("room:1", "key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // the backend signs a token with the `token` scope
+        let claims = json!({ "sub": "backend", "scope": "token", "exp": chrono::Utc::now().timestamp() + 60 });
+        let backend = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let response = socket.token_router(None).oneshot(post_token("room:1", &backend)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_joins() {
+        let limits = LimitsConfig {
+            joins: Some(crate::config::Rate { per_second: 0.001, burst: 1 }),
+            max_violations: 1,
+            ..Default::default()
+        };
+        let addr = serve(ChannelSocket::builder().limits(limits).into_router()).await;
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+
+        let resp = request(&mut ws, r#"["1","1","room:1","phx_join",{}]"#).await;
+        assert_eq!(resp[4]["status"], "ok");
+        let resp = request(&mut ws, r#"["2","2","room:2","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "rate limited", "limit": "joins" } }));
+
+        // over the limits again within the minute, disconnected with the reason
+        ws.send(Message::text(r#"["3","3","room:3","phx_join",{}]"#)).await.unwrap();
+        let mut close = None;
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Close(frame) = msg {
+                close = frame;
+                break;
+            }
+        }
+        let close = close.unwrap();
+        assert_eq!(u16::from(close.code), 1008);
+        assert_eq!(close.reason.as_str(), "rate limit exceeded");
+    }
+
+    #[tokio::test]
+    async fn test_message_limits() {
+        let limits = MessagesConfig {
+            max_frame_bytes: 256,
+            ..Default::default()
+        };
+        let addr = serve(ChannelSocket::builder().message_limits(limits).into_router()).await;
+        let (mut ws, _) = connect_async(addr.clone()).await.unwrap();
+
+        let resp = request(&mut ws, r#"["1","1","room 1","phx_join",{}]"#).await;
+        assert_eq!(resp[4], json!({ "status": "error", "response": { "reason": "invalid_topic" } }));
+
+        // a frame over the limit closes the connection
+        let too_big = || {
+            METRICS
+                .invalid_messages()
+                .into_iter()
+                .find(|(reason, _)| reason == "frame_too_big")
+                .map_or(0, |(_, count)| count)
+        };
+        let counted = too_big();
+        let payload = "x".repeat(256);
+        ws.send(Message::text(format!(r#"["2","2","room:1","phx_join",{{"a":"{}"}}]"#, payload)))
+            .await
+            .unwrap();
+        let mut close = None;
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Close(frame) = msg {
+                close = frame;
+                break;
+            }
+        }
+        let close = close.unwrap();
+        assert_eq!(u16::from(close.code), 1009);
+        assert_eq!(close.reason.as_str(), "message too big");
+        assert!(too_big() > counted);
+
+        // over twice the limit, dropped by the transport before it is buffered
+        let (mut ws, _) = connect_async(addr).await.unwrap();
+        ws.send(Message::text("x".repeat(1024))).await.unwrap();
+        let ended = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(msg)) = ws.next().await {
+                assert!(!msg.is_text(), "unexpected reply: {}", msg);
+            }
+        });
+        assert!(ended.await.is_ok());
+    }
+}
@@ -0,0 +1,284 @@
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use std::{
+    fmt,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
+
+use crate::config::TlsConfig;
+
+/// a client slower than this to complete the handshake is dropped
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the verified client certificate of a mutual TLS connection, added to the extensions of its requests
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerIdentity {
+    pub subject: String, // e.g. `CN=alice, O=Acme`
+    pub common_name: Option<String>,
+}
+
+impl PeerIdentity {
+    /// the common name, or the whole subject without one
+    pub fn name(&self) -> String {
+        self.common_name.clone().unwrap_or_else(|| self.subject.clone())
+    }
+
+    /// the identity of the first certificate the client presented, if any
+    pub(crate) fn of(conn: &rustls::ServerConnection) -> Option<PeerIdentity> {
+        conn.peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| PeerIdentity::from_der(cert))
+    }
+
+    fn from_der(der: &[u8]) -> Option<PeerIdentity> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let subject = cert.subject();
+        let common_name = subject.iter_common_name().next().and_then(|cn| cn.as_str().ok()).map(|cn| cn.to_string());
+        Some(PeerIdentity {
+            subject: subject.to_string(),
+            common_name,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TlsError {
+    Read { path: PathBuf, message: String },
+    Invalid(String),
+}
+
+impl std::error::Error for TlsError {}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Read { path, message } => write!(formatter, "fail to read {}: {}", path.display(), message),
+            TlsError::Invalid(message) => write!(formatter, "{}", message),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<std::fs::File>, TlsError> {
+    std::fs::File::open(path).map(BufReader::new).map_err(|e| TlsError::Read {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .map_err(|e| TlsError::Read {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+    if certs.is_empty() {
+        return Err(TlsError::Invalid(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| TlsError::Read {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?
+        .ok_or_else(|| TlsError::Invalid(format!("no private key in {}", path.display())))
+}
+
+/// rustls config of the certificate and key files, verifying client certificates against `client_ca` if set
+pub fn server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>, TlsError> {
+    let (Some(cert), Some(key)) = (&tls.cert, &tls.key) else {
+        return Err(TlsError::Invalid("`tls.cert` and `tls.key` are required".into()));
+    };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| TlsError::Invalid(e.to_string()))?;
+    let builder = match &tls.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in certs(client_ca)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| TlsError::Invalid(format!("{}: {}", client_ca.display(), e)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| TlsError::Invalid(e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs(cert)?, private_key(key)?)
+        .map_err(|e| TlsError::Invalid(e.to_string()))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()]; // websockets upgrade over http/1.1
+    Ok(Arc::new(config))
+}
+
+/// TLS acceptor whose certificates are reloaded when the files change, see `watch`
+pub struct Acceptor {
+    tls: TlsConfig,
+    current: RwLock<(tokio_rustls::TlsAcceptor, Vec<Option<SystemTime>>)>, // acceptor, modification times of the files
+}
+
+impl Acceptor {
+    pub fn new(tls: &TlsConfig) -> Result<Acceptor, TlsError> {
+        let modified = modified(tls);
+        let acceptor = tokio_rustls::TlsAcceptor::from(server_config(tls)?);
+        Ok(Acceptor {
+            tls: tls.clone(),
+            current: RwLock::new((acceptor, modified)),
+        })
+    }
+
+    pub(crate) fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        self.current.read().unwrap().0.clone()
+    }
+
+    /// load the files again, the current certificates stay if they are invalid
+    /// new connections use the new certificates, established ones are not affected
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let modified = modified(&self.tls);
+        let acceptor = tokio_rustls::TlsAcceptor::from(server_config(&self.tls)?);
+        *self.current.write().unwrap() = (acceptor, modified);
+        Ok(())
+    }
+
+    /// check the files every `interval`, reload when any of them changed
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = modified(&self.tls);
+            if self.current.read().unwrap().1 == modified {
+                continue;
+            }
+            match self.reload() {
+                Ok(()) => info!("TLS / certificates reloaded"),
+                Err(e) => {
+                    error!("TLS / fail to reload, keeping the current certificates: {}", e);
+                    self.current.write().unwrap().1 = modified; // retried on the next change
+                }
+            }
+        }
+    }
+}
+
+fn modified(tls: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [&tls.cert, &tls.key, &tls.client_ca]
+        .into_iter()
+        .map(|path| path.as_ref().and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::listener::{serve, Listener};
+    use axum::{routing::get, Extension, Router};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // a CA, a server certificate for localhost and a client one for alice, as PEM
+    struct Pki {
+        ca: String,
+        server: (String, String),
+        client: (CertificateDer<'static>, PrivateKeyDer<'static>),
+    }
+
+    fn pki() -> Pki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "test ca");
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".into()]).unwrap();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let server = params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params.distinguished_name.push(DnType::OrganizationName, "Acme");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        Pki {
+            ca: ca.pem(),
+            server: (server.pem(), server_key.serialize_pem()),
+            client: (client.der().clone(), PrivateKeyDer::try_from(client_key.serialize_der()).unwrap()),
+        }
+    }
+
+    fn write(dir: &Path, name: &str, pem: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_identity() {
+        let pki = pki();
+        let dir = std::env::temp_dir().join(format!("channel-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tls = TlsConfig {
+            cert: Some(write(&dir, "cert.pem", &pki.server.0)),
+            key: Some(write(&dir, "key.pem", &pki.server.1)),
+            client_ca: Some(write(&dir, "ca.pem", &pki.ca)),
+            ..Default::default()
+        };
+        let acceptor = Arc::new(Acceptor::new(&tls).unwrap());
+
+        let app = Router::new().route("/", get(|Extension(peer): Extension<PeerIdentity>| async move { peer.subject }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(Listener::Tcp(listener), Some(acceptor.clone()), app, std::future::pending()));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certs(tls.client_ca.as_ref().unwrap()).unwrap().remove(0)).unwrap();
+        let client = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(vec![pki.client.0], pki.client.1)
+            .unwrap();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("CN=alice, O=Acme"), "{}", response);
+
+        // an invalid certificate is not loaded, the current one stays
+        write(&dir, "cert.pem", "garbage");
+        assert!(acceptor.reload().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_peer_identity_name() {
+        let identity = PeerIdentity {
+            subject: "O=Acme".into(),
+            common_name: None,
+        };
+        assert_eq!(identity.name(), "O=Acme");
+    }
+}
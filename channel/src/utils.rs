@@ -4,3 +4,11 @@ use rand::{thread_rng, Rng};
 pub fn random_string(length: usize) -> String {
     thread_rng().sample_iter(&Alphanumeric).take(length).map(char::from).collect()
 }
+
+/// match a topic against a pattern, a trailing `*` matches any suffix, e.g. `room:*` matches `room:42`
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => topic.starts_with(prefix),
+        None => pattern == topic,
+    }
+}
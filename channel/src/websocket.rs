@@ -1,19 +1,29 @@
-use crate::channel::{listen_to_redis, Channel, ChannelError};
+use crate::auth::{ConnectAuth, Grant, Identity, JoinAuth, JwtAuth, JwtKeys};
+use crate::broker::{Broker, Publisher};
+use crate::channel::{agent_id, agent_parts, Channel, ChannelError, ConnInfo, ControlEvent};
 use crate::channel::{ChannelControl, ChannelMessage};
-use futures::SinkExt;
-use futures::StreamExt;
-use redis::AsyncCommands;
-use redis::Client;
-use redis::RedisResult;
+use crate::config::MessagesConfig;
+use crate::connection::{drive, Frame};
+use crate::cors::Origins;
+use crate::expiry::{Holder, TokenIndex};
+use crate::handler::{ChannelHandler, Reply};
+use crate::metrics::{Metrics, METRICS};
+use crate::proxy::Proxies;
+use crate::ratelimit::{Limit, RateLimits};
+use futures::{future, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_tuple::{Deserialize_tuple, Serialize_tuple};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Display, Error};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::sync::{broadcast::error::RecvError, Mutex};
 use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite;
 use tracing::{debug, error, info, warn};
-use uuid::Uuid;
 use warp::filters::ws::WebSocket;
 
 /// reply data structures
@@ -104,6 +114,80 @@ impl Display for RequestMessage {
     }
 }
 
+// the refs and names of a request, its payload is skipped
+#[derive(Debug, Deserialize_tuple)]
+struct RequestHeader {
+    join_ref: Option<String>,
+    event_ref: String,
+    topic: String,
+    event: String,
+    _payload: serde::de::IgnoredAny,
+}
+
+impl From<RequestHeader> for RequestMessage {
+    fn from(header: RequestHeader) -> Self {
+        RequestMessage {
+            join_ref: header.join_ref,
+            event_ref: header.event_ref,
+            topic: header.topic,
+            event: header.event,
+            payload: RequestPayload::JsonValue(serde_json::Value::Null),
+        }
+    }
+}
+
+// the nesting and the bytes of the payload of a request, scanned on the raw frame
+#[derive(Debug, Default, PartialEq)]
+struct FrameShape {
+    depth: usize,         // `1` is 0, `{}` and `[1]` are 1, `{"a": [1]}` is 2
+    payload_bytes: usize, // as sent, with its whitespace
+}
+
+impl FrameShape {
+    // the payload is the 5th item of the array, whatever it holds is not checked here
+    fn of(text: &str) -> Self {
+        let mut shape = FrameShape::default();
+        let (mut level, mut item, mut start) = (0, 0, None);
+        let (mut in_string, mut escaped) = (false, false);
+        for (i, byte) in text.bytes().enumerate() {
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => in_string = true,
+                b'[' | b'{' => {
+                    level += 1;
+                    if item == 4 {
+                        shape.depth = shape.depth.max(level - 1);
+                    }
+                }
+                b']' | b'}' => {
+                    level = usize::saturating_sub(level, 1);
+                    if level == 0 && item == 4 {
+                        if let Some(start) = start.take() {
+                            shape.payload_bytes = text[start..i].trim().len();
+                        }
+                    }
+                }
+                b',' if level == 1 => {
+                    item += 1;
+                    if item == 4 {
+                        start = Some(i + 1);
+                    }
+                }
+                _ => {}
+            }
+        }
+        shape
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(untagged)]
 enum RequestPayload {
@@ -112,181 +196,393 @@ enum RequestPayload {
     JsonValue(serde_json::Value), // 这样允许提交的数据只要是JSON 就可以了
 }
 
-pub struct State {
-    pub ctl: Mutex<ChannelControl>,
-    pub redis_url: String,
-    pub redis_client: redis::Client,
-    pub jwt_secret: String,
+impl RequestPayload {
+    // the payload as sent, only the joins and the messages are serialized again
+    fn value(&self) -> Cow<'_, serde_json::Value> {
+        match self {
+            RequestPayload::JsonValue(value) => Cow::Borrowed(value),
+            payload => Cow::Owned(serde_json::to_value(payload).unwrap_or_default()),
+        }
+    }
 }
 
-impl State {}
+/// a client breaking the protocol, answered as `action` says, the connection never dies from it
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    FrameTooBig(usize),    // bytes of the frame, over `MessagesConfig::max_frame_bytes`
+    BinaryFrame,           // phoenix messages are text
+    Malformed(String),     // not a phoenix message, the parse error
+    Invalid(&'static str), // over the message limits, see `check_request`
+    MissingJoinRef,        // joins and leaves need a join_ref
+    NotJoined,             // leave of a topic not joined
+    Unauthorized,          // push the grant of the join does not allow
+}
 
-pub async fn axum_on_connected(ws: axum::extract::ws::WebSocket, state: Arc<State>) {
-    let conn_id = Uuid::new_v4().to_string();
-    state.ctl.lock().await.conn_add_tx(conn_id.clone()).await;
-    info!("AXUM / WS_TX / new connection connected: {}", conn_id);
+/// how a protocol error is answered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Ignore,     // logged and counted only
+    Reply,      // phx_reply with the error, the message is dropped
+    Close(u16), // the connection is closed with the code
+}
 
-    let (mut ws_tx, mut ws_rx) = ws.split();
+impl ProtocolError {
+    pub fn action(&self) -> Action {
+        match self {
+            ProtocolError::FrameTooBig(_) => Action::Close(1009),
+            ProtocolError::BinaryFrame | ProtocolError::Malformed(_) => Action::Ignore,
+            ProtocolError::Invalid(_) | ProtocolError::MissingJoinRef | ProtocolError::NotJoined | ProtocolError::Unauthorized => Action::Reply,
+        }
+    }
 
-    // conn rx => ws tx
-    let ws_tx_state = state.clone();
-    let ws_tx_conn_id = conn_id.clone();
-    let mut ws_tx_task = tokio::spawn(async move {
-        info!("AXUM / WS_TX / launch websocket tx task (conn rx => ws tx) ...");
+    /// label of `channel_invalid_messages_total`
+    pub fn label(&self) -> &'static str {
+        match self {
+            ProtocolError::FrameTooBig(_) => "frame_too_big",
+            ProtocolError::BinaryFrame => "binary",
+            ProtocolError::Malformed(_) => "malformed",
+            ProtocolError::Invalid(reason) => reason,
+            ProtocolError::MissingJoinRef => "missing_join_ref",
+            ProtocolError::NotJoined => "not_joined",
+            ProtocolError::Unauthorized => "unauthorized",
+        }
+    }
+}
 
-        let mut conn_rx = ws_tx_state.ctl.lock().await.conn_rx(ws_tx_conn_id.clone()).await.unwrap();
-        loop {
-            match conn_rx.recv().await {
-                Ok(channel_message) => {
-                    let ChannelMessage::Reply(reply_message) = channel_message;
-                    let text_result = serde_json::to_string(&reply_message);
-                    if text_result.is_err() {
-                        error!("AXUM / WS_TX / fail to serialize reply message: {}", text_result.err().unwrap());
-                        break;
-                    }
-                    let text = text_result.unwrap();
-                    let sending_result = ws_tx.send(axum::extract::ws::Message::Text(text)).await;
-                    if sending_result.is_err() {
-                        error!("AXUM / WS_TX / websocket tx sending failed: {}", sending_result.err().unwrap());
-                        break; // what happend? exit if the connection is lost
-                    }
+impl std::error::Error for ProtocolError {}
+
+/// the reason sent to the client, in the error reply or the close frame
+impl Display for ProtocolError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::FrameTooBig(_) => write!(formatter, "message too big"),
+            ProtocolError::BinaryFrame => write!(formatter, "binary frame"),
+            ProtocolError::Malformed(e) => write!(formatter, "malformed message: {}", e),
+            ProtocolError::Invalid(reason) => write!(formatter, "{}", reason),
+            ProtocolError::MissingJoinRef => write!(formatter, "missing join_ref"),
+            ProtocolError::NotJoined => write!(formatter, "unmatched topic"),
+            ProtocolError::Unauthorized => write!(formatter, "unauthorized"),
+        }
+    }
+}
+
+/// where a connection is in the protocol
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Open,    // frames are handled
+    Closing, // closed by the server, frames are ignored until the close frame is sent
+    Closed,  // closed by the client
+}
+
+/// protocol state of a connection, fed with the frames of the client by `connection::drive`
+pub(crate) struct Session {
+    state: Arc<State>,
+    conn_id: String,
+    identity: Option<Arc<Identity>>, // of `ConnInfo`, kept along for the pushes, see `handle_reauth`
+    phase: Phase,
+}
+
+impl Session {
+    pub(crate) fn new(state: Arc<State>, conn_id: &str, identity: Option<Identity>) -> Self {
+        Session {
+            state,
+            conn_id: conn_id.to_string(),
+            identity: identity.map(Arc::new),
+            phase: Phase::Open,
+        }
+    }
+
+    /// handle a frame of the client, the phase after it
+    pub(crate) async fn on_frame(&mut self, frame: Frame) -> Phase {
+        if let Frame::Close(reason) = frame {
+            info!("CONN / {} closed by client: {:?}", self.conn_id, reason);
+            self.phase = Phase::Closed;
+            return self.phase;
+        }
+        if self.phase != Phase::Open {
+            return self.phase;
+        }
+
+        let size = match &frame {
+            Frame::Text(text) => text.len(),
+            Frame::Binary(data) => data.len(),
+            _ => 0,
+        };
+        if size > self.state.message_limits().max_frame_bytes {
+            return self.fail(ProtocolError::FrameTooBig(size), None).await;
+        }
+        match frame {
+            Frame::Text(text) => {
+                // measured once on the raw frame, a payload too deep is refused before it is parsed
+                let shape = FrameShape::of(&text);
+                if shape.depth > self.state.message_limits().max_depth {
+                    return match serde_json::from_str::<RequestHeader>(&text) {
+                        Ok(header) => self.fail(ProtocolError::Invalid("too_deep"), Some(&header.into())).await,
+                        Err(e) => self.fail(ProtocolError::Malformed(e.to_string()), None).await,
+                    };
                 }
-                Err(e) => {
-                    error!("AXUM / WS_TX / rx error: {:?}", e);
-                    break;
+                match serde_json::from_str::<RequestMessage>(&text) {
+                    Ok(rm) => {
+                        if let Err(e) = handle_message(self.state.clone(), &self.conn_id, &mut self.identity, &rm, &shape).await {
+                            return self.fail(e, Some(&rm)).await;
+                        }
+                    }
+                    Err(e) => return self.fail(ProtocolError::Malformed(e.to_string()), None).await,
                 }
             }
+            Frame::Binary(_) => return self.fail(ProtocolError::BinaryFrame, None).await,
+            Frame::Ping(_) | Frame::Pong(_) | Frame::Close(_) => {} // pings are answered by the websocket implementations
         }
-    });
-
-    let ws_rx_state = state.clone();
-    let ws_rx_conn_id = conn_id.clone();
-    let mut ws_rx_task = tokio::spawn(async move {
-        info!("AXUM / WS_RX / websocket rx handling (ws rx =>) ...");
-        let mut redis_conn = Client::open(ws_rx_state.redis_url.clone())
-            .unwrap()
-            .get_multiplexed_async_connection()
-            .await
-            .unwrap();
+        self.phase
+    }
 
-        // 从 websocket rx 读取所有消息，处理或者分发到各个 channel
-        loop {
-            let ws_msg_opt = ws_rx.next().await;
-            if ws_msg_opt.is_none() {
-                error!("AXUM / WS_RX / ws rx receiving failure");
-                break;
+    // answer the error of the message, if any, as its action says
+    async fn fail(&mut self, e: ProtocolError, rm: Option<&RequestMessage>) -> Phase {
+        METRICS.invalid_message(e.label());
+        match (e.action(), rm) {
+            (Action::Reply, Some(rm)) => {
+                warn!("WS_RX / conn {} message refused, {}: {}", self.conn_id, e, rm);
+                let response = serde_json::json!({ "reason": e.to_string() });
+                json_reply(&self.conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "error", response, self.state.clone()).await;
             }
-            let msg_result = ws_msg_opt.unwrap();
-            if msg_result.is_err() {
-                error!("AXUM / WS_RX / rx error: {:?}", msg_result.err());
-                break;
+            (Action::Reply, None) | (Action::Ignore, _) => warn!("WS_RX / conn {} message ignored, {}", self.conn_id, e),
+            (Action::Close(code), _) => {
+                warn!("WS_RX / conn {} closing with {}, {}", self.conn_id, code, e);
+                let _ = self.state.ctl.lock().await.conn_close(self.conn_id.clone(), code, &e.to_string()).await;
+                self.phase = Phase::Closing;
             }
-            let msg = msg_result.unwrap();
-            handle_message(ws_rx_state.clone(), &ws_rx_conn_id, msg.to_text().unwrap(), &mut redis_conn)
-                .await
-                .unwrap();
         }
-    });
+        self.phase
+    }
+}
 
-    // Wait for either task to finish: 一个结束了总是等另外一个
-    tokio::select! {
-        _ = (&mut ws_tx_task) => {
-            info!("AXUM / ws_tx_task exits.");
+pub struct State {
+    pub ctl: Mutex<ChannelControl>,
+    pub broker: Arc<dyn Broker>,
+    pub auth: Option<Arc<dyn JoinAuth>>,
+    pub connect_auth: Option<Arc<dyn ConnectAuth>>, // checked before the upgrade, see `ChannelSocketBuilder::connect_auth`
+    pub(crate) api_key: Option<String>,             // joins the admin channel, see `may_join_admin`
+    jwt_secret: RwLock<String>,                     // reloadable, see `set_jwt_secret`
+    jwt_keys: RwLock<Arc<JwtKeys>>,                 // reloadable, see `set_jwt_keys`
+    special_channels: RwLock<Vec<String>>,          // reloadable, see `set_special_channels`
+    origins: RwLock<Origins>,                       // reloadable, see `set_origins`
+    proxies: RwLock<Proxies>,                       // reloadable, see `set_proxies`
+    pub limits: RateLimits,                         // reloadable, see `RateLimits::set_config`
+    message_limits: RwLock<MessagesConfig>,         // reloadable, see `set_message_limits`
+    grants: RwLock<HashMap<String, Grant>>,         // agent_id -> grant of its join, with `auth`
+    revoked: RwLock<HashMap<String, u64>>,          // token id -> unix time it is forgotten at, see `revoke_token`
+    tokens: RwLock<TokenIndex>,                     // expiry and token id of the grants and identities, see `expire_sessions`
+}
 
-            ws_rx_task.abort();
-            info!("AXUM / ws_rx_task aborts.");
-        },
-        _ = (&mut ws_rx_task) => {
-            info!("AXUM / ws_rx_task exits.");
+impl State {
+    pub fn new(ctl: ChannelControl, broker: Arc<dyn Broker>, auth: Option<Arc<dyn JoinAuth>>, jwt_secret: String) -> Self {
+        State {
+            ctl: Mutex::new(ctl),
+            broker,
+            auth,
+            connect_auth: None,
+            api_key: None,
+            jwt_keys: RwLock::new(Arc::new(JwtKeys::secret(&jwt_secret))),
+            jwt_secret: RwLock::new(jwt_secret),
+            special_channels: RwLock::new(SPECIAL_CHANNELS.iter().map(|name| name.to_string()).collect()),
+            origins: RwLock::new(Origins::default()),
+            proxies: RwLock::new(Proxies::default()),
+            limits: RateLimits::default(),
+            message_limits: RwLock::new(MessagesConfig::default()),
+            grants: RwLock::new(HashMap::new()),
+            revoked: RwLock::new(HashMap::new()),
+            tokens: RwLock::new(TokenIndex::default()),
+        }
+    }
 
-            ws_tx_task.abort();
-            info!("AXUM / ws_tx_task aborts.");
-        },
+    /// the grant of a joined agent, none if it has not joined or joins are not authorized
+    pub fn grant(&self, agent_id: &str) -> Option<Grant> {
+        self.grants.read().unwrap().get(agent_id).cloned()
     }
 
-    state.ctl.lock().await.conn_cleanup(conn_id.clone()).await;
-    info!("AXUM / CONNECTION CLOSED");
-    // phoenix/admin/system 之外，如果是 channel 的最后一个 agent，清理 channel 相关
-}
+    fn grant_set(&self, agent_id: &str, grant: Grant) {
+        let holder = Holder::Agent(agent_id.to_string());
+        self.tokens.write().unwrap().set(holder, grant.expires, grant.token_id.clone());
+        self.grants.write().unwrap().insert(agent_id.to_string(), grant);
+    }
 
-/// handle websocket connection
-pub async fn warp_on_connected(ws: WebSocket, state: Arc<State>) {
-    let conn_id = Uuid::new_v4().to_string(); // 服务端生成的，内部使用
-    state.ctl.lock().await.conn_add_tx(conn_id.clone()).await;
-    info!("on_connected, 新连接: {}", conn_id);
+    fn grant_rm(&self, agent_id: &str) {
+        self.tokens.write().unwrap().rm(&Holder::Agent(agent_id.to_string()));
+        self.grants.write().unwrap().remove(agent_id);
+    }
 
-    let (mut ws_tx, mut ws_rx) = ws.split();
+    /// index the expiry and the token of the identity of a connection, when it connects or authenticates again
+    pub(crate) fn identity_set(&self, conn_id: &str, identity: Option<&Identity>) {
+        let expires = identity.and_then(Identity::expires);
+        let token_id = identity.and_then(Identity::token_id).map(str::to_string);
+        self.tokens.write().unwrap().set(Holder::Conn(conn_id.to_string()), expires, token_id);
+    }
 
-    // conn rx => ws tx
-    // 把消息经由 websocket 发送到客户端
-    let ws_state = state.clone();
-    let ws_conn_id = conn_id.clone();
-    let mut ws_tx_task = tokio::spawn(async move {
-        debug!("launch websocket tx task (conn rx => ws tx) ...");
+    /// the token was revoked, its joins and connections are refused
+    pub fn is_revoked(&self, token_id: &str) -> bool {
+        self.revoked.read().unwrap().contains_key(token_id)
+    }
 
-        let mut conn_rx = ws_state.ctl.lock().await.conn_rx(ws_conn_id.clone()).await.unwrap();
-        while let Ok(channel_message) = conn_rx.recv().await {
-            let ChannelMessage::Reply(reply_message) = channel_message;
-            let text = serde_json::to_string(&reply_message).unwrap();
-            let result = ws_tx.send(warp::ws::Message::text(text)).await;
-            if result.is_err() {
-                error!("websocket tx sending failed: {}", result.err().unwrap());
-                break; // what happend? exit if the connection is lost
-            }
-        }
-    });
-    // let mut ws_rx_task = tokio::spawn(websocket_rx(ws_rx, state.clone(), conn_id.clone()));
-    let state_clone = state.clone();
-    let conn_id_clone = conn_id.clone();
-    let mut ws_rx_task = tokio::spawn(async move {
-        info!("websocket rx handling (ws rx =>) ...");
-
-        let redis_client = Client::open(state_clone.redis_url.clone()).unwrap();
-        let mut redis_conn = redis_client.get_multiplexed_async_connection().await.unwrap();
-
-        // 从 websocket rx 读取所有消息，处理或者分发到各个 channel
-        while let Some(msg_result) = ws_rx.next().await {
-            if msg_result.is_err() {
-                error!("ws rx receiving failure: {}", msg_result.err().unwrap());
-                break;
+    /// forget the grants and the tokens of a closed connection
+    pub(crate) fn grants_conn_rm(&self, conn_id: &str) {
+        let mut removed = vec![Holder::Conn(conn_id.to_string())];
+        self.grants.write().unwrap().retain(|agent_id, _| {
+            let kept = agent_parts(agent_id).is_some_and(|(id, _, _)| id != conn_id);
+            if !kept {
+                removed.push(Holder::Agent(agent_id.clone()));
             }
-            let msg = msg_result.unwrap();
-            let text = msg.to_str().unwrap();
-            handle_message(state_clone.clone(), &conn_id_clone, text, &mut redis_conn).await.unwrap();
-        }
-    });
+            kept
+        });
+        let mut tokens = self.tokens.write().unwrap();
+        removed.iter().for_each(|holder| tokens.rm(holder));
+    }
 
-    tokio::select! {
-        _ = (&mut ws_rx_task) => {
-            ws_rx_task.abort();
-            info!("ws {} rx task aborted.", conn_id);
-        },
-        _ = (&mut ws_tx_task) => {
-            ws_tx_task.abort();
-            info!("ws {} tx task aborted.", conn_id);
-        }
+    /// secret of the channel and api tokens
+    pub fn jwt_secret(&self) -> String {
+        self.jwt_secret.read().unwrap().clone()
     }
 
-    // 这个是 conn 结束，不是 agent 结束
-    // state.ctl.lock().await.agent_rm(conn_id.to_string()).await;
-    info!("client connection closed");
-}
+    /// tokens signed with the old secret are refused from now on, joined agents stay
+    pub fn set_jwt_secret(&self, secret: &str) {
+        *self.jwt_secret.write().unwrap() = secret.to_string();
+        let keys = self.jwt_keys().with_secret(secret);
+        *self.jwt_keys.write().unwrap() = Arc::new(keys);
+    }
 
-async fn handle_message(state: Arc<State>, conn_id: &str, text: &str, redis_conn: &mut redis::aio::MultiplexedConnection) -> RedisResult<()> {
-    let rm_result = serde_json::from_str::<RequestMessage>(text);
-    if rm_result.is_err() {
-        error!("WS_RX / conn: {}, error: {:?}", &conn_id, rm_result.err());
-        // 清理 conn_id 的所有 agent
-        // state.ctl.lock().await.agent_rm(conn_id).await;
-        return Ok(());
+    /// keys verifying the api tokens
+    pub fn jwt_keys(&self) -> Arc<JwtKeys> {
+        self.jwt_keys.read().unwrap().clone()
+    }
+
+    /// the HS* tokens are verified with the secret of `set_jwt_secret` whatever the secret of the keys
+    pub fn set_jwt_keys(&self, keys: JwtKeys) {
+        let keys = keys.with_secret(&self.jwt_secret());
+        *self.jwt_keys.write().unwrap() = Arc::new(keys);
+    }
+
+    /// origins of the browsers allowed to connect, and to call the http api
+    pub fn origins(&self) -> Origins {
+        self.origins.read().unwrap().clone()
+    }
+
+    /// established connections stay, whatever their origin
+    pub fn set_origins(&self, origins: Origins) {
+        *self.origins.write().unwrap() = origins;
     }
-    let rm: RequestMessage = rm_result.unwrap();
+
+    /// proxies telling the address of the clients they forward
+    pub fn proxies(&self) -> Proxies {
+        self.proxies.read().unwrap().clone()
+    }
+
+    pub fn set_proxies(&self, proxies: Proxies) {
+        *self.proxies.write().unwrap() = proxies;
+    }
+
+    pub fn message_limits(&self) -> MessagesConfig {
+        self.message_limits.read().unwrap().clone()
+    }
+
+    pub fn set_message_limits(&self, limits: MessagesConfig) {
+        *self.message_limits.write().unwrap() = limits;
+    }
+
+    pub fn special_channels(&self) -> Vec<String> {
+        self.special_channels.read().unwrap().clone()
+    }
+
+    pub fn is_special_channel(&self, ch: &str) -> bool {
+        self.special_channels.read().unwrap().iter().any(|name| name == ch)
+    }
+
+    /// the admin channel feeds every connection and channel, it is joined with `{"token": ...}`,
+    /// the api key or a JWT whose scope has `admin`
+    pub fn may_join_admin(&self, payload: &serde_json::Value) -> bool {
+        let Some(token) = payload.get("token").and_then(serde_json::Value::as_str) else {
+            return false;
+        };
+        self.api_key.as_ref().is_some_and(|key| key.as_bytes().ct_eq(token.as_bytes()).into())
+            || JwtAuth::default().verify_scope(self, token, "admin")
+    }
+}
+
+/// handle axum websocket connection
+pub async fn axum_on_connected(ws: axum::extract::ws::WebSocket, state: Arc<State>, info: ConnInfo) {
+    let (ws_tx, ws_rx) = ws.split();
+    let ws_rx = tokio_stream::StreamExt::map_while(ws_rx, |msg| msg.map_err(|e| error!("AXUM / WS_RX / rx error: {}", e)).ok().map(Frame::from));
+    let ws_tx = ws_tx.with(|frame: Frame| future::ready(Ok::<_, axum::Error>(axum::extract::ws::Message::from(frame))));
+    drive(state, info, ws_rx, Box::pin(ws_tx)).await
+}
+
+/// handle warp websocket connection
+pub async fn warp_on_connected(ws: WebSocket, state: Arc<State>, info: ConnInfo) {
+    let (ws_tx, ws_rx) = ws.split();
+    let ws_rx = tokio_stream::StreamExt::map_while(ws_rx, |msg| msg.map_err(|e| error!("WARP / WS_RX / rx error: {}", e)).ok().map(Frame::from));
+    let ws_tx = ws_tx.with(|frame: Frame| future::ready(Ok::<_, warp::Error>(warp::ws::Message::from(frame))));
+    drive(state, info, ws_rx, Box::pin(ws_tx)).await
+}
+
+/// handle websocket connection accepted with tokio-tungstenite, no http framework involved
+pub async fn tungstenite_on_connected<T>(ws: tokio_tungstenite::WebSocketStream<T>, state: Arc<State>, info: ConnInfo)
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let (ws_tx, ws_rx) = ws.split();
+    let ws_rx =
+        tokio_stream::StreamExt::map_while(ws_rx, |msg| msg.map_err(|e| error!("TUNGSTENITE / WS_RX / rx error: {}", e)).ok().map(Frame::from));
+    let ws_tx = ws_tx.with(|frame: Frame| future::ready(Ok::<_, tungstenite::Error>(tungstenite::Message::from(frame))));
+    drive(state, info, ws_rx, Box::pin(ws_tx)).await
+}
+
+// a message of the client, the errors are answered by `Session::fail`
+async fn handle_message(
+    state: Arc<State>, conn_id: &str, identity: &mut Option<Arc<Identity>>, rm: &RequestMessage, shape: &FrameShape,
+) -> Result<(), ProtocolError> {
     let channel_name = &rm.topic;
     let join_ref = &rm.join_ref;
     let event_ref = &rm.event_ref;
     let event = &rm.event;
     let payload = &rm.payload;
 
+    check_request(rm, shape, &state.message_limits()).map_err(ProtocolError::Invalid)?;
+    if (event == "phx_join" || event == "phx_leave") && join_ref.is_none() {
+        return Err(ProtocolError::MissingJoinRef);
+    }
+
+    // with join authorization, clients push to the topics they joined, the events of their grant
+    // checked before the limits, the refused pushes take no tokens
+    let push = !(event == "phx_join" || event == "phx_leave" || event == REAUTH_EVENT || (channel_name == "phoenix" && event == "heartbeat"));
+    if push && state.auth.is_some() {
+        let agent_id = agent_id(conn_id, &channel_name, join_ref.as_deref());
+        if !state.grant(&agent_id).is_some_and(|grant| grant.may_publish(event)) {
+            return Err(ProtocolError::Unauthorized);
+        }
+    }
+
+    // heartbeats and leaves are never limited
+    let limits = match event.as_str() {
+        "heartbeat" if channel_name == "phoenix" => vec![],
+        "phx_leave" => vec![],
+        "phx_join" => vec![Limit::Messages, Limit::Joins],
+        _ => vec![Limit::Messages, Limit::Pushes],
+    };
+    if let Err(violation) = state.limits.check(conn_id, channel_name, &limits) {
+        warn!("WS_RX / conn {} over the {} limit on {}", conn_id, violation.limit, channel_name);
+        METRICS.rate_limited(violation.limit);
+        let reason = serde_json::json!({ "reason": "rate limited", "limit": violation.limit });
+        json_reply(conn_id, join_ref.clone(), event_ref, channel_name, "error", reason, state.clone()).await;
+        if violation.disconnect {
+            let _ = kick_conn(&state, conn_id, "rate limit exceeded").await;
+        }
+        return Ok(());
+    }
+
+    if event == REAUTH_EVENT {
+        return handle_reauth(rm, state, conn_id, identity).await;
+    }
+
+    let handler = state.ctl.lock().await.handler_for(channel_name).await;
+
     if channel_name == "phoenix" && event == "heartbeat" {
         ok_reply(conn_id, None, event_ref, "phoenix", state.clone()).await;
         debug!("WS_RX / heartbeat processed");
@@ -297,90 +593,302 @@ async fn handle_message(state: Arc<State>, conn_id: &str, text: &str, redis_conn
         // 如果没有channel，创建
 
         // TODO: 这里启动了一个新的 relay task(agent rx => conn tx), 需要在agent leave 的时候清除
-        let _relay_task = handle_join(&rm, state.clone(), conn_id).await;
+        let _relay_task = handle_join(rm, state.clone(), conn_id, identity.as_deref(), handler.clone()).await;
         debug!("WS_RX / join processed");
         // continue;
     }
 
     if event == "phx_leave" {
-        handle_leave(state.clone(), conn_id, join_ref.clone(), event_ref, channel_name.clone()).await;
+        handle_leave(state.clone(), conn_id, join_ref.clone(), event_ref, channel_name.clone()).await?;
         // TODO: cleanup _relay_task
         debug!("WS_RX / leave processed");
         // continue;
     }
 
+    // topics with a handler are processed in-process, nothing goes to redis
+    if let Some(handler) = handler {
+        if event != "phx_join" && event != "phx_leave" {
+            handle_in(rm, state.clone(), conn_id, handler).await;
+        }
+        return Ok(());
+    }
+
     // all events are dispatched to reids
-    dispatch_by_redis(redis_conn, channel_name.clone(), event.clone(), payload).await?;
+    let from = Publisher {
+        conn_id: conn_id.to_string(),
+        identity: identity.clone(),
+    };
+    dispatch_by_redis(state.broker.as_ref(), channel_name.clone(), event.clone(), &payload.value(), &from).await;
     Ok(())
 }
 
-/// events from client are published over redis
-/// iredis --url redis://localhost:6379 psubscribe 'from*'
-async fn dispatch_by_redis(
-    redis_conn: &mut redis::aio::MultiplexedConnection, channel_name: String, event_name: String, payload: &RequestPayload,
-) -> RedisResult<()> {
-    let redis_topic = format!("from:{}:{}", channel_name, event_name);
-    let message = serde_json::to_string(&payload).unwrap();
-    let result: RedisResult<String> = redis_conn.publish(redis_topic.clone(), message.clone()).await;
-    match result {
-        Err(e) => error!("fail to publish to redis: {}", e),
-        _ => {
-            // info!("published to redis {}, {}", redis_topic, message.clone());
+// a new token for the connection on `phoenix`, with the connect params, or for a joined topic, like its join
+// the expiry of the new token applies, the current one is kept if the new one is refused
+async fn handle_reauth(rm: &RequestMessage, state: Arc<State>, conn_id: &str, identity: &mut Option<Arc<Identity>>) -> Result<(), ProtocolError> {
+    let payload = rm.payload.value();
+    let revoked = |token_id: Option<&str>| token_id.is_some_and(|id| state.is_revoked(id));
+
+    if rm.topic == "phoenix" {
+        let (Some(connect_auth), Some(current)) = (&state.connect_auth, identity.as_deref()) else {
+            return Err(ProtocolError::Unauthorized);
+        };
+        let params = match payload.as_object() {
+            Some(params) => params
+                .iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect(),
+            None => HashMap::new(),
+        };
+        match connect_auth.authenticate(&state, &params).await {
+            Ok(Some(renewed)) if renewed.user_id == current.user_id && !revoked(renewed.token_id()) => {
+                info!("AUTH / conn {} authenticated again, until {:?}", conn_id, renewed.expires());
+                state.ctl.lock().await.conn_identity_set(conn_id, renewed.clone()).await;
+                state.identity_set(conn_id, Some(&renewed));
+                *identity = Some(Arc::new(renewed));
+            }
+            Ok(_) => return Err(ProtocolError::Unauthorized),
+            Err(reason) => {
+                warn!("AUTH / conn {} fails to authenticate again: {}", conn_id, reason);
+                return Err(ProtocolError::Unauthorized);
+            }
+        }
+    } else {
+        let agent_id = agent_id(conn_id, &rm.topic, rm.join_ref.as_deref());
+        let Some(auth) = &state.auth else {
+            return Err(ProtocolError::Unauthorized);
+        };
+        if state.grant(&agent_id).is_none() {
+            return Err(ProtocolError::NotJoined);
+        }
+        match auth.authorize(&state, conn_id, identity.as_deref(), &rm.topic, &payload).await {
+            Ok(grant) if !revoked(grant.token_id.as_deref()) => {
+                info!("AUTH / {} authorized again, until {:?}", agent_id, grant.expires);
+                state.grant_set(&agent_id, grant);
+            }
+            Ok(_) => return Err(ProtocolError::Unauthorized),
+            Err(reason) => {
+                json_reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "error", reason, state.clone()).await;
+                return Ok(());
+            }
         }
     }
+    ok_reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, state.clone()).await;
     Ok(())
 }
 
-pub fn is_special_channel(ch: &str) -> bool {
-    let excludes: Vec<&str> = vec!["phoenix", "admin", "system"];
-    excludes.contains(&ch)
+/// the reason a request breaks the limits, like `payload_too_large`
+fn check_request(rm: &RequestMessage, shape: &FrameShape, limits: &MessagesConfig) -> Result<(), &'static str> {
+    for (name, reason) in [(&rm.topic, "invalid_topic"), (&rm.event, "invalid_event")] {
+        let allowed = |c: char| c.is_ascii_alphanumeric() || limits.name_chars.contains(c);
+        if name.is_empty() || name.len() > limits.max_name_length || !name.chars().all(allowed) {
+            return Err(reason);
+        }
+    }
+    if shape.payload_bytes > limits.max_payload_bytes {
+        return Err("payload_too_large");
+    }
+    if shape.depth > limits.max_depth {
+        return Err("too_deep");
+    }
+    Ok(())
+}
+
+/// events from client are published over redis, or whatever the broker is
+async fn dispatch_by_redis(broker: &dyn Broker, channel_name: String, event_name: String, payload: &serde_json::Value, from: &Publisher) {
+    match broker.publish_from(&channel_name, &event_name, payload, from).await {
+        Ok(()) => Metrics::inc(&METRICS.redis_out),
+        Err(e) => {
+            Metrics::inc(&METRICS.dropped);
+            error!("fail to publish to redis: {}", e);
+        }
+    }
+}
+
+/// event renewing a token before it expires, `{"token": ...}` on a joined topic,
+/// or the connect params, e.g. `{"userToken": ...}`, on `phoenix`
+pub const REAUTH_EVENT: &str = "phx_reauth";
+
+/// how long a revoked token is refused when the revocation does not tell, the validity of the `/token` tokens
+pub const REVOCATION_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// the default special channels, created at startup and never removed when empty
+pub const SPECIAL_CHANNELS: [&str; 3] = ["phoenix", ADMIN_CHANNEL, "system"];
+
+/// channel of the control events and the stats, see `admin_feed` and `State::may_join_admin`
+pub const ADMIN_CHANNEL: &str = "admin";
+
+/// replace the special channels, the new ones are added, the dropped ones are removed once empty
+pub async fn set_special_channels(state: &State, names: Vec<String>) {
+    let old = std::mem::replace(&mut *state.special_channels.write().unwrap(), names.clone());
+    for name in names.iter().filter(|name| !old.contains(name)) {
+        add_channel(&state.ctl, state.broker.clone(), name.clone()).await;
+        info!("ADD_CH / special channel {} added", name);
+    }
+    for name in old.iter().filter(|name| !names.contains(name)) {
+        channel_rm_if_empty(state, name).await;
+        info!("ADD_CH / {} is no longer special", name);
+    }
+}
+
+/// empty channels are removed, except the special and the persistent ones
+async fn channel_rm_if_empty(state: &State, channel_name: &str) {
+    if state.is_special_channel(channel_name) {
+        return;
+    }
+    let ctl = state.ctl.lock().await;
+    let removable = ctl.channels.lock().await.get(channel_name).is_some_and(|ch| ch.empty() && !ch.persistent);
+    if removable {
+        warn!("LEAVE / channel {} is empty, cleaning up ...", channel_name);
+        let closing = ctl.channel_rm(channel_name.to_string()).await;
+        drop(ctl);
+        closing.terminate().await;
+    }
 }
 
-pub async fn add_channel(ctl: &Mutex<ChannelControl>, redis_client: redis::Client, channel_name: String) {
+/// add a channel kept when empty, until removed with `ChannelControl::channel_rm`
+pub async fn add_persistent_channel(ctl: &Mutex<ChannelControl>, broker: Arc<dyn Broker>, channel_name: String) {
+    add_channel(ctl, broker, channel_name.clone()).await;
+    let _ = ctl.lock().await.channel_persist(&channel_name).await; // just added
+}
+
+/// add the channel, with a task relaying messages of the channel from the broker
+pub async fn add_channel(ctl: &Mutex<ChannelControl>, broker: Arc<dyn Broker>, channel_name: String) {
     let ctl = ctl.lock().await;
 
-    let mut channels = ctl.channels.lock().await;
-    let channel_exists = channels.contains_key(&channel_name);
-    if channel_exists {
+    if ctl.channel_exists(&channel_name).await {
         warn!("ADD_CH / channel {} already exists", channel_name);
     }
-
-    channels
-        .entry(channel_name.clone())
-        .or_insert_with(|| Channel::new(channel_name.clone(), None));
+    ctl.channel_add(channel_name.clone(), None).await;
     warn!("ADD_CH / {} added", channel_name);
 
+    let mut channels = ctl.channels.lock().await;
     let channel: &mut Channel = channels.get_mut(&channel_name).unwrap();
-    channel.redis_listen_task = Some(tokio::spawn(listen_to_redis(channel.tx.clone(), redis_client, channel_name.clone())));
-    warn!("ADD_CH / {} redis_listen_task launched", channel_name);
+    if channel.redis_listen_task.as_ref().is_some_and(|task| !task.is_finished()) {
+        debug!("ADD_CH / {} redis_listen_task is running", channel_name);
+    } else {
+        channel.redis_listen_task = broker.listen(&channel_name, channel.tx.clone(), ctl.events_tx());
+        warn!("ADD_CH / {} redis_listen_task launched", channel_name);
+    }
 
     let channel_names = channels.keys().cloned().collect::<Vec<String>>();
     info!("ADD_CH / {} created, channels: {} {:?}", channel_name, channel_names.len(), channel_names);
 }
 
+/// send a message to the handler of every agent in the channel, see `ChannelHandler::handle_info`
+/// the handlers are called once the ctl lock is released, it returns the number of handlers who received the message
+pub async fn channel_info(ctl: &Mutex<ChannelControl>, channel_name: &str, message: serde_json::Value) -> Result<usize, ChannelError> {
+    let sockets = ctl.lock().await.channel_sockets(channel_name).await?;
+    for hs in sockets.iter() {
+        hs.info(&message).await;
+    }
+    Ok(sockets.len())
+}
+
 // 添加 agent tx, join channel, spawn agent/conn relay task, ack joining
-async fn handle_join(rm: &RequestMessage, state: Arc<State>, conn_id: &str) -> Result<JoinHandle<()>, ChannelError> {
+// the handler of the topic, if any, has to accept the join first
+async fn handle_join(
+    rm: &RequestMessage, state: Arc<State>, conn_id: &str, identity: Option<&Identity>, handler: Option<Arc<dyn ChannelHandler>>,
+) -> Result<JoinHandle<()>, ChannelError> {
     let channel_name = rm.topic.clone();
-    if is_special_channel(&channel_name) {
+    let join_ref = rm.join_ref.clone();
+    let event_ref = rm.event_ref.clone();
+
+    let payload = rm.payload.value();
+    if channel_name == ADMIN_CHANNEL && !state.may_join_admin(&payload) {
+        warn!("JOIN / conn {} is not authorized to join {}", conn_id, channel_name);
+        METRICS.join_failed("unauthorized");
+        let reason = serde_json::json!({ "reason": "unauthorized" });
+        json_reply(conn_id, join_ref, &event_ref, &channel_name, "error", reason, state.clone()).await;
+        return Err(ChannelError::JoinRefused);
+    }
+
+    let mut grant = None;
+    if let Some(auth) = &state.auth {
+        match auth.authorize(&state, conn_id, identity, &channel_name, &payload).await {
+            Ok(authorized) if authorized.token_id.as_deref().is_some_and(|id| state.is_revoked(id)) => {
+                warn!("JOIN / conn {} joins {} with a revoked token", conn_id, channel_name);
+                METRICS.join_failed("unauthorized");
+                let reason = serde_json::json!({ "reason": "unauthorized" });
+                json_reply(conn_id, join_ref, &event_ref, &channel_name, "error", reason, state.clone()).await;
+                return Err(ChannelError::JoinRefused);
+            }
+            Ok(authorized) => grant = Some(authorized),
+            Err(reason) => {
+                warn!("JOIN / conn {} is not authorized to join {}: {}", conn_id, channel_name, reason);
+                METRICS.join_failed("unauthorized");
+                json_reply(conn_id, join_ref, &event_ref, &channel_name, "error", reason, state.clone()).await;
+                return Err(ChannelError::JoinRefused);
+            }
+        }
+    }
+
+    if state.is_special_channel(&channel_name) {
         info!("ADD_CH / channel {} is special, ignored", channel_name);
     } else {
-        add_channel(&state.ctl, state.redis_client.clone(), channel_name.clone()).await;
+        add_channel(&state.ctl, state.broker.clone(), channel_name.clone()).await;
     }
 
-    let agent_id = format!("{}:{}:{}", conn_id, channel_name.clone(), rm.join_ref.clone().unwrap());
-    let join_ref = rm.join_ref.clone();
-    let event_ref = rm.event_ref.clone();
+    let agent_id = agent_id(conn_id, &channel_name, rm.join_ref.as_deref());
+
+    let mut join_response = grant.as_ref().and_then(|grant| grant.response.clone());
+    if let Some(handler) = handler {
+        let socket = state
+            .ctl
+            .lock()
+            .await
+            .socket_new(conn_id, &agent_id, &channel_name, join_ref.clone())
+            .await;
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("JOIN / {} fail to create the socket: {}", agent_id, e);
+                METRICS.join_failed("channel_error");
+                channel_rm_if_empty(&state, &channel_name).await;
+                let reason = serde_json::json!({ "reason": "join_failed" });
+                json_reply(conn_id, join_ref, &event_ref, &channel_name, "error", reason, state.clone()).await;
+                return Err(e);
+            }
+        };
+        match handler.join(&channel_name, &payload, &socket).await {
+            Ok(response) => {
+                state.ctl.lock().await.socket_add(socket, handler).await;
+                join_response = Some(response);
+            }
+            Err(reason) => {
+                warn!("JOIN / {} refused by handler: {}", agent_id, reason);
+                METRICS.join_failed("handler_refused");
+                channel_rm_if_empty(&state, &channel_name).await;
+                json_reply(conn_id, join_ref, &event_ref, &channel_name, "error", reason, state.clone()).await;
+                return Err(ChannelError::JoinRefused);
+            }
+        }
+    }
 
     info!("JOIN / agent joining ({} => {}) ...", agent_id, channel_name);
     state.ctl.lock().await.agent_add(agent_id.to_string(), None).await;
-    match state.ctl.lock().await.channel_join(&channel_name.clone(), agent_id.to_string()).await {
+    // the lock is released before the failure is handled, it locks again
+    let joined = state.ctl.lock().await.channel_join(&channel_name.clone(), agent_id.to_string()).await;
+    match joined {
         Ok(_) => {}
         Err(e) => {
             // relay task 在连接断开的时候会发生什么?
             error!("JOIN / fail to join: {}", e);
+            METRICS.join_failed("channel_error");
+            // the handler accepted already, it is terminated
+            let ctl = state.ctl.lock().await;
+            let closing = ctl.socket_rm(&agent_id, "error").await;
+            ctl.agent_rm(agent_id.clone()).await;
+            drop(ctl);
+            closing.terminate().await;
+            channel_rm_if_empty(&state, &channel_name).await;
+            let reason = serde_json::json!({ "reason": "join_failed" });
+            json_reply(conn_id, join_ref, &event_ref, &channel_name, "error", reason, state.clone()).await;
             return Err(e);
         }
     }
+    if let Some(grant) = grant {
+        state.grant_set(&agent_id, grant);
+    }
 
     // agent rx 到 conn tx 转发消息
     // 这个需要在 join 完整之前准备好，才不会丢失消息
@@ -388,21 +896,33 @@ async fn handle_join(rm: &RequestMessage, state: Arc<State>, conn_id: &str) -> R
     let local_join_ref = rm.join_ref.clone();
     let local_conn_id = conn_id.to_string();
     let relay_task = tokio::spawn(async move {
-        let mut agent_rx = relay_state.ctl.lock().await.agent_rx(agent_id.clone()).await.unwrap();
-        let conn_tx = relay_state.ctl.lock().await.conn_tx(local_conn_id.to_string()).await.unwrap();
+        // both are gone when the connection closed meanwhile
+        let Ok(mut agent_rx) = relay_state.ctl.lock().await.agent_rx(agent_id.clone()).await else {
+            return warn!("agent {} removed before relaying", agent_id);
+        };
+        let Ok(conn_tx) = relay_state.ctl.lock().await.conn_tx(local_conn_id.to_string()).await else {
+            return warn!("conn {} closed before relaying", local_conn_id);
+        };
 
         debug!("agent {} => conn {}", agent_id.clone(), local_conn_id.clone());
         loop {
             let message_opt = agent_rx.recv().await;
+            if let Err(RecvError::Lagged(n)) = message_opt {
+                warn!("agent {} lagged, {} messages skipped", agent_id, n);
+                Metrics::add(&METRICS.lagged, n);
+                continue;
+            }
             if message_opt.is_err() {
                 error!("fail to get message from agent rx: {}", message_opt.err().unwrap());
                 break;
             }
             let mut channel_message = message_opt.unwrap();
-            let ChannelMessage::Reply(ref mut reply) = channel_message;
-            reply.join_ref = local_join_ref.clone();
+            if let ChannelMessage::Reply(ref mut reply) = channel_message {
+                reply.join_ref = local_join_ref.clone();
+            }
             let result = conn_tx.send(channel_message.clone()); // agent rx => conn tx => conn rx => ws tx
             if result.is_err() {
+                Metrics::inc(&METRICS.dropped);
                 error!("agent {}, conn: {}, sending failure: {:?}", agent_id, &local_conn_id, result.err().unwrap());
                 break; // fails when there's no reciever, stop forwarding
             }
@@ -411,25 +931,153 @@ async fn handle_join(rm: &RequestMessage, state: Arc<State>, conn_id: &str) -> R
     });
 
     // phx_reply, 确认 join 事件
-    ok_reply(conn_id, join_ref.clone(), &event_ref, &channel_name, state.clone()).await;
+    match join_response {
+        Some(response) => json_reply(conn_id, join_ref.clone(), &event_ref, &channel_name, "ok", response, state.clone()).await,
+        None => ok_reply(conn_id, join_ref.clone(), &event_ref, &channel_name, state.clone()).await,
+    }
     Ok(relay_task)
 }
 
-async fn handle_leave(state: Arc<State>, conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: String) {
-    let agent_id = format!("{}:{}:{}", conn_id, channel_name.clone(), join_ref.clone().unwrap());
+// client event to the channel handler, the reply of the handler goes back as phx_reply
+async fn handle_in(rm: &RequestMessage, state: Arc<State>, conn_id: &str, handler: Arc<dyn ChannelHandler>) {
+    let agent_id = agent_id(conn_id, &rm.topic, rm.join_ref.as_deref());
+    let socket = state.ctl.lock().await.socket_get(&agent_id).await;
+    let Some(socket) = socket else {
+        warn!("HANDLE_IN / agent {} has not joined, event: {}", agent_id, rm.event);
+        let reason = serde_json::json!({ "reason": "unmatched topic" });
+        json_reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "error", reason, state).await;
+        return;
+    };
+
+    let payload = rm.payload.value();
+    match handler.handle_in(&rm.event, &payload, &socket).await {
+        Reply::Ok(response) => json_reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "ok", response, state).await,
+        Reply::Error(response) => json_reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "error", response, state).await,
+        Reply::NoReply => {}
+    }
+}
+
+async fn handle_leave(
+    state: Arc<State>, conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: String,
+) -> Result<(), ProtocolError> {
+    let join_ref = join_ref.ok_or(ProtocolError::MissingJoinRef)?;
+    let agent_id = agent_id(conn_id, &channel_name, Some(&join_ref));
+    let joined = match state.ctl.lock().await.channels.lock().await.get(&channel_name) {
+        Some(channel) => channel.agents().await.contains(&agent_id),
+        None => false,
+    };
+    if !joined {
+        return Err(ProtocolError::NotJoined);
+    }
+
+    state.grant_rm(&agent_id);
+    let closing = state.ctl.lock().await.socket_rm(&agent_id, "leave").await;
+    closing.terminate().await;
     state.ctl.lock().await.agent_rm(agent_id.clone()).await;
-    let agent_count = state
-        .ctl
+    if let Err(e) = state.ctl.lock().await.channel_leave(channel_name.clone(), agent_id.clone()).await {
+        warn!("LEAVE / {} fail to leave {}: {}", agent_id, channel_name, e); // removed meanwhile
+    }
+    channel_rm_if_empty(&state, &channel_name).await;
+    ok_reply(conn_id, Some(join_ref), event_ref, &channel_name, state.clone()).await;
+    Ok(())
+}
+
+/// kick an agent out of its channel, the client gets phx_close with the reason
+pub async fn kick_agent(state: &State, agent_id: &str, reason: &str) -> Result<(), ChannelError> {
+    let (_, channel_name, _) = agent_parts(agent_id).ok_or(ChannelError::AgentNotInitiated)?;
+    let ctl = state.ctl.lock().await;
+    let joined = ctl
+        .channels
         .lock()
         .await
-        .channel_leave(channel_name.clone(), agent_id.clone())
+        .get(channel_name)
+        .ok_or(ChannelError::ChannelNotFound)?
+        .agents()
         .await
-        .unwrap();
-    if agent_count == 0 && !is_special_channel(&channel_name) {
-        warn!("LEAVE / channel {} is empty, cleaning up ...", channel_name);
-        state.ctl.lock().await.channel_rm(channel_name.clone()).await;
+        .contains(&agent_id.to_string());
+    if !joined {
+        return Err(ChannelError::AgentNotInitiated);
+    }
+
+    if let Err(e) = ctl.agent_notify(agent_id, "phx_close", serde_json::json!({ "reason": reason })).await {
+        warn!("KICK / fail to notify agent {}: {}", agent_id, e);
     }
-    ok_reply(conn_id, join_ref, event_ref, &channel_name, state.clone()).await;
+    state.grant_rm(agent_id);
+    let closing = ctl.socket_rm(agent_id, "kicked").await;
+    ctl.agent_rm(agent_id.to_string()).await;
+    let left = ctl.channel_leave(channel_name.to_string(), agent_id.to_string()).await;
+    drop(ctl);
+    closing.terminate().await;
+    left?;
+    channel_rm_if_empty(state, channel_name).await;
+    info!("KICK / agent {} kicked: {}", agent_id, reason);
+    Ok(())
+}
+
+/// close a connection with 1008 policy violation, its agents get phx_close with the reason first
+pub async fn kick_conn(state: &State, conn_id: &str, reason: &str) -> Result<(), ChannelError> {
+    let ctl = state.ctl.lock().await;
+    let agent_ids = ctl
+        .agent_list()
+        .await
+        .into_iter()
+        .filter(|id| agent_parts(id).is_some_and(|(id, _, _)| id == conn_id))
+        .collect::<Vec<String>>();
+    for agent_id in agent_ids {
+        let _ = ctl.agent_notify(&agent_id, "phx_close", serde_json::json!({ "reason": reason })).await;
+    }
+    ctl.conn_close(conn_id.to_string(), 1008, reason).await?;
+    info!("KICK / conn {} kicked: {}", conn_id, reason);
+    Ok(())
+}
+
+/// kick the agents whose grant expired with `phx_close`, and close the connections whose connect token expired
+/// the number of agents and connections kicked
+pub async fn expire_sessions(state: &State) -> usize {
+    let now = chrono::Utc::now().timestamp() as u64;
+    state.revoked.write().unwrap().retain(|_, until| *until > now);
+
+    let expired = state.tokens.write().unwrap().expired(now);
+    kick_all(state, expired, "token expired").await
+}
+
+/// check the expiry of the tokens every `interval`, see `expire_sessions`
+pub async fn watch_expiry(state: Arc<State>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        expire_sessions(&state).await;
+    }
+}
+
+/// revoke a token, the agents and connections using it are kicked now,
+/// the joins and connections with it are refused until `until`, unix time
+/// the number of agents and connections kicked
+pub async fn revoke_token(state: &State, token_id: &str, until: u64) -> usize {
+    state.revoked.write().unwrap().insert(token_id.to_string(), until);
+    let holders = state.tokens.read().unwrap().holding(token_id);
+    let kicked = kick_all(state, holders, "token revoked").await;
+    info!("AUTH / token {} revoked, {} kicked", token_id, kicked);
+    kicked
+}
+
+// the agents first, they get their `phx_close` before their connection is closed
+async fn kick_all(state: &State, mut holders: Vec<Holder>, reason: &str) -> usize {
+    holders.sort();
+    let mut kicked = 0;
+    for holder in holders {
+        match holder {
+            Holder::Agent(agent_id) => match kick_agent(state, &agent_id, reason).await {
+                Ok(()) => kicked += 1,
+                Err(_) => state.grant_rm(&agent_id), // left meanwhile
+            },
+            Holder::Conn(conn_id) => {
+                if kick_conn(state, &conn_id, reason).await.is_ok() {
+                    kicked += 1;
+                }
+            }
+        }
+    }
+    kicked
 }
 
 async fn ok_reply(conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: &str, state: Arc<State>) {
@@ -444,17 +1092,91 @@ async fn ok_reply(conn_id: &str, join_ref: Option<String>, event_ref: &str, chan
             response: Response::Empty {},
         }),
     };
-    state
+    if let Err(e) = state
         .ctl
         .lock()
         .await
         .conn_send(conn_id.to_string(), ChannelMessage::Reply(join_reply_message))
         .await
-        .unwrap();
+    {
+        error!("REPLY / fail to reply to conn {}: {}", conn_id, e);
+    }
     // let text = serde_json::to_string(&join_reply_message).unwrap();
     // debug!("sent to connection {}: {}", &conn_id, text);
 }
 
+// phx_reply with an arbitrary JSON response, as returned by channel handlers
+async fn json_reply(
+    conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: &str, status: &str, response: serde_json::Value, state: Arc<State>,
+) {
+    let reply_message = ServerMessage {
+        join_ref,
+        event_ref: event_ref.to_string(),
+        topic: channel_name.to_string(),
+        event: "phx_reply".to_string(),
+        payload: ServerPayload::ServerJsonValue(serde_json::json!({ "status": status, "response": response })),
+    };
+    if let Err(e) = state
+        .ctl
+        .lock()
+        .await
+        .conn_send(conn_id.to_string(), ChannelMessage::Reply(reply_message))
+        .await
+    {
+        error!("REPLY / fail to reply to conn {}: {}", conn_id, e);
+    }
+}
+
+/// graceful shutdown, once the server stops accepting connections
+/// joined agents are notified with `event`, then connections are closed after flushing their queued messages.
+/// connections still open after `deadline` are left behind, redis listen tasks are aborted at last.
+/// it returns the number of connections not closed in time
+pub async fn shutdown(state: &State, event: &str, payload: serde_json::Value, deadline: Duration) -> usize {
+    let closing = state.ctl.lock().await.close_all(event, payload).await;
+    info!("SHUTDOWN / {} connections closing, deadline {:?}", closing, deadline);
+
+    let deadline = tokio::time::Instant::now() + deadline;
+    let mut remaining = state.ctl.lock().await.conn_count().await;
+    while remaining > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        remaining = state.ctl.lock().await.conn_count().await;
+    }
+    if remaining > 0 {
+        warn!("SHUTDOWN / {} connections not closed in time", remaining);
+    }
+
+    state.ctl.lock().await.listen_tasks_abort().await;
+    info!("SHUTDOWN / done");
+    remaining
+}
+
+/// live feed of the admin channel: control events as they happen, the event name is the event type,
+/// and a `stats` snapshot of the node every `interval`
+/// broadcasts are left out, they are too many and broadcasting to admin is one too
+pub async fn admin_feed(state: Arc<State>, interval: Duration) {
+    let mut events = state.ctl.lock().await.subscribe();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(ControlEvent::Broadcast { .. }) => {}
+                Ok(event) => {
+                    let payload = serde_json::to_value(&event).unwrap_or_default();
+                    let name = payload["type"].as_str().unwrap_or("control").to_string();
+                    state.ctl.lock().await.admin_pub(&name, payload).await;
+                }
+                Err(RecvError::Lagged(n)) => warn!("ADMIN / feed lagged, {} events skipped", n),
+                Err(RecvError::Closed) => break,
+            },
+            _ = ticker.tick() => {
+                let ctl = state.ctl.lock().await;
+                let stats = ctl.stats().await;
+                ctl.admin_pub("stats", stats).await;
+            }
+        }
+    }
+}
+
 // 每秒发送一个时间戳
 pub async fn datetime_handler(state: Arc<State>, channel_name: String) {
     tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
@@ -500,24 +1222,42 @@ pub async fn datetime_handler(state: Arc<State>, channel_name: String) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::broker::LocalBroker;
     use futures::{SinkExt, StreamExt};
     use serde_json::json;
     use std::collections::HashSet;
     use std::sync::Arc;
-    use tokio::sync::Mutex;
     use tokio_tungstenite::connect_async;
     use tokio_tungstenite::tungstenite::Message;
     use warp::Filter;
 
+    #[test]
+    fn test_check_request() {
+        let limits = MessagesConfig {
+            max_payload_bytes: 32,
+            max_depth: 2,
+            max_name_length: 8,
+            ..Default::default()
+        };
+        let check = |text: &str| check_request(&serde_json::from_str::<RequestMessage>(text).unwrap(), &FrameShape::of(text), &limits);
+        assert_eq!(check(r#"["1","1","room:1","phx_join",{}]"#), Ok(()));
+        assert_eq!(check(r#"["1","1","room:1","ping",{"a":{"b":1}}]"#), Ok(()));
+        assert_eq!(check(r#"["1","1","room:1","ping",{"a":{"b":[1]}}]"#), Err("too_deep"));
+        assert_eq!(check(r#"["1","1","room:1","ping",{"a":"0123456789012345678901234"}]"#), Err("payload_too_large"));
+        assert_eq!(check(r#"["1","1","room:123456","phx_join",{}]"#), Err("invalid_topic"));
+        assert_eq!(check(r#"["1","1","","phx_join",{}]"#), Err("invalid_topic"));
+        assert_eq!(check(r#"["1","1","room:1","say hi",{}]"#), Err("invalid_event"));
+
+        // brackets and commas in strings are not counted
+        assert_eq!(FrameShape::of(r#"[null,"1","phoenix","heartbeat",{}]"#), FrameShape { depth: 1, payload_bytes: 2 });
+        assert_eq!(FrameShape::of(r#"["1","1","a,b","x", {"a":"]}\"[{"} ]"#), FrameShape { depth: 1, payload_bytes: 14 });
+        assert_eq!(FrameShape::of(r#"["1","1","room:1","ping","hi"]"#), FrameShape { depth: 0, payload_bytes: 4 });
+
+        assert_eq!(FrameShape::of(r#"["1","1","room:1","ping",{"a": [1, {"b": {}}], "c": 1}]"#).depth, 4);
+    }
+
     async fn setup_test_server() -> (String, Arc<State>) {
-        let redis_url = "redis://192.168.11.37:6379".to_string(); // FIXME
-        let redis_client = redis::Client::open(redis_url.clone()).unwrap();
-        let state = Arc::new(State {
-            ctl: Mutex::new(ChannelControl::new()),
-            redis_url,
-            redis_client,
-            jwt_secret: "secret".clone(),
-        });
+        let state = Arc::new(State::new(ChannelControl::new(), Arc::new(LocalBroker::default()), None, "secret".to_string()));
 
         // Setup channels
         state.ctl.lock().await.channel_add("phoenix".into(), None).await;
@@ -525,14 +1265,14 @@ mod tests {
         state.ctl.lock().await.channel_add("streaming".into(), None).await;
 
         // Spawn system task
-        tokio::spawn(datetime_handler(state.clone(), "system"));
+        tokio::spawn(datetime_handler(state.clone(), "system".into()));
 
         let websocket_shared_state = state.clone();
         let websocket_shared_state = warp::any().map(move || websocket_shared_state.clone());
         let ws = warp::path("websocket")
             .and(warp::ws())
             .and(websocket_shared_state)
-            .map(|ws: warp::ws::Ws, state| ws.on_upgrade(move |socket| warp_on_connected(socket, state)));
+            .map(|ws: warp::ws::Ws, state| ws.on_upgrade(move |socket| warp_on_connected(socket, state, ConnInfo::default())));
 
         let (addr, server) = warp::serve(ws).bind_ephemeral(([127, 0, 0, 1], 0));
         let addr = format!("ws://127.0.0.1:{}/websocket", addr.port());
@@ -679,7 +1419,6 @@ mod tests {
             let ctl = state.ctl.lock().await;
             let channels = ctl.channels.lock().await;
             let agents = channels.get("system").unwrap().agents.lock().await;
-            assert_eq!(*agents, vec!["foobar"]);
             assert_eq!(agents.len(), 0);
         }
     }
@@ -722,7 +1461,10 @@ mod tests {
         );
 
         let agents = channels.get("system").unwrap().agents.lock().await;
-        assert_eq!(*agents, vec!["a".to_string()]);
+        assert_eq!(agents.len(), 3);
+        for i in 0..3 {
+            assert!(agents.iter().any(|agent_id| agent_id.ends_with(&format!(":system:{}", i))));
+        }
     }
 
     #[tokio::test]
@@ -746,7 +1488,7 @@ mod tests {
             event_ref: "broadcast".to_string(),
             topic: "system".to_string(),
             event: "test".to_string(),
-            payload: ServerPayload {
+            payload: ServerPayload::ServerResponse(ServerResponse {
                 status: "ok".to_string(),
                 // response: json!({
                 //     "message": "test broadcast".to_string(),
@@ -754,7 +1496,7 @@ mod tests {
                 response: Response::Message {
                     message: "test broadcast".to_string(),
                 },
-            },
+            }),
         };
 
         state
@@ -813,10 +1555,16 @@ mod tests {
         // Send invalid message format
         tx.send(Message::text(r#"["invalid","format"]"#)).await.unwrap();
 
-        // Send to non-existent channel
+        // Send to non-existent channel, it is created on join
         let invalid_channel = r#"["1","ref1","nonexistent","phx_join",{"token":"test"}]"#;
         tx.send(Message::text(invalid_channel)).await.unwrap();
 
+        if let Some(Ok(msg)) = rx.next().await {
+            let resp: serde_json::Value = serde_json::from_str(&msg.to_string()).unwrap();
+            assert_eq!(resp[2], "nonexistent");
+            assert_eq!(resp[4]["status"], "ok");
+        }
+
         // Connection should still be alive
         let heartbeat = r#"[null,"1","phoenix","heartbeat",{}]"#;
         tx.send(Message::text(heartbeat)).await.unwrap();
@@ -952,4 +1700,50 @@ mod tests {
         // Invalid type for number elements
         assert!(serde_json::from_str::<RequestMessage>(r#"[123, "ref1", "room123", "phx_join", {"token": "secret"}]"#).is_err());
     }
+
+    #[tokio::test]
+    async fn test_admin_feed() {
+        let state = crate::socket::ChannelSocket::builder().build().state();
+        add_channel(&state.ctl, state.broker.clone(), "admin".into()).await;
+        let mut admin_rx = {
+            let ctl = state.ctl.lock().await;
+            ctl.agent_add("conn1:admin:1".into(), None).await;
+            ctl.channel_join("admin", "conn1:admin:1".into()).await.unwrap().subscribe()
+        };
+        tokio::spawn(admin_feed(state.clone(), Duration::from_secs(3600)));
+
+        fn next(rx: &mut tokio::sync::broadcast::Receiver<ChannelMessage>) -> ServerMessage {
+            match rx.try_recv() {
+                Ok(ChannelMessage::Reply(m)) => m,
+                other => panic!("admin message expected: {:?}", other),
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let stats = next(&mut admin_rx);
+        assert_eq!(stats.event, "stats");
+        assert_eq!(serde_json::to_value(&stats.payload).unwrap()["agents"], 1);
+
+        state.ctl.lock().await.channel_add("room:1".into(), None).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let created = next(&mut admin_rx);
+        assert_eq!(created.event, "channel_created");
+        assert_eq!(serde_json::to_value(&created.payload).unwrap(), json!({ "type": "channel_created", "channel": "room:1" }));
+        assert!(admin_rx.try_recv().is_err()); // the broadcasts to admin are not fed back
+    }
+
+    #[tokio::test]
+    async fn test_set_special_channels() {
+        let state = crate::socket::ChannelSocket::builder().build().state();
+        for name in SPECIAL_CHANNELS {
+            add_channel(&state.ctl, state.broker.clone(), name.to_string()).await;
+        }
+
+        set_special_channels(&state, vec!["phoenix".into(), "news".into()]).await;
+        assert!(state.is_special_channel("news"));
+        assert!(!state.is_special_channel("admin"));
+        let ctl = state.ctl.lock().await;
+        assert!(ctl.channel_exists("news").await);
+        assert!(!ctl.channel_exists("admin").await); // empty
+        assert!(ctl.channel_exists("phoenix").await);
+    }
 }